@@ -0,0 +1,26 @@
+-- Rows are never deleted, removed offers and demands are marked with removed_at
+-- to keep the history of the market.
+CREATE TABLE offer
+(
+    id              TEXT NOT NULL PRIMARY KEY,
+    provider_id     TEXT NOT NULL,
+    requestor_id    TEXT NULL,
+    expiration      DATETIME NOT NULL,
+    pushed_at       DATETIME NOT NULL,
+    removed_at      DATETIME NULL,
+    data            TEXT NOT NULL
+);
+
+CREATE INDEX idx_offer_available ON offer (removed_at, requestor_id);
+CREATE INDEX idx_offer_provider_id ON offer (provider_id);
+
+CREATE TABLE demand
+(
+    id              TEXT NOT NULL PRIMARY KEY,
+    node_id         TEXT NOT NULL,
+    expiration      DATETIME NOT NULL,
+    removed_at      DATETIME NULL,
+    data            TEXT NOT NULL
+);
+
+CREATE INDEX idx_demand_node_id ON demand (removed_at, node_id);
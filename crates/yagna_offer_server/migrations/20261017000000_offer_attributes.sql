@@ -0,0 +1,22 @@
+-- Offer attributes used by lookups, so the sqlite storage serves them from indexes
+-- instead of keeping offers in memory. Values of stored offers are taken from their data.
+ALTER TABLE offer ADD COLUMN exe_name TEXT NOT NULL DEFAULT '';
+ALTER TABLE offer ADD COLUMN subnet TEXT NOT NULL DEFAULT '';
+ALTER TABLE offer ADD COLUMN cpu_architecture TEXT NOT NULL DEFAULT '';
+ALTER TABLE offer ADD COLUMN node_group TEXT NOT NULL DEFAULT '';
+
+UPDATE offer SET
+    exe_name = coalesce(json_extract(data, '$.attributes.exe_name'), ''),
+    subnet = coalesce(json_extract(data, '$.attributes.subnet'), ''),
+    cpu_architecture = coalesce(json_extract(data, '$.attributes.cpu_architecture'), ''),
+    node_group = coalesce(substr(
+        json_extract(data, '$.attributes.node_name'),
+        1,
+        instr(json_extract(data, '$.attributes.node_name') || '-', '-') - 1
+    ), '');
+
+CREATE INDEX idx_offer_exe_name ON offer (removed_at, exe_name);
+CREATE INDEX idx_offer_subnet ON offer (removed_at, subnet);
+CREATE INDEX idx_offer_cpu_architecture ON offer (removed_at, cpu_architecture);
+CREATE INDEX idx_offer_node_group ON offer (removed_at, node_group);
+CREATE INDEX idx_offer_expiration ON offer (removed_at, expiration);
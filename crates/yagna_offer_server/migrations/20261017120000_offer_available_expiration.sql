@@ -0,0 +1,4 @@
+-- Picker looks up available offers that are not expired yet, without the expiration
+-- in the index sqlite prefers idx_offer_expiration and reads nearly every offer.
+DROP INDEX idx_offer_available;
+CREATE INDEX idx_offer_available ON offer (removed_at, requestor_id, expiration);
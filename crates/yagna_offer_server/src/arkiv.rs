@@ -0,0 +1,313 @@
+use crate::config::MirrorSource;
+use crate::ingest::{decode_offer, ValidationError};
+use crate::model::offer::base::GolemBaseOffer;
+use crate::state::OfferObj;
+use base64::Engine;
+use chrono::Utc;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+/// Average block time of Arkiv chains, used to convert entity expiration block to time
+const BLOCK_TIME_SECS: i64 = 2;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArkivEntity {
+    pub key: String,
+    /// Base64 encoded payload, JSON of the offer for offer entities
+    pub value: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArkivEntityMetadata {
+    pub expires_at_block: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcResponse<T> {
+    result: Option<T>,
+    error: Option<RpcError>,
+}
+
+/// Minimal client of the Arkiv (Golem Base) JSON-RPC entity API
+pub struct ArkivClient {
+    client: reqwest::Client,
+    source: MirrorSource,
+}
+
+impl ArkivClient {
+    pub fn new(source: &MirrorSource) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs_f64(source.timeout_secs))
+            .build()?;
+        Ok(Self {
+            client,
+            source: source.clone(),
+        })
+    }
+
+    async fn call<T: DeserializeOwned>(
+        &self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<T> {
+        let body = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        });
+        let mut request = self
+            .client
+            .post(&self.source.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        if let Some(auth_header) = &self.source.auth_header {
+            request = request.header(reqwest::header::AUTHORIZATION, auth_header);
+        }
+        let text = request
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to call {}: {}", method, e))?
+            .error_for_status()?
+            .text()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to read response of {}: {}", method, e))?;
+        let response = serde_json::from_str::<RpcResponse<T>>(&text)
+            .map_err(|e| anyhow::anyhow!("Failed to parse response of {}: {}", method, e))?;
+        if let Some(error) = response.error {
+            anyhow::bail!("{} failed: {} (code {})", method, error.message, error.code);
+        }
+        response
+            .result
+            .ok_or_else(|| anyhow::anyhow!("{} returned no result", method))
+    }
+
+    /// Entities having the string annotation of the source, expired and deleted ones are not returned
+    pub async fn query_offer_entities(&self) -> anyhow::Result<Vec<ArkivEntity>> {
+        let annotation = &self.source.annotation;
+        let query = format!("{} = \"{}\"", annotation.key, annotation.value);
+        Ok(self
+            .call::<Option<Vec<ArkivEntity>>>("golembase_queryEntities", serde_json::json!([query]))
+            .await?
+            .unwrap_or_default())
+    }
+
+    pub async fn entity_metadata(&self, key: &str) -> anyhow::Result<ArkivEntityMetadata> {
+        self.call("golembase_getEntityMetaData", serde_json::json!([key]))
+            .await
+    }
+
+    pub async fn block_number(&self) -> anyhow::Result<u64> {
+        let block: String = self.call("eth_blockNumber", serde_json::json!([])).await?;
+        u64::from_str_radix(block.trim_start_matches("0x"), 16)
+            .map_err(|e| anyhow::anyhow!("Invalid block number {}: {}", block, e))
+    }
+}
+
+/// Decodes offer stored in the entity, the same way as offers pushed by providers
+pub fn decode_entity(entity: &ArkivEntity) -> Result<GolemBaseOffer, ValidationError> {
+    let payload = base64::engine::general_purpose::STANDARD
+        .decode(&entity.value)
+        .map_err(|e| {
+            ValidationError::new("format", "", format!("Invalid base64 payload: {}", e))
+        })?;
+    let payload = String::from_utf8(payload)
+        .map_err(|e| ValidationError::new("format", "", format!("Invalid payload: {}", e)))?;
+    decode_offer(&payload)
+}
+
+/// Offers currently stored in Arkiv and ids of offers of this source that are gone
+/// from there, because their entities were deleted or expired.
+pub struct ArkivOffers {
+    pub offers: Vec<OfferObj>,
+    pub removed: Vec<String>,
+    /// Entities which are not valid offers
+    pub rejected: u64,
+}
+
+/// Downloads offer entities of the source, `known` are offers currently in the store.
+/// New offers get their expiration shortened to expiration of the entity, so they are
+/// cleaned up when the entity expires.
+pub async fn fetch_offers(
+    source: &MirrorSource,
+    known: &[OfferObj],
+) -> anyhow::Result<ArkivOffers> {
+    let known: HashMap<&str, &OfferObj> = known
+        .iter()
+        .map(|offer_obj| (offer_obj.offer.id.as_str(), offer_obj))
+        .collect();
+    let client = ArkivClient::new(source)?;
+    let entities = client.query_offer_entities().await?;
+    let block_number = client.block_number().await?;
+
+    let mut offers = Vec::new();
+    let mut rejected = 0;
+    for entity in entities.iter() {
+        let mut offer = match decode_entity(entity) {
+            Ok(offer) => offer,
+            Err(e) => {
+                log::warn!(
+                    "Skipping entity {} from {}, rule {} failed at '{}': {}",
+                    entity.key,
+                    source.name,
+                    e.rule,
+                    e.field,
+                    e.message
+                );
+                rejected += 1;
+                continue;
+            }
+        };
+        if !known.contains_key(offer.id.as_str()) {
+            let metadata = client.entity_metadata(&entity.key).await?;
+            let blocks_left = metadata.expires_at_block.saturating_sub(block_number);
+            let expires_at = Utc::now()
+                + chrono::Duration::seconds((blocks_left as i64).saturating_mul(BLOCK_TIME_SECS));
+            offer.expiration = offer.expiration.min(expires_at);
+        }
+        offers.push(OfferObj::new(offer));
+    }
+
+    let present: HashSet<&str> = offers
+        .iter()
+        .map(|offer_obj| offer_obj.offer.id.as_str())
+        .collect();
+    let removed = known
+        .values()
+        .filter(|offer_obj| {
+            offer_obj.source.as_deref() == Some(source.name.as_str())
+                && !present.contains(offer_obj.offer.id.as_str())
+        })
+        .map(|offer_obj| offer_obj.offer.id.clone())
+        .collect();
+    Ok(ArkivOffers {
+        offers,
+        removed,
+        rejected,
+    })
+}
+
+/// Stand-in for Arkiv JSON-RPC, serves entities as (key, expiration block, payload)
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct FakeArkiv {
+    block_number: u64,
+    entities: std::sync::Mutex<Vec<(String, u64, String)>>,
+}
+
+#[cfg(test)]
+async fn fake_arkiv_rpc(
+    arkiv: actix_web::web::Data<FakeArkiv>,
+    body: actix_web::web::Json<serde_json::Value>,
+) -> actix_web::HttpResponse {
+    let entities = arkiv.entities.lock().unwrap().clone();
+    let param = body["params"][0].as_str().unwrap_or_default().to_string();
+    let result = match body["method"].as_str() {
+        Some("golembase_queryEntities") => {
+            assert_eq!(param, "golem_type = \"offer\"");
+            serde_json::to_value(
+                entities
+                    .iter()
+                    .map(|(key, _, payload)| ArkivEntity {
+                        key: key.clone(),
+                        value: base64::engine::general_purpose::STANDARD.encode(payload),
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .unwrap()
+        }
+        Some("golembase_getEntityMetaData") => entities
+            .iter()
+            .find(|(key, _, _)| key == &param)
+            .map(|(_, expires_at_block, _)| {
+                serde_json::json!({ "expiresAtBlock": expires_at_block })
+            })
+            .unwrap_or_default(),
+        Some("eth_blockNumber") => format!("0x{:x}", arkiv.block_number).into(),
+        _ => {
+            return actix_web::HttpResponse::Ok().json(serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": body["id"],
+                "error": { "code": -32601, "message": "method not found" },
+            }))
+        }
+    };
+    actix_web::HttpResponse::Ok().json(serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": body["id"],
+        "result": result,
+    }))
+}
+
+#[actix_web::test]
+async fn test_ingest_offers_from_arkiv() {
+    use crate::config::SourceKind;
+    use crate::offers::download_offers_from_mirror;
+    use crate::state::AppState;
+    use crate::test_util::{app_state, golem_offer};
+    use actix_web::web;
+
+    let payload =
+        |id: &str, provider: u8| serde_json::to_string(&golem_offer(id, provider)).unwrap();
+    let arkiv = web::Data::new(FakeArkiv {
+        block_number: 1000,
+        entities: std::sync::Mutex::new(vec![
+            ("0x01".to_string(), 1030, payload("a", 1)),
+            ("0x02".to_string(), 1000000, payload("b", 2)),
+            ("0x03".to_string(), 1000000, "not an offer".to_string()),
+        ]),
+    });
+    let server_arkiv = arkiv.clone();
+    let server = actix_web::HttpServer::new(move || {
+        actix_web::App::new()
+            .app_data(server_arkiv.clone())
+            .route("/rpc", web::post().to(fake_arkiv_rpc))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let addr = server.addrs()[0];
+    let handle = server.run();
+    let server_handle = handle.handle();
+    actix_web::rt::spawn(handle);
+
+    let data = app_state();
+    let mut source = MirrorSource::new("arkiv", &format!("http://{}/rpc", addr));
+    source.kind = SourceKind::Arkiv;
+    let sync = |data: web::Data<AppState>, source: MirrorSource| async move {
+        download_offers_from_mirror(data.clone(), source).await;
+        data.mirror_status.snapshot()["arkiv"].clone()
+    };
+
+    let status = sync(data.clone(), source.clone()).await;
+    assert!(status.last_error.is_none(), "{:?}", status.last_error);
+    assert_eq!(status.received, 2);
+    assert_eq!(status.last_counts.added, 2);
+    assert_eq!(status.last_counts.rejected, 1);
+    let offers = data.lock.lock().await.all().await.unwrap();
+    assert_eq!(offers[0].source.as_deref(), Some("arkiv"));
+    // entity of "a" expires in 30 blocks, before the offer itself
+    assert!(offers[0].offer.expiration <= Utc::now() + chrono::Duration::seconds(60));
+    assert!(offers[1].offer.expiration > Utc::now() + chrono::Duration::minutes(59));
+
+    // entity deleted (or expired) in Arkiv
+    arkiv.entities.lock().unwrap().remove(0);
+    let status = sync(data.clone(), source).await;
+    assert_eq!(status.last_counts.removed, 1);
+    assert_eq!(status.last_counts.already_present, 1);
+    let offers = data.lock.lock().await.all().await.unwrap();
+    assert_eq!(offers.len(), 1);
+    assert_eq!(offers[0].offer.id, "b");
+
+    server_handle.stop(true).await;
+}
@@ -0,0 +1,368 @@
+use crate::config::AuthConfig;
+use crate::state::AppState;
+use actix_web::dev::ServiceRequest;
+use actix_web::error::ErrorUnauthorized;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use chrono::Utc;
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, Secp256k1};
+use sha3::{Digest, Keccak256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use ya_client_model::NodeId;
+
+/// Hex encoded 65 byte signature (r, s, v) of the signing message
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+/// Unix timestamp in seconds, part of the signing message
+pub const TIMESTAMP_HEADER: &str = "X-Timestamp";
+
+/// Message signed by the client, the same way as with Ethereum personal_sign:
+/// request path, timestamp and the raw request body, separated by new lines
+pub fn signing_message(path: &str, timestamp: i64, body: &str) -> String {
+    format!("{}\n{}\n{}", path, timestamp, body)
+}
+
+fn keccak256(data: &[u8]) -> [u8; 32] {
+    Keccak256::digest(data).into()
+}
+
+fn eth_message_hash(message: &[u8]) -> [u8; 32] {
+    let mut data = format!("\x19Ethereum Signed Message:\n{}", message.len()).into_bytes();
+    data.extend_from_slice(message);
+    keccak256(&data)
+}
+
+/// Recovers address of the signer of EIP-191 (personal_sign) signature.
+/// Only canonical (low-s) signatures are accepted, `(r, n - s)` is rejected.
+pub fn recover_address(message: &[u8], signature: &[u8]) -> anyhow::Result<NodeId> {
+    if signature.len() != 65 {
+        anyhow::bail!("Signature has to be 65 bytes long, got {}", signature.len());
+    }
+    let v = match signature[64] {
+        v @ 0..=1 => v,
+        v @ 27..=28 => v - 27,
+        v => anyhow::bail!("Invalid signature recovery id {}", v),
+    };
+    let signature =
+        RecoverableSignature::from_compact(&signature[..64], RecoveryId::from_i32(v as i32)?)?;
+    let mut normalized = signature.to_standard();
+    normalized.normalize_s();
+    if normalized.serialize_compact() != signature.to_standard().serialize_compact() {
+        anyhow::bail!(
+            "Signature is not canonical, s has to be in the lower half of the curve order"
+        );
+    }
+    let message = Message::from_slice(&eth_message_hash(message))?;
+    let public_key = Secp256k1::verification_only().recover_ecdsa(&message, &signature)?;
+    let hash = keccak256(&public_key.serialize_uncompressed()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    Ok(NodeId::from(address))
+}
+
+/// Verifies that requests are signed by the provider or requestor they act for.
+/// Requests are accepted only within the timestamp window and every signature can be used once.
+#[derive(Debug, Default)]
+pub struct SignatureVerifier {
+    required: bool,
+    max_skew_secs: i64,
+    used_signatures: Mutex<HashMap<String, i64>>,
+}
+
+impl SignatureVerifier {
+    pub fn new(required: bool, max_skew_secs: i64) -> Self {
+        Self {
+            required,
+            max_skew_secs,
+            used_signatures: Default::default(),
+        }
+    }
+
+    pub fn from_config(config: &AuthConfig) -> Self {
+        if config.require_signatures {
+            log::info!(
+                "Requests have to be signed, accepted timestamp skew {} seconds",
+                config.signature_max_skew_secs
+            );
+        } else {
+            log::warn!("Signatures are not required, requests are not authenticated");
+        }
+        Self::new(config.require_signatures, config.signature_max_skew_secs)
+    }
+
+    fn check(&self, req: &HttpRequest, body: &str, expected: NodeId) -> anyhow::Result<()> {
+        let header = |name: &str| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| anyhow::anyhow!("Missing {} header", name))
+        };
+        let signature = header(SIGNATURE_HEADER)?;
+        let timestamp: i64 = header(TIMESTAMP_HEADER)?
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid {} header", TIMESTAMP_HEADER))?;
+
+        let now = Utc::now().timestamp();
+        if (now - timestamp).abs() > self.max_skew_secs {
+            anyhow::bail!(
+                "Request timestamp {} is outside of accepted window",
+                timestamp
+            );
+        }
+
+        let signature_bytes = hex::decode(signature.trim_start_matches("0x"))
+            .map_err(|e| anyhow::anyhow!("Invalid signature encoding: {}", e))?;
+        let message = signing_message(req.path(), timestamp, body);
+        let signer = recover_address(message.as_bytes(), &signature_bytes)?;
+        if signer != expected {
+            anyhow::bail!("Request signed by {}, expected {}", signer, expected);
+        }
+
+        let mut used = self
+            .used_signatures
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        used.retain(|_, used_timestamp| (now - *used_timestamp).abs() <= self.max_skew_secs);
+        // keyed by (r, s), encoding of the header and recovery id do not make a new signature
+        if used
+            .insert(hex::encode(&signature_bytes[..64]), timestamp)
+            .is_some()
+        {
+            anyhow::bail!("Signature was already used");
+        }
+        Ok(())
+    }
+
+    /// Checks signature of the request made on behalf of expected node.
+    /// Returns 401 response if verification is required and fails.
+    pub fn verify(
+        &self,
+        req: &HttpRequest,
+        body: &str,
+        expected: NodeId,
+    ) -> Result<(), HttpResponse> {
+        if !self.required {
+            return Ok(());
+        }
+        self.check(req, body, expected).map_err(|e| {
+            log::warn!("Signature verification failed for {}: {}", req.path(), e);
+            HttpResponse::Unauthorized().body(format!("Signature verification failed: {}", e))
+        })
+    }
+}
+
+/// Name of the admin token holder, stored in request extensions for the audit log
+#[derive(Debug, Clone)]
+pub struct AdminIdentity(pub String);
+
+/// Bearer tokens allowed to call /admin endpoints, each with a name used in the audit log
+#[derive(Debug, Clone, Default)]
+pub struct AdminTokens {
+    tokens: Vec<(String, String)>,
+    audit_file: Option<String>,
+}
+
+impl AdminTokens {
+    /// Parses `name:token` entries, entries without name are numbered
+    pub fn new(entries: &[String], audit_file: Option<String>) -> Self {
+        let tokens = entries
+            .iter()
+            .map(|entry| entry.trim())
+            .filter(|entry| !entry.is_empty())
+            .enumerate()
+            .map(|(idx, entry)| match entry.split_once(':') {
+                Some((name, token)) => (name.trim().to_string(), token.trim().to_string()),
+                None => (format!("token-{}", idx + 1), entry.to_string()),
+            })
+            .collect();
+        Self { tokens, audit_file }
+    }
+
+    pub fn from_config(config: &AuthConfig) -> Self {
+        let tokens = Self::new(&config.admin_tokens, config.admin_audit_file.clone());
+        if tokens.tokens.is_empty() {
+            log::warn!("No admin tokens configured, /admin endpoints are disabled");
+        } else {
+            log::info!("Loaded {} admin tokens", tokens.tokens.len());
+        }
+        tokens
+    }
+
+    /// Returns name of the matching token
+    pub fn authorize(&self, token: &str) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|(_, expected)| constant_time_eq(expected.as_bytes(), token.as_bytes()))
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// Writes audit entry to the log and, if audit file is configured, appends it as json line
+    pub fn audit(&self, admin: &str, method: &str, path: &str, status: u16) {
+        log::info!(target: "audit", "{} {} {} by {}", status, method, path, admin);
+        let Some(file_name) = &self.audit_file else {
+            return;
+        };
+        let entry = serde_json::json!({
+            "time": Utc::now(),
+            "admin": admin,
+            "method": method,
+            "path": path,
+            "status": status,
+        });
+        let res = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(file_name)
+            .and_then(|mut file| writeln!(file, "{}", entry));
+        if let Err(e) = res {
+            log::error!("Failed to write audit entry to {}: {}", file_name, e);
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub async fn admin_validator(
+    req: ServiceRequest,
+    credentials: Option<BearerAuth>,
+) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let Some(admin_tokens) = req
+        .app_data::<web::Data<AppState>>()
+        .map(|data| data.admin_tokens.clone())
+    else {
+        return Err((ErrorUnauthorized("Admin endpoints are not configured"), req));
+    };
+    let name = credentials.and_then(|credentials| {
+        admin_tokens
+            .authorize(credentials.token())
+            .map(str::to_string)
+    });
+    match name {
+        Some(name) => {
+            req.extensions_mut().insert(AdminIdentity(name));
+            Ok(req)
+        }
+        None => {
+            admin_tokens.audit("unauthorized", req.method().as_str(), req.path(), 401);
+            Err((ErrorUnauthorized("Invalid admin token"), req))
+        }
+    }
+}
+
+#[test]
+fn test_signature_verification() {
+    use actix_web::test::TestRequest;
+    use secp256k1::SecretKey;
+
+    let secp = Secp256k1::new();
+    let secret_key = SecretKey::from_slice(&[0x42; 32]).unwrap();
+    let public_key = secret_key.public_key(&secp);
+    let hash = keccak256(&public_key.serialize_uncompressed()[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    let signer = NodeId::from(address);
+
+    let sign = |path: &str, timestamp: i64, body: &str| {
+        let message = signing_message(path, timestamp, body);
+        let message = Message::from_slice(&eth_message_hash(message.as_bytes())).unwrap();
+        let (recovery_id, compact) = secp
+            .sign_ecdsa_recoverable(&message, &secret_key)
+            .serialize_compact();
+        let mut signature = compact.to_vec();
+        signature.push(recovery_id.to_i32() as u8 + 27);
+        format!("0x{}", hex::encode(signature))
+    };
+    let request = |timestamp: i64, signature: &str| {
+        TestRequest::post()
+            .uri("/requestor/demand/new")
+            .insert_header((TIMESTAMP_HEADER, timestamp.to_string()))
+            .insert_header((SIGNATURE_HEADER, signature))
+            .to_http_request()
+    };
+
+    let verifier = SignatureVerifier::new(true, 60);
+    let now = Utc::now().timestamp();
+    let body = r#"{"id":"demand"}"#;
+    let signature = sign("/requestor/demand/new", now, body);
+    let req = request(now, &signature);
+    assert!(verifier.verify(&req, body, signer).is_ok());
+    // replay of the same request
+    assert!(verifier.verify(&req, body, signer).is_err());
+    // replay of the same signature encoded differently
+    let bytes = hex::decode(signature.trim_start_matches("0x")).unwrap();
+    let mut raw_v = bytes.clone();
+    raw_v[64] -= 27;
+    let mut high_s = bytes.clone();
+    let s = SecretKey::from_slice(&bytes[32..64]).unwrap().negate();
+    high_s[32..64].copy_from_slice(&s.secret_bytes());
+    high_s[64] = if high_s[64] == 27 { 28 } else { 27 };
+    // (r, n - s) with flipped recovery id recovers the same signer
+    assert_eq!(
+        recover_address(
+            signing_message("/requestor/demand/new", now, body).as_bytes(),
+            &high_s
+        )
+        .unwrap_err()
+        .to_string(),
+        "Signature is not canonical, s has to be in the lower half of the curve order"
+    );
+    for replay in [
+        signature.trim_start_matches("0x").to_string(),
+        signature.to_uppercase().replace("0X", "0x"),
+        format!("0x{}", hex::encode(&raw_v)),
+        format!("0x{}", hex::encode(&high_s)),
+    ] {
+        assert!(
+            verifier
+                .verify(&request(now, &replay), body, signer)
+                .is_err(),
+            "{}",
+            replay
+        );
+    }
+    // different body
+    let signature = sign("/requestor/demand/new", now, body);
+    assert!(verifier
+        .verify(&request(now, &signature), "{}", signer)
+        .is_err());
+    // signed for other endpoint
+    let signature = sign("/provider/offer/new", now, body);
+    assert!(verifier
+        .verify(&request(now, &signature), body, signer)
+        .is_err());
+    // other node
+    let signature = sign("/requestor/demand/new", now, body);
+    assert!(verifier
+        .verify(&request(now, &signature), body, NodeId::from([1u8; 20]))
+        .is_err());
+    // outside of timestamp window
+    let signature = sign("/requestor/demand/new", now - 120, body);
+    assert!(verifier
+        .verify(&request(now - 120, &signature), body, signer)
+        .is_err());
+    // missing headers
+    let req = TestRequest::post()
+        .uri("/requestor/demand/new")
+        .to_http_request();
+    let resp = verifier.verify(&req, body, signer).unwrap_err();
+    assert_eq!(resp.status(), actix_web::http::StatusCode::UNAUTHORIZED);
+    assert!(SignatureVerifier::default()
+        .verify(&req, body, signer)
+        .is_ok());
+}
+
+#[test]
+fn test_admin_tokens() {
+    let entries = ["ci:secret1", " ops : secret2", "anonymous"].map(str::to_string);
+    let tokens = AdminTokens::new(&entries, None);
+    assert_eq!(tokens.authorize("secret1"), Some("ci"));
+    assert_eq!(tokens.authorize("secret2"), Some("ops"));
+    assert_eq!(tokens.authorize("anonymous"), Some("token-3"));
+    assert_eq!(tokens.authorize("secret"), None);
+    assert_eq!(tokens.authorize(""), None);
+    assert_eq!(AdminTokens::default().authorize(""), None);
+}
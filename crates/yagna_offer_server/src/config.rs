@@ -0,0 +1,843 @@
+use crate::model::flatten::flatten;
+use crate::model::offer::pricing::OfferOrder;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::watch;
+use ya_client_model::NodeId;
+
+/// Runtime configuration, loaded from `--config` TOML file. Every value can be
+/// overridden with environment variable, listed next to the field.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub mirror: MirrorConfig,
+    pub picker: PickerConfig,
+    pub cleanup: CleanupConfig,
+    pub state: StateConfig,
+    pub lease: LeaseConfig,
+    pub auth: AuthConfig,
+    pub validation: ValidationConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// HTTP_WORKERS
+    pub workers: usize,
+    pub cors: CorsConfig,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            workers: 4,
+            cors: CorsConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    /// Allow any origin, method and header
+    pub permissive: bool,
+    /// CORS_ALLOWED_ORIGINS (comma separated, disables permissive mode)
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            permissive: true,
+            allowed_origins: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// OFFER_SOURCE_URL, synchronized as source named `default` next to `sources`
+    pub source_url: Option<String>,
+    /// OFFER_MIRROR_SYNC_INTERVAL_SECS, used by sources without their own interval
+    pub sync_interval_secs: f64,
+    /// Offer servers to mirror, offers are not synchronized if empty and source_url is not set
+    pub sources: Vec<MirrorSource>,
+}
+
+impl Default for MirrorConfig {
+    fn default() -> Self {
+        Self {
+            source_url: None,
+            sync_interval_secs: 300.0,
+            sources: Vec::new(),
+        }
+    }
+}
+
+/// Name of the source created from `mirror.source_url`
+pub const DEFAULT_SOURCE_NAME: &str = "default";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// Offer list of other offer server (or any url returning offers in the same format)
+    #[default]
+    Offers,
+    /// Arkiv (Golem Base) JSON-RPC endpoint, offers are stored there as entities
+    Arkiv,
+}
+
+/// Annotation selecting offer entities in Arkiv
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArkivAnnotation {
+    pub key: String,
+    pub value: String,
+}
+
+impl Default for ArkivAnnotation {
+    fn default() -> Self {
+        Self {
+            key: "golem_type".to_string(),
+            value: "offer".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorSource {
+    /// Recorded in offers downloaded from this source and shown on the status endpoint
+    pub name: String,
+    /// Url returning list of offers, the same as /offers/list of other offer server
+    pub url: String,
+    /// Defaults to mirror.sync_interval_secs
+    #[serde(default)]
+    pub interval_secs: Option<f64>,
+    #[serde(default = "default_source_timeout_secs")]
+    pub timeout_secs: f64,
+    /// Value of the Authorization header sent to the source, e.g. `Bearer <token>`
+    #[serde(default)]
+    pub auth_header: Option<String>,
+    #[serde(default)]
+    pub kind: SourceKind,
+    /// Arkiv sources only, string annotation of provider offer entities
+    #[serde(default)]
+    pub annotation: ArkivAnnotation,
+}
+
+fn default_source_timeout_secs() -> f64 {
+    30.0
+}
+
+impl MirrorSource {
+    pub fn new(name: &str, url: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            url: url.to_string(),
+            interval_secs: None,
+            timeout_secs: default_source_timeout_secs(),
+            auth_header: None,
+            kind: SourceKind::default(),
+            annotation: ArkivAnnotation::default(),
+        }
+    }
+}
+
+impl MirrorConfig {
+    /// Configured sources, including the one given by source_url
+    pub fn all_sources(&self) -> Vec<MirrorSource> {
+        let mut sources = self.sources.clone();
+        if let Some(url) = &self.source_url {
+            sources.push(MirrorSource::new(DEFAULT_SOURCE_NAME, url));
+        }
+        sources
+    }
+
+    pub fn interval_secs(&self, source: &MirrorSource) -> f64 {
+        source.interval_secs.unwrap_or(self.sync_interval_secs)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PickerConfig {
+    /// PICK_OFFERS_INTERVAL_SECS, values >= 1e9 disable periodic picking
+    pub interval_secs: f64,
+    /// LOG_EVERY_SEC
+    pub log_every_secs: f64,
+    /// OFFER_GROUP, pick only offers from nodes with `<group>-` in their name (used in integration tests)
+    pub offer_group: Option<String>,
+    /// PICK_OFFERS_ORDER, used when demand does not specify its own order
+    pub order: OfferOrder,
+    /// Weights of the `weighted` order
+    pub weights: ScoreWeights,
+    /// PICK_BUDGET_PER_TICK, maximum number of offers assigned in one tick
+    pub budget_per_tick: usize,
+    /// PICK_QUEUE_DEPTH, offers kept queued for demands not declaring `queueDepth`,
+    /// None keeps assigning offers to them as long as the budget allows
+    pub queue_depth: Option<usize>,
+    /// PICK_SHARE_WINDOW_SECS, fair-share usage decays by factor e over that time
+    pub share_window_secs: f64,
+    /// Fair-share weights of central nets, nets not listed have weight 1
+    pub net_weights: BTreeMap<String, f64>,
+    /// Fair-share weights of requestor nodes (lowercase node ids) within their
+    /// central net, nodes not listed have weight 1
+    pub requestor_weights: BTreeMap<String, f64>,
+}
+
+impl Default for PickerConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 30.0,
+            log_every_secs: 10.0,
+            offer_group: None,
+            order: OfferOrder::default(),
+            weights: ScoreWeights::default(),
+            budget_per_tick: 10,
+            queue_depth: None,
+            share_window_secs: 3600.0,
+            net_weights: BTreeMap::new(),
+            requestor_weights: BTreeMap::new(),
+        }
+    }
+}
+
+/// Weights of score components, each component is scaled to 0..1 among the candidates
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScoreWeights {
+    /// PICK_WEIGHT_PRICE, cheaper offers score higher
+    pub price: f64,
+    /// PICK_WEIGHT_FRESHNESS, recently published offers score higher
+    pub freshness: f64,
+    /// PICK_WEIGHT_RESOURCES, offers with more threads, memory and storage score higher
+    pub resources: f64,
+}
+
+impl Default for ScoreWeights {
+    fn default() -> Self {
+        Self {
+            price: 1.0,
+            freshness: 1.0,
+            resources: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CleanupConfig {
+    /// CLEAN_OFFERS_INTERVAL_SECS
+    pub offers_interval_secs: f64,
+    /// CLEAN_DEMANDS_INTERVAL_SECS
+    pub demands_interval_secs: f64,
+    /// OFFER_EXPIRY_GRACE_SECS, offers are kept that long after they expire
+    pub offer_expiry_grace_secs: i64,
+    /// HISTORY_RETENTION_SECS, removed offers and demands are kept that long in the database
+    pub history_retention_secs: i64,
+}
+
+impl Default for CleanupConfig {
+    fn default() -> Self {
+        Self {
+            offers_interval_secs: 60.0,
+            demands_interval_secs: 60.0,
+            offer_expiry_grace_secs: 3600,
+            history_retention_secs: 7 * 24 * 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /// STATE_SAVE_INTERVAL_SECS
+    pub save_interval_secs: f64,
+}
+
+impl Default for StateConfig {
+    fn default() -> Self {
+        Self {
+            save_interval_secs: 60.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaseConfig {
+    /// OFFER_LEASE_TTL_SECS, assigned offers are kept until they expire if not set
+    pub ttl_secs: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    /// REQUIRE_SIGNATURES
+    pub require_signatures: bool,
+    /// SIGNATURE_MAX_SKEW_SECS
+    pub signature_max_skew_secs: i64,
+    /// ADMIN_TOKENS (comma separated), entries in `name:token` form
+    pub admin_tokens: Vec<String>,
+    /// ADMIN_AUDIT_FILE
+    pub admin_audit_file: Option<String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            require_signatures: false,
+            signature_max_skew_secs: 60,
+            admin_tokens: Vec::new(),
+            admin_audit_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ValidationConfig {
+    /// OFFER_MAX_CLOCK_SKEW_SECS, how far in the future offer timestamp can be
+    pub max_clock_skew_secs: i64,
+    /// OFFER_MAX_LIFETIME_SECS, offers expiring later than that are rejected
+    pub max_lifetime_secs: i64,
+}
+
+impl Default for ValidationConfig {
+    fn default() -> Self {
+        Self {
+            max_clock_skew_secs: 300,
+            max_lifetime_secs: 86400,
+        }
+    }
+}
+
+fn override_value<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    target: &mut T,
+) -> anyhow::Result<()>
+where
+    T::Err: Display,
+{
+    if let Some(value) = var(name) {
+        *target = value
+            .parse()
+            .map_err(|e| anyhow::anyhow!("Invalid value of {}: {}", name, e))?;
+    }
+    Ok(())
+}
+
+fn override_option<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    target: &mut Option<T>,
+) -> anyhow::Result<()>
+where
+    T::Err: Display,
+{
+    if let Some(value) = var(name) {
+        *target = Some(
+            value
+                .parse()
+                .map_err(|e| anyhow::anyhow!("Invalid value of {}: {}", name, e))?,
+        );
+    }
+    Ok(())
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+impl Config {
+    /// Reads config file (defaults if not given), applies environment overrides and validates
+    pub fn load(file_name: Option<&str>) -> anyhow::Result<Self> {
+        let mut config = match file_name {
+            Some(file_name) => {
+                let text = std::fs::read_to_string(file_name)
+                    .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", file_name, e))?;
+                toml::from_str(&text)
+                    .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", file_name, e))?
+            }
+            None => Config::default(),
+        };
+        config.apply_overrides(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn apply_overrides(&mut self, var: impl Fn(&str) -> Option<String>) -> anyhow::Result<()> {
+        override_value(&var, "HTTP_WORKERS", &mut self.server.workers)?;
+        if let Some(origins) = var("CORS_ALLOWED_ORIGINS") {
+            self.server.cors.permissive = false;
+            self.server.cors.allowed_origins = split_list(&origins);
+        }
+        override_option(&var, "OFFER_SOURCE_URL", &mut self.mirror.source_url)?;
+        override_value(
+            &var,
+            "OFFER_MIRROR_SYNC_INTERVAL_SECS",
+            &mut self.mirror.sync_interval_secs,
+        )?;
+        override_value(
+            &var,
+            "PICK_OFFERS_INTERVAL_SECS",
+            &mut self.picker.interval_secs,
+        )?;
+        override_value(&var, "LOG_EVERY_SEC", &mut self.picker.log_every_secs)?;
+        override_option(&var, "OFFER_GROUP", &mut self.picker.offer_group)?;
+        override_value(&var, "PICK_OFFERS_ORDER", &mut self.picker.order)?;
+        override_value(
+            &var,
+            "PICK_BUDGET_PER_TICK",
+            &mut self.picker.budget_per_tick,
+        )?;
+        override_option(&var, "PICK_QUEUE_DEPTH", &mut self.picker.queue_depth)?;
+        override_value(
+            &var,
+            "PICK_SHARE_WINDOW_SECS",
+            &mut self.picker.share_window_secs,
+        )?;
+        override_value(&var, "PICK_WEIGHT_PRICE", &mut self.picker.weights.price)?;
+        override_value(
+            &var,
+            "PICK_WEIGHT_FRESHNESS",
+            &mut self.picker.weights.freshness,
+        )?;
+        override_value(
+            &var,
+            "PICK_WEIGHT_RESOURCES",
+            &mut self.picker.weights.resources,
+        )?;
+        override_value(
+            &var,
+            "CLEAN_OFFERS_INTERVAL_SECS",
+            &mut self.cleanup.offers_interval_secs,
+        )?;
+        override_value(
+            &var,
+            "CLEAN_DEMANDS_INTERVAL_SECS",
+            &mut self.cleanup.demands_interval_secs,
+        )?;
+        override_value(
+            &var,
+            "OFFER_EXPIRY_GRACE_SECS",
+            &mut self.cleanup.offer_expiry_grace_secs,
+        )?;
+        override_value(
+            &var,
+            "HISTORY_RETENTION_SECS",
+            &mut self.cleanup.history_retention_secs,
+        )?;
+        override_value(
+            &var,
+            "STATE_SAVE_INTERVAL_SECS",
+            &mut self.state.save_interval_secs,
+        )?;
+        override_option(&var, "OFFER_LEASE_TTL_SECS", &mut self.lease.ttl_secs)?;
+        if let Some(value) = var("REQUIRE_SIGNATURES") {
+            self.auth.require_signatures = value == "1" || value.to_lowercase() == "true";
+        }
+        override_value(
+            &var,
+            "SIGNATURE_MAX_SKEW_SECS",
+            &mut self.auth.signature_max_skew_secs,
+        )?;
+        if let Some(tokens) = var("ADMIN_TOKENS") {
+            self.auth.admin_tokens = split_list(&tokens);
+        }
+        override_option(&var, "ADMIN_AUDIT_FILE", &mut self.auth.admin_audit_file)?;
+        override_value(
+            &var,
+            "OFFER_MAX_CLOCK_SKEW_SECS",
+            &mut self.validation.max_clock_skew_secs,
+        )?;
+        override_value(
+            &var,
+            "OFFER_MAX_LIFETIME_SECS",
+            &mut self.validation.max_lifetime_secs,
+        )?;
+        Ok(())
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let positive = [
+            ("mirror.sync_interval_secs", self.mirror.sync_interval_secs),
+            ("picker.interval_secs", self.picker.interval_secs),
+            ("picker.log_every_secs", self.picker.log_every_secs),
+            ("picker.share_window_secs", self.picker.share_window_secs),
+            (
+                "cleanup.offers_interval_secs",
+                self.cleanup.offers_interval_secs,
+            ),
+            (
+                "cleanup.demands_interval_secs",
+                self.cleanup.demands_interval_secs,
+            ),
+            ("state.save_interval_secs", self.state.save_interval_secs),
+            ("lease.ttl_secs", self.lease.ttl_secs.unwrap_or(1.0)),
+        ];
+        for (name, value) in positive {
+            if !(value.is_finite() && value > 0.0) {
+                anyhow::bail!("{} has to be positive, got {}", name, value);
+            }
+        }
+        if self.server.workers == 0 {
+            anyhow::bail!("server.workers has to be at least 1");
+        }
+        if self.cleanup.offer_expiry_grace_secs < 0 {
+            anyhow::bail!("cleanup.offer_expiry_grace_secs cannot be negative");
+        }
+        if self.cleanup.history_retention_secs < 0 {
+            anyhow::bail!("cleanup.history_retention_secs cannot be negative");
+        }
+        if self.auth.signature_max_skew_secs <= 0 {
+            anyhow::bail!("auth.signature_max_skew_secs has to be positive");
+        }
+        if self.validation.max_clock_skew_secs < 0 {
+            anyhow::bail!("validation.max_clock_skew_secs cannot be negative");
+        }
+        if self.validation.max_lifetime_secs <= 0 {
+            anyhow::bail!("validation.max_lifetime_secs has to be positive");
+        }
+        let mut source_names = std::collections::HashSet::new();
+        for source in self.mirror.all_sources() {
+            if source.name.is_empty() {
+                anyhow::bail!("mirror source name cannot be empty");
+            }
+            if !source_names.insert(source.name.clone()) {
+                anyhow::bail!("mirror source {} is defined more than once", source.name);
+            }
+            if !source.url.starts_with("http://") && !source.url.starts_with("https://") {
+                anyhow::bail!(
+                    "url of mirror source {} has to be http(s) url, got {}",
+                    source.name,
+                    source.url
+                );
+            }
+            if source.kind == SourceKind::Arkiv
+                && (source.annotation.key.is_empty() || source.annotation.value.contains('"'))
+            {
+                anyhow::bail!(
+                    "annotation of arkiv source {} needs a key and a value without quotes",
+                    source.name
+                );
+            }
+            for (name, value) in [
+                ("interval_secs", self.mirror.interval_secs(&source)),
+                ("timeout_secs", source.timeout_secs),
+            ] {
+                if !(value.is_finite() && value > 0.0) {
+                    anyhow::bail!(
+                        "{} of mirror source {} has to be positive, got {}",
+                        name,
+                        source.name,
+                        value
+                    );
+                }
+            }
+        }
+        if self.picker.offer_group.as_deref() == Some("") {
+            anyhow::bail!("picker.offer_group cannot be empty");
+        }
+        if self.picker.budget_per_tick == 0 {
+            anyhow::bail!("picker.budget_per_tick has to be at least 1");
+        }
+        if self.picker.queue_depth == Some(0) {
+            anyhow::bail!("picker.queue_depth has to be at least 1");
+        }
+        let share_weights = self
+            .picker
+            .net_weights
+            .iter()
+            .map(|(net, weight)| ("net_weights", net, weight))
+            .chain(
+                self.picker
+                    .requestor_weights
+                    .iter()
+                    .map(|(node_id, weight)| ("requestor_weights", node_id, weight)),
+            );
+        for (table, key, weight) in share_weights {
+            if !(weight.is_finite() && *weight > 0.0) {
+                anyhow::bail!(
+                    "picker.{}.{} has to be positive, got {}",
+                    table,
+                    key,
+                    weight
+                );
+            }
+        }
+        for node_id in self.picker.requestor_weights.keys() {
+            match NodeId::from_str(node_id) {
+                Ok(parsed) if parsed.to_string() == *node_id => {}
+                _ => anyhow::bail!(
+                    "picker.requestor_weights key {} is not a lowercase node id",
+                    node_id
+                ),
+            }
+        }
+        let weights = self.picker.weights;
+        for (name, value) in [
+            ("price", weights.price),
+            ("freshness", weights.freshness),
+            ("resources", weights.resources),
+        ] {
+            if !(value.is_finite() && value >= 0.0) {
+                anyhow::bail!("picker.weights.{} cannot be negative, got {}", name, value);
+            }
+        }
+        if weights.price + weights.freshness + weights.resources <= 0.0 {
+            anyhow::bail!("at least one of picker.weights has to be positive");
+        }
+        if !self.server.cors.permissive && self.server.cors.allowed_origins.is_empty() {
+            log::warn!("CORS is not permissive and no origins are allowed");
+        }
+        Ok(())
+    }
+}
+
+/// Settings read only at startup, changing them in runtime has no effect
+const RESTART_REQUIRED: [&str; 2] = ["server.", "auth."];
+
+impl Config {
+    /// Lists changed settings as `name: old -> new`, admin tokens and auth headers are not printed
+    pub fn diff(&self, other: &Config) -> Vec<String> {
+        let flat = |config: &Config| {
+            let mut config = config.clone();
+            for source in config.mirror.sources.iter_mut() {
+                if source.auth_header.is_some() {
+                    source.auth_header = Some("<hidden>".to_string());
+                }
+            }
+            serde_json::to_value(config)
+                .map(flatten)
+                .unwrap_or_default()
+        };
+        let old = flat(self);
+        let new = flat(other);
+        let mut changes = Vec::new();
+        for (name, new_value) in new.iter() {
+            let old_value = old.get(name).unwrap_or(&serde_json::Value::Null);
+            if old_value == new_value {
+                continue;
+            }
+            if name == "auth.admin_tokens" {
+                changes.push(format!("{}: <changed>", name));
+            } else {
+                changes.push(format!("{}: {} -> {}", name, old_value, new_value));
+            }
+        }
+        let sources_listed = changes
+            .iter()
+            .any(|change| change.starts_with("mirror.sources:"));
+        if !sources_listed && self.mirror.sources != other.mirror.sources {
+            changes.push("mirror.sources: <auth header changed>".to_string());
+        }
+        changes
+    }
+}
+
+/// Current configuration shared by the server, replaced on reload. Periodic tasks
+/// subscribe to get notified about changes, others read current value on every use.
+#[derive(Debug)]
+pub struct SharedConfig {
+    file_name: Option<String>,
+    sender: watch::Sender<Arc<Config>>,
+}
+
+impl Default for SharedConfig {
+    fn default() -> Self {
+        Self::new(Config::default(), None)
+    }
+}
+
+impl SharedConfig {
+    pub fn new(config: Config, file_name: Option<String>) -> Self {
+        Self {
+            file_name,
+            sender: watch::Sender::new(Arc::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.sender.borrow().clone()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Config>> {
+        self.sender.subscribe()
+    }
+
+    /// Replaces configuration, returns list of changed settings
+    pub fn set(&self, config: Config) -> Vec<String> {
+        let changes = self.get().diff(&config);
+        for change in changes.iter() {
+            if RESTART_REQUIRED
+                .iter()
+                .any(|prefix| change.starts_with(prefix))
+            {
+                log::warn!("Config changed: {} (applied after restart)", change);
+            } else {
+                log::info!("Config changed: {}", change);
+            }
+        }
+        if !changes.is_empty() {
+            self.sender.send_replace(Arc::new(config));
+        }
+        changes
+    }
+
+    /// Loads configuration again from the same file and environment
+    pub fn reload(&self) -> anyhow::Result<Vec<String>> {
+        let config = Config::load(self.file_name.as_deref())?;
+        let changes = self.set(config);
+        if changes.is_empty() {
+            log::info!("Configuration reloaded, nothing changed");
+        }
+        Ok(changes)
+    }
+}
+
+#[test]
+fn test_config_example_and_overrides() {
+    use std::collections::HashMap;
+
+    let mut config: Config = toml::from_str(include_str!("../offer_server.example.toml")).unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.picker.order, OfferOrder::Cheapest);
+    assert_eq!(config.picker.weights.price, 2.0);
+    assert_eq!(config.cleanup.offer_expiry_grace_secs, 3600);
+
+    let env: HashMap<&str, &str> = [
+        ("OFFER_GROUP", "brick"),
+        ("PICK_OFFERS_INTERVAL_SECS", "5"),
+        (
+            "CORS_ALLOWED_ORIGINS",
+            "https://a.example, https://b.example",
+        ),
+        ("ADMIN_TOKENS", "ci:secret"),
+        ("PICK_OFFERS_ORDER", "leastRecentlyAssigned"),
+        ("PICK_WEIGHT_RESOURCES", "0"),
+        ("PICK_QUEUE_DEPTH", "20"),
+    ]
+    .into_iter()
+    .collect();
+    config
+        .apply_overrides(|name| env.get(name).map(|v| v.to_string()))
+        .unwrap();
+    config.validate().unwrap();
+    assert_eq!(config.picker.offer_group.as_deref(), Some("brick"));
+    assert_eq!(config.picker.interval_secs, 5.0);
+    assert!(!config.server.cors.permissive);
+    assert_eq!(config.server.cors.allowed_origins.len(), 2);
+    assert_eq!(config.auth.admin_tokens, vec!["ci:secret".to_string()]);
+    assert_eq!(config.picker.order, OfferOrder::LeastRecentlyAssigned);
+    assert_eq!(config.picker.weights.resources, 0.0);
+    assert_eq!(config.picker.queue_depth, Some(20));
+
+    assert!(config
+        .clone()
+        .apply_overrides(|name| (name == "LOG_EVERY_SEC").then(|| "often".to_string()))
+        .is_err());
+    let mut zero_weights = config.clone();
+    zero_weights.picker.weights = ScoreWeights {
+        price: 0.0,
+        freshness: 0.0,
+        resources: 0.0,
+    };
+    assert!(zero_weights.validate().is_err());
+    let mut zero_depth = config.clone();
+    zero_depth.picker.queue_depth = Some(0);
+    assert!(zero_depth.validate().is_err());
+    let shares = r#"
+        [picker.net_weights]
+        "192.168.1.1" = 2.0
+        [picker.requestor_weights]
+        "0xa3bde9e2ef344407afdc931c97fd33d506ec6545" = 0.5
+    "#;
+    let mut share_config: Config = toml::from_str(shares).unwrap();
+    share_config.validate().unwrap();
+    share_config.picker.requestor_weights = [(
+        "0xA3BDE9E2EF344407AFDC931C97FD33D506EC6545".to_string(),
+        1.0,
+    )]
+    .into();
+    assert!(share_config.validate().is_err());
+    config.picker.interval_secs = 0.0;
+    assert!(config.validate().is_err());
+    assert!(toml::from_str::<Config>("[picker]\nunknown = 1").is_err());
+    assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+
+    let sources = r#"
+        [mirror]
+        source_url = "https://legacy.example/offers/list"
+        [[mirror.sources]]
+        name = "eu"
+        url = "https://eu.example/offers/list"
+        interval_secs = 60
+        auth_header = "Bearer secret"
+        [[mirror.sources]]
+        name = "arkiv"
+        kind = "arkiv"
+        url = "https://arkiv.example/rpc"
+        annotation = { key = "golem_type", value = "offer" }
+    "#;
+    let config: Config = toml::from_str(sources).unwrap();
+    config.validate().unwrap();
+    let all = config.mirror.all_sources();
+    assert_eq!(all.len(), 3);
+    assert_eq!(config.mirror.interval_secs(&all[0]), 60.0);
+    assert_eq!(all[0].kind, SourceKind::Offers);
+    assert_eq!(all[1].kind, SourceKind::Arkiv);
+    assert_eq!(all[2].name, DEFAULT_SOURCE_NAME);
+    assert_eq!(config.mirror.interval_secs(&all[2]), 300.0);
+    let mut quoted = config.clone();
+    quoted.mirror.sources[1].annotation.value = "\"offer".to_string();
+    assert!(quoted.validate().is_err());
+    let mut duplicated = config.clone();
+    duplicated.mirror.sources[0].name = DEFAULT_SOURCE_NAME.to_string();
+    assert!(duplicated.validate().is_err());
+    let mut changed = config.clone();
+    changed.mirror.sources[0].auth_header = Some("Bearer other".to_string());
+    let changes = config.diff(&changed);
+    assert_eq!(changes, vec!["mirror.sources: <auth header changed>"]);
+    changed.mirror.sources[0].timeout_secs = 5.0;
+    let changes = config.diff(&changed);
+    assert_eq!(changes.len(), 1);
+    assert!(!changes[0].contains("secret") && !changes[0].contains("other"));
+}
+
+#[tokio::test]
+async fn test_shared_config_change() {
+    let shared = SharedConfig::default();
+    let mut receiver = shared.subscribe();
+
+    let mut config = Config::default();
+    config.picker.interval_secs = 5.0;
+    config.picker.offer_group = Some("brick".to_string());
+    config.auth.admin_tokens = vec!["ci:secret".to_string()];
+    let changes = shared.set(config.clone());
+    assert_eq!(
+        changes,
+        vec![
+            "auth.admin_tokens: <changed>".to_string(),
+            "picker.interval_secs: 30.0 -> 5.0".to_string(),
+            "picker.offer_group: null -> \"brick\"".to_string(),
+        ]
+    );
+    assert!(receiver.has_changed().unwrap());
+    assert_eq!(receiver.borrow_and_update().picker.interval_secs, 5.0);
+
+    assert!(shared.set(config).is_empty());
+    assert!(!receiver.has_changed().unwrap());
+}
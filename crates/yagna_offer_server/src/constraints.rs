@@ -0,0 +1,508 @@
+use crate::model::demand::base::DemandSubscription;
+use crate::model::flatten::flatten;
+use crate::model::offer::base::GolemBaseOffer;
+use anyhow::bail;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::fmt::Display;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Equal,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Parsed Golem constraint expression, LDAP filter syntax, for example
+/// `(&(golem.inf.mem.gib>=8)(golem.node.debug.subnet=public))`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    /// Empty expression `()`, accepts everything
+    Any,
+    And(Vec<Constraint>),
+    Or(Vec<Constraint>),
+    Not(Box<Constraint>),
+    /// `(property=*)`
+    Present(String),
+    Compare {
+        property: String,
+        operator: Operator,
+        value: String,
+    },
+}
+
+impl FromStr for Constraint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        parser.skip_whitespace();
+        if parser.pos == parser.chars.len() {
+            return Ok(Constraint::Any);
+        }
+        let constraint = parser.parse_filter()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.chars.len() {
+            bail!(
+                "Unexpected characters after expression at position {}",
+                parser.pos
+            );
+        }
+        Ok(constraint)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map(|c| c.is_whitespace()).unwrap_or(false) {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> anyhow::Result<()> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(c) => bail!(
+                "Expected '{}' at position {}, found '{}'",
+                expected,
+                self.pos,
+                c
+            ),
+            None => bail!("Expected '{}', found end of expression", expected),
+        }
+    }
+
+    fn parse_filter(&mut self) -> anyhow::Result<Constraint> {
+        self.expect('(')?;
+        self.skip_whitespace();
+        let constraint = match self.peek() {
+            Some(')') => Constraint::Any,
+            Some('&') => {
+                self.pos += 1;
+                Constraint::And(self.parse_filter_list()?)
+            }
+            Some('|') => {
+                self.pos += 1;
+                Constraint::Or(self.parse_filter_list()?)
+            }
+            Some('!') => {
+                self.pos += 1;
+                Constraint::Not(Box::new(self.parse_filter()?))
+            }
+            Some(_) => self.parse_item()?,
+            None => bail!("Unexpected end of expression"),
+        };
+        self.expect(')')?;
+        Ok(constraint)
+    }
+
+    fn parse_filter_list(&mut self) -> anyhow::Result<Vec<Constraint>> {
+        let mut list = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some('(') => list.push(self.parse_filter()?),
+                _ => return Ok(list),
+            }
+        }
+    }
+
+    fn parse_item(&mut self) -> anyhow::Result<Constraint> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if matches!(c, '=' | '<' | '>' | '(' | ')') {
+                break;
+            }
+            self.pos += 1;
+        }
+        let property: String = self.chars[start..self.pos].iter().collect();
+        let property = property.trim().to_string();
+        if property.is_empty() {
+            bail!("Missing property name at position {}", start);
+        }
+
+        let operator = match (self.peek(), self.chars.get(self.pos + 1).copied()) {
+            (Some('='), _) => Operator::Equal,
+            (Some('<'), Some('=')) => Operator::LessOrEqual,
+            (Some('<'), _) => Operator::Less,
+            (Some('>'), Some('=')) => Operator::GreaterOrEqual,
+            (Some('>'), _) => Operator::Greater,
+            _ => bail!(
+                "Expected operator after property {} at position {}",
+                property,
+                self.pos
+            ),
+        };
+        self.pos += match operator {
+            Operator::LessOrEqual | Operator::GreaterOrEqual => 2,
+            _ => 1,
+        };
+
+        let mut value = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                ')' => break,
+                '(' => bail!("Unexpected '(' in value at position {}", self.pos),
+                '\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(escaped) => value.push(escaped),
+                        None => bail!("Unexpected end of expression after '\\'"),
+                    }
+                }
+                c => value.push(c),
+            }
+            self.pos += 1;
+        }
+        let value = value.trim().to_string();
+
+        if operator == Operator::Equal && value == "*" {
+            return Ok(Constraint::Present(property));
+        }
+        Ok(Constraint::Compare {
+            property,
+            operator,
+            value,
+        })
+    }
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+    let mut rest = text;
+    for (idx, part) in parts.iter().enumerate() {
+        if idx == 0 {
+            match rest.strip_prefix(part) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        } else if idx == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else {
+            match rest.find(part) {
+                Some(found) => rest = &rest[found + part.len()..],
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+fn compare_value(property_value: &Value, operator: Operator, value: &str) -> Option<bool> {
+    let ordering = match property_value {
+        Value::Null | Value::Object(_) => return None,
+        Value::Array(items) => {
+            if operator != Operator::Equal {
+                return None;
+            }
+            return Some(
+                items
+                    .iter()
+                    .any(|item| compare_value(item, operator, value) == Some(true)),
+            );
+        }
+        Value::Bool(b) => {
+            if operator != Operator::Equal {
+                return None;
+            }
+            return bool::from_str(&value.to_lowercase())
+                .ok()
+                .map(|parsed| parsed == *b);
+        }
+        Value::Number(n) => n.as_f64()?.partial_cmp(&value.parse::<f64>().ok()?)?,
+        Value::String(s) => {
+            if operator == Operator::Equal {
+                return Some(wildcard_match(value, s));
+            }
+            match (s.parse::<f64>(), value.parse::<f64>()) {
+                (Ok(left), Ok(right)) => left.partial_cmp(&right)?,
+                _ => s.as_str().cmp(value),
+            }
+        }
+    };
+    Some(match operator {
+        Operator::Equal => ordering == Ordering::Equal,
+        Operator::Less => ordering == Ordering::Less,
+        Operator::LessOrEqual => ordering != Ordering::Greater,
+        Operator::Greater => ordering == Ordering::Greater,
+        Operator::GreaterOrEqual => ordering != Ordering::Less,
+    })
+}
+
+impl Constraint {
+    /// Three-valued evaluation as in LDAP, None means undefined
+    /// (property missing or not comparable with the value).
+    fn evaluate(&self, properties: &Map<String, Value>) -> Option<bool> {
+        match self {
+            Constraint::Any => Some(true),
+            Constraint::And(list) => {
+                let mut result = Some(true);
+                for constraint in list {
+                    match constraint.evaluate(properties) {
+                        Some(false) => return Some(false),
+                        None => result = None,
+                        Some(true) => {}
+                    }
+                }
+                result
+            }
+            Constraint::Or(list) => {
+                let mut result = Some(false);
+                for constraint in list {
+                    match constraint.evaluate(properties) {
+                        Some(true) => return Some(true),
+                        None => result = None,
+                        Some(false) => {}
+                    }
+                }
+                result
+            }
+            Constraint::Not(constraint) => constraint.evaluate(properties).map(|r| !r),
+            Constraint::Present(property) => Some(
+                properties
+                    .get(property)
+                    .map(|v| !v.is_null())
+                    .unwrap_or(false),
+            ),
+            Constraint::Compare {
+                property,
+                operator,
+                value,
+            } => compare_value(properties.get(property)?, *operator, value),
+        }
+    }
+
+    /// Only definite match is accepted, undefined result is treated as no match
+    pub fn matches(&self, properties: &Map<String, Value>) -> bool {
+        self.evaluate(properties) == Some(true)
+    }
+}
+
+impl Display for Operator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let op = match self {
+            Operator::Equal => "=",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+        };
+        write!(f, "{}", op)
+    }
+}
+
+impl Display for Constraint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constraint::Any => write!(f, "()"),
+            Constraint::And(list) | Constraint::Or(list) => {
+                let op = if matches!(self, Constraint::And(_)) {
+                    '&'
+                } else {
+                    '|'
+                };
+                write!(f, "({}", op)?;
+                for constraint in list {
+                    write!(f, "{}", constraint)?;
+                }
+                write!(f, ")")
+            }
+            Constraint::Not(constraint) => write!(f, "(!{})", constraint),
+            Constraint::Present(property) => write!(f, "({}=*)", property),
+            Constraint::Compare {
+                property,
+                operator,
+                value,
+            } => {
+                let escaped = value
+                    .replace('\\', "\\\\")
+                    .replace('(', "\\(")
+                    .replace(')', "\\)");
+                write!(f, "({}{}{})", property, operator, escaped)
+            }
+        }
+    }
+}
+
+pub fn offer_flat_properties(offer: &GolemBaseOffer) -> Map<String, Value> {
+    flatten(serde_json::to_value(&offer.properties).unwrap_or_default())
+}
+
+/// Offer side of the matching, kept with the offer so it is not computed for every demand
+#[derive(Debug)]
+pub struct OfferMatching {
+    properties: Map<String, Value>,
+    /// None if the offer constraints are invalid, such offer never matches
+    constraints: Option<Constraint>,
+}
+
+impl OfferMatching {
+    pub fn new(offer: &GolemBaseOffer) -> Self {
+        let constraints = match Constraint::from_str(&offer.constraints) {
+            Ok(constraints) => Some(constraints),
+            Err(e) => {
+                log::debug!("Invalid constraints in offer {}: {}", offer.id, e);
+                None
+            }
+        };
+        Self {
+            properties: offer_flat_properties(offer),
+            constraints,
+        }
+    }
+}
+
+/// Demand properties are sent by yagna as JSON string, usually already flat
+pub fn demand_flat_properties(demand: &DemandSubscription) -> anyhow::Result<Map<String, Value>> {
+    Ok(flatten(serde_json::from_str::<Value>(&demand.properties)?))
+}
+
+/// Demand side of the matching, parsed once and checked against many offers
+#[derive(Debug, Clone)]
+pub struct DemandMatcher {
+    constraints: Constraint,
+    properties: Map<String, Value>,
+}
+
+impl DemandMatcher {
+    pub fn new(demand: &DemandSubscription) -> anyhow::Result<Self> {
+        let constraints = match Constraint::from_str(&demand.constraints) {
+            Ok(constraints) => constraints,
+            Err(e) => bail!("Invalid constraints in demand {}: {}", demand.id, e),
+        };
+        let properties = match demand_flat_properties(demand) {
+            Ok(properties) => properties,
+            Err(e) => bail!("Invalid properties in demand {}: {}", demand.id, e),
+        };
+        Ok(Self {
+            constraints,
+            properties,
+        })
+    }
+
+    /// Demand constraints have to accept offer properties and offer constraints
+    /// have to accept demand properties.
+    pub fn matches(&self, offer: &OfferMatching) -> bool {
+        if !self.constraints.matches(&offer.properties) {
+            return false;
+        }
+        offer
+            .constraints
+            .as_ref()
+            .is_some_and(|offer_constraints| offer_constraints.matches(&self.properties))
+    }
+}
+
+#[test]
+fn test_parse_constraints() {
+    let parsed = Constraint::from_str(
+        "(&\n  (golem.srv.comp.expiration>1765401640654)\n  (golem.node.debug.subnet=public)\n)",
+    )
+    .unwrap();
+    assert_eq!(
+        parsed,
+        Constraint::And(vec![
+            Constraint::Compare {
+                property: "golem.srv.comp.expiration".to_string(),
+                operator: Operator::Greater,
+                value: "1765401640654".to_string(),
+            },
+            Constraint::Compare {
+                property: "golem.node.debug.subnet".to_string(),
+                operator: Operator::Equal,
+                value: "public".to_string(),
+            },
+        ])
+    );
+    assert_eq!(Constraint::from_str("()").unwrap(), Constraint::Any);
+    assert_eq!(Constraint::from_str("  ").unwrap(), Constraint::Any);
+
+    let parsed = Constraint::from_str("(|(a<=1)(!(b=*))(c=x\\)y))").unwrap();
+    assert_eq!(parsed.to_string(), "(|(a<=1)(!(b=*))(c=x\\)y))");
+
+    assert!(Constraint::from_str("(&(a=1)").is_err());
+    assert!(Constraint::from_str("(a=1))").is_err());
+    assert!(Constraint::from_str("(=1)").is_err());
+    assert!(Constraint::from_str("(a~1)").is_err());
+}
+
+#[test]
+fn test_constraints_match_offer() {
+    use crate::test_util::golem_offer;
+
+    let gbo = golem_offer("offer", 1);
+    let properties = offer_flat_properties(&gbo);
+
+    let check = |expr: &str| Constraint::from_str(expr).unwrap().matches(&properties);
+    assert!(check(
+        "(&(golem.inf.mem.gib>=8)(golem.node.debug.subnet=public))"
+    ));
+    assert!(check("(golem.com.pricing.model=linear)"));
+    assert!(check("(golem.runtime.name=ya-runtime-*)"));
+    assert!(check("(golem.com.usage.vector=golem.usage.cpu_sec)"));
+    assert!(check(
+        "(golem.com.payment.platform.erc20-polygon-glm.address=*)"
+    ));
+    assert!(check("(!(golem.node.net.is-public=true))"));
+    assert!(check(
+        "(|(golem.inf.cpu.cores>100)(golem.inf.cpu.threads=1))"
+    ));
+    assert!(!check("(golem.inf.mem.gib>64)"));
+    assert!(!check(
+        "(golem.com.payment.platform.erc20-hoodi-tglm.address=*)"
+    ));
+    // missing property is undefined, negation of undefined is still undefined
+    assert!(!check("(golem.missing=1)"));
+    assert!(!check("(!(golem.missing=1))"));
+    assert!(check("(|(golem.missing=1)(golem.inf.cpu.cores=14))"));
+}
+
+#[test]
+fn test_demand_matcher_uses_offer_matching() {
+    use crate::state::OfferObj;
+    use crate::test_util::{demand, golem_offer};
+
+    let mut demand = demand("demand", 0x11, chrono::Utc::now());
+    demand.properties = r#"{"golem.srv.comp.expiration": 1765401640654}"#.to_string();
+    demand.constraints = "(golem.inf.mem.gib>=8)".to_string();
+    let matcher = DemandMatcher::new(&demand).unwrap();
+
+    let mut gbo = golem_offer("offer", 1);
+    gbo.constraints = "(golem.srv.comp.expiration>1)".to_string();
+    let mut offer_obj = OfferObj::new(gbo);
+    assert!(matcher.matches(offer_obj.matching()));
+
+    // matching data is kept until the offer is refreshed
+    offer_obj.offer.constraints = "(golem.srv.comp.expiration=".to_string();
+    assert!(matcher.matches(offer_obj.matching()));
+    offer_obj.refresh_derived();
+    assert!(!matcher.matches(offer_obj.matching()));
+
+    // offers read back from storage compute it on first use
+    offer_obj.offer.constraints = "()".to_string();
+    let decoded =
+        serde_json::from_str::<OfferObj>(&serde_json::to_string(&offer_obj).unwrap()).unwrap();
+    assert!(matcher.matches(decoded.matching()));
+}
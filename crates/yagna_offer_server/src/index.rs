@@ -0,0 +1,277 @@
+use crate::state::OfferObj;
+use chrono::{DateTime, Utc};
+use std::collections::{BTreeSet, HashMap};
+use ya_client_model::NodeId;
+
+/// Group of the provider node, prefix of its name before the first `-`, e.g. `brick`
+pub fn node_name_group(node_name: &str) -> &str {
+    node_name.split('-').next().unwrap_or("N/A")
+}
+
+/// Indexed conditions of offer lookup, offers have to satisfy all of the given ones.
+/// Other conditions are checked by the caller on returned candidates.
+#[derive(Debug, Clone, Default)]
+pub struct OfferQuery {
+    pub exe_name: Option<String>,
+    pub subnet: Option<String>,
+    pub cpu_architecture: Option<String>,
+    pub provider_id: Option<NodeId>,
+    /// Accepted node name groups, see [`node_name_group`]
+    pub node_groups: Option<Vec<String>>,
+    /// Only offers not assigned to any requestor
+    pub available: bool,
+    /// Only offers expiring after that time
+    pub not_expired_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone)]
+struct IndexEntry {
+    exe_name: String,
+    subnet: String,
+    cpu_architecture: String,
+    provider_id: NodeId,
+    node_group: String,
+    available: bool,
+    expiration: DateTime<Utc>,
+}
+
+impl IndexEntry {
+    fn new(offer_obj: &OfferObj) -> Self {
+        Self {
+            exe_name: offer_obj.attributes.exe_name.clone(),
+            subnet: offer_obj.attributes.subnet.clone(),
+            cpu_architecture: offer_obj.attributes.cpu_architecture.clone(),
+            provider_id: offer_obj.offer.provider_id,
+            node_group: node_name_group(&offer_obj.attributes.node_name).to_string(),
+            available: offer_obj.requestor_id.is_none(),
+            expiration: offer_obj.offer.expiration,
+        }
+    }
+}
+
+type IdSet = BTreeSet<String>;
+
+fn add_to<K: std::hash::Hash + Eq>(map: &mut HashMap<K, IdSet>, key: K, id: &str) {
+    map.entry(key).or_default().insert(id.to_string());
+}
+
+fn remove_from<K: std::hash::Hash + Eq>(map: &mut HashMap<K, IdSet>, key: &K, id: &str) {
+    if let Some(ids) = map.get_mut(key) {
+        ids.remove(id);
+        if ids.is_empty() {
+            map.remove(key);
+        }
+    }
+}
+
+/// Ids under the key, None if the query does not restrict that attribute
+fn lookup<'a, K: std::hash::Hash + Eq>(
+    map: &'a HashMap<K, IdSet>,
+    key: Option<&K>,
+    empty: &'a IdSet,
+) -> Option<&'a IdSet> {
+    key.map(|key| map.get(key).unwrap_or(empty))
+}
+
+/// Secondary indexes of offers kept in memory, the sqlite storage uses its own. Sets of ids
+/// are ordered, so candidates come in the same order as from a full scan of the store.
+#[derive(Debug, Clone, Default)]
+pub struct OfferIndex {
+    entries: HashMap<String, IndexEntry>,
+    all: IdSet,
+    by_exe_name: HashMap<String, IdSet>,
+    by_subnet: HashMap<String, IdSet>,
+    by_cpu_architecture: HashMap<String, IdSet>,
+    by_provider_id: HashMap<NodeId, IdSet>,
+    by_node_group: HashMap<String, IdSet>,
+    available: IdSet,
+    by_expiration: BTreeSet<(DateTime<Utc>, String)>,
+}
+
+impl OfferIndex {
+    pub fn insert(&mut self, offer_obj: &OfferObj) {
+        let id = offer_obj.offer.id.as_str();
+        self.remove(id);
+        let entry = IndexEntry::new(offer_obj);
+        self.all.insert(id.to_string());
+        add_to(&mut self.by_exe_name, entry.exe_name.clone(), id);
+        add_to(&mut self.by_subnet, entry.subnet.clone(), id);
+        add_to(
+            &mut self.by_cpu_architecture,
+            entry.cpu_architecture.clone(),
+            id,
+        );
+        add_to(&mut self.by_provider_id, entry.provider_id, id);
+        add_to(&mut self.by_node_group, entry.node_group.clone(), id);
+        if entry.available {
+            self.available.insert(id.to_string());
+        }
+        self.by_expiration
+            .insert((entry.expiration, id.to_string()));
+        self.entries.insert(id.to_string(), entry);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        let Some(entry) = self.entries.remove(id) else {
+            return;
+        };
+        self.all.remove(id);
+        remove_from(&mut self.by_exe_name, &entry.exe_name, id);
+        remove_from(&mut self.by_subnet, &entry.subnet, id);
+        remove_from(&mut self.by_cpu_architecture, &entry.cpu_architecture, id);
+        remove_from(&mut self.by_provider_id, &entry.provider_id, id);
+        remove_from(&mut self.by_node_group, &entry.node_group, id);
+        self.available.remove(id);
+        self.by_expiration
+            .remove(&(entry.expiration, id.to_string()));
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Node name groups of all offers
+    pub fn node_groups(&self) -> Vec<String> {
+        let mut groups: Vec<String> = self.by_node_group.keys().cloned().collect();
+        groups.sort();
+        groups
+    }
+
+    /// Ids of offers expiring at or before the given time, the soonest first
+    pub fn expiring_before(&self, time: DateTime<Utc>) -> Vec<String> {
+        self.by_expiration
+            .iter()
+            .take_while(|(expiration, _)| *expiration <= time)
+            .map(|(_, id)| id.clone())
+            .collect()
+    }
+
+    /// Ids of offers satisfying the query, ordered by id. Cost is proportional to the size
+    /// of the smallest matching index entry, not to the number of all offers.
+    pub fn query(&self, query: &OfferQuery) -> Vec<String> {
+        let empty = IdSet::new();
+        let groups: Option<IdSet> = query.node_groups.as_ref().map(|groups| {
+            groups
+                .iter()
+                .filter_map(|group| self.by_node_group.get(group))
+                .flatten()
+                .cloned()
+                .collect()
+        });
+        let mut sets: Vec<&IdSet> = [
+            lookup(&self.by_exe_name, query.exe_name.as_ref(), &empty),
+            lookup(&self.by_subnet, query.subnet.as_ref(), &empty),
+            lookup(
+                &self.by_cpu_architecture,
+                query.cpu_architecture.as_ref(),
+                &empty,
+            ),
+            lookup(&self.by_provider_id, query.provider_id.as_ref(), &empty),
+            groups.as_ref(),
+            query.available.then_some(&self.available),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        sets.sort_by_key(|set| set.len());
+
+        let (smallest, others) = match sets.split_first() {
+            Some((smallest, others)) => (*smallest, others),
+            None => (&self.all, &[][..]),
+        };
+        smallest
+            .iter()
+            .filter(|id| others.iter().all(|set| set.contains(*id)))
+            .filter(|id| match query.not_expired_at {
+                Some(now) => self
+                    .entries
+                    .get(*id)
+                    .is_some_and(|entry| entry.expiration >= now),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+#[test]
+fn test_offer_index_query() {
+    use crate::test_util::node_offer as offer;
+
+    let mut index = OfferIndex::default();
+    index.insert(&offer("a", 1, "brick-1"));
+    index.insert(&offer("b", 2, "brick-2"));
+    let mut taken = offer("c", 3, "stone-1");
+    taken.assign(NodeId::from([9; 20]));
+    index.insert(&taken);
+    assert_eq!(index.len(), 3);
+    assert_eq!(index.node_groups(), vec!["brick", "stone"]);
+
+    assert_eq!(index.query(&OfferQuery::default()), vec!["a", "b", "c"]);
+    assert_eq!(
+        index.query(&OfferQuery {
+            available: true,
+            ..Default::default()
+        }),
+        vec!["a", "b"]
+    );
+    assert_eq!(
+        index.query(&OfferQuery {
+            exe_name: Some("ya-runtime-cruncher".to_string()),
+            node_groups: Some(vec!["stone".to_string()]),
+            ..Default::default()
+        }),
+        vec!["c"]
+    );
+    assert!(index
+        .query(&OfferQuery {
+            provider_id: Some(NodeId::from([3; 20])),
+            available: true,
+            ..Default::default()
+        })
+        .is_empty());
+    assert!(index
+        .query(&OfferQuery {
+            subnet: Some("private".to_string()),
+            ..Default::default()
+        })
+        .is_empty());
+
+    // the same offer inserted again replaces its index entries
+    taken.release();
+    index.insert(&taken);
+    assert_eq!(
+        index.query(&OfferQuery {
+            available: true,
+            ..Default::default()
+        }),
+        vec!["a", "b", "c"]
+    );
+    let expiration = taken.offer.expiration;
+    assert_eq!(index.expiring_before(expiration).len(), 3);
+    assert!(index
+        .query(&OfferQuery {
+            not_expired_at: Some(expiration + chrono::Duration::seconds(1)),
+            ..Default::default()
+        })
+        .is_empty());
+
+    index.remove("a");
+    index.remove("missing");
+    assert_eq!(index.query(&OfferQuery::default()), vec!["b", "c"]);
+    assert_eq!(
+        index.query(&OfferQuery {
+            provider_id: Some(NodeId::from([1; 20])),
+            ..Default::default()
+        }),
+        Vec::<String>::new()
+    );
+}
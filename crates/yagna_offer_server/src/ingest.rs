@@ -0,0 +1,448 @@
+use crate::config::ValidationConfig;
+use crate::constraints::Constraint;
+use crate::index::OfferQuery;
+use crate::metrics::{IngestCounts, Metrics};
+use crate::model::offer::base::GolemBaseOffer;
+use crate::model::offer::properties::OfferProperties;
+use crate::state::{OfferObj, Offers};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use ya_client_model::NodeId;
+
+/// What happened with a single offer passed to [`ingest_offers`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase", tag = "outcome")]
+pub enum IngestOutcome {
+    Added,
+    /// Added, older offer of the same provider was removed
+    #[serde(rename_all = "camelCase")]
+    Replaced {
+        previous_id: String,
+    },
+    /// Offer with the same id is already in the store
+    AlreadyPresent,
+    /// Newer (or the same age) offer of the provider is already in the store
+    #[serde(rename_all = "camelCase")]
+    Outdated {
+        newer_id: String,
+    },
+    Rejected {
+        errors: Vec<ValidationError>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestResult {
+    pub offer_id: String,
+    #[serde(flatten)]
+    pub outcome: IngestOutcome,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IngestReport {
+    pub results: Vec<IngestResult>,
+    pub counts: IngestCounts,
+}
+
+/// Failed validation rule, `field` is the path of the offending field in offer JSON
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationError {
+    pub rule: &'static str,
+    pub field: String,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub fn new(rule: &'static str, field: &str, message: String) -> Self {
+        Self {
+            rule,
+            field: field.to_string(),
+            message,
+        }
+    }
+}
+
+/// Decodes offer pushed by a provider, error points to the field that failed to parse
+pub fn decode_offer(body: &str) -> Result<GolemBaseOffer, ValidationError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(body);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        let mut error = ValidationError {
+            rule: "format",
+            field: if field == "." { String::new() } else { field },
+            message: e.into_inner().to_string(),
+        };
+        // path inside properties is lost when they are parsed, parse them again to find it
+        if error.field == "properties" {
+            if let Ok(offer) = serde_json::from_str::<serde_json::Value>(body) {
+                if let Err(e) = OfferProperties::new(offer["properties"].clone()) {
+                    error.field = format!("properties.{}", e.path());
+                    error.message = e.into_inner().to_string();
+                }
+            }
+        }
+        error
+    })
+}
+
+/// Checks offer before it is stored, returns every failed rule
+pub fn validate_offer(
+    offer: &GolemBaseOffer,
+    rules: &ValidationConfig,
+    now: DateTime<Utc>,
+) -> Vec<ValidationError> {
+    let mut errors = Vec::new();
+    if offer.id.is_empty() {
+        errors.push(ValidationError::new(
+            "id",
+            "id",
+            "Offer id is empty".to_string(),
+        ));
+    }
+    if offer.expiration <= now {
+        errors.push(ValidationError::new(
+            "expiration",
+            "expiration",
+            format!("Offer expired at {}", offer.expiration),
+        ));
+    } else if offer.expiration > now + chrono::Duration::seconds(rules.max_lifetime_secs) {
+        errors.push(ValidationError::new(
+            "expiration",
+            "expiration",
+            format!(
+                "Offer expires at {}, more than {} seconds from now",
+                offer.expiration, rules.max_lifetime_secs
+            ),
+        ));
+    }
+    if offer.expiration <= offer.timestamp {
+        errors.push(ValidationError::new(
+            "expiration",
+            "expiration",
+            format!(
+                "Offer expiration {} is not after its timestamp {}",
+                offer.expiration, offer.timestamp
+            ),
+        ));
+    }
+    if offer.timestamp > now + chrono::Duration::seconds(rules.max_clock_skew_secs) {
+        errors.push(ValidationError::new(
+            "clockSkew",
+            "timestamp",
+            format!(
+                "Offer timestamp {} is in the future, check clock of the provider",
+                offer.timestamp
+            ),
+        ));
+    }
+    if let Err(e) = Constraint::from_str(&offer.constraints) {
+        errors.push(ValidationError::new(
+            "constraints",
+            "constraints",
+            format!("Invalid constraints: {}", e),
+        ));
+    }
+
+    let platforms = offer.properties.golem.com.payment.platform.addresses();
+    if platforms.is_empty() {
+        errors.push(ValidationError::new(
+            "paymentPlatform",
+            "properties.golem.com.payment.platform",
+            "Offer has no payment platform".to_string(),
+        ));
+    }
+    for (name, address) in platforms {
+        let address = address.unwrap_or_default();
+        if NodeId::from_str(address).ok() != Some(offer.provider_id) {
+            errors.push(ValidationError::new(
+                "paymentPlatform",
+                &format!("properties.golem.com.payment.platform.{}.address", name),
+                format!(
+                    "Payment address {} does not match provider id {}",
+                    address, offer.provider_id
+                ),
+            ));
+        }
+    }
+
+    if offer.properties.golem.runtime.name.trim().is_empty() {
+        errors.push(ValidationError::new(
+            "runtimeName",
+            "properties.golem.runtime.name",
+            "Runtime name is empty".to_string(),
+        ));
+    }
+    errors
+}
+
+/// Single path of offers into the store, used by provider pushes and mirror sources.
+/// Offers are validated, duplicates are skipped and only the newest offer of every
+/// provider is kept, no matter where it came from. Attributes are computed here,
+/// so they do not depend on the server the offer was downloaded from.
+pub async fn ingest_offers(
+    lock: &mut Offers,
+    metrics: &Metrics,
+    rules: &ValidationConfig,
+    offers: Vec<OfferObj>,
+) -> anyhow::Result<IngestReport> {
+    let mut report = IngestReport::default();
+    if offers.is_empty() {
+        return Ok(report);
+    }
+
+    //build map of existing by provider_id, only incoming providers are looked up
+    let mut by_provider_id: HashMap<NodeId, (String, DateTime<Utc>)> = HashMap::new();
+    let provider_ids: HashSet<NodeId> = offers
+        .iter()
+        .map(|offer_obj| offer_obj.offer.provider_id)
+        .collect();
+    for provider_id in provider_ids {
+        let query = OfferQuery {
+            provider_id: Some(provider_id),
+            ..Default::default()
+        };
+        for offer_obj in lock.query(&query, |_| true).await? {
+            let res = by_provider_id.insert(
+                provider_id,
+                (offer_obj.offer.id.clone(), offer_obj.offer.timestamp),
+            );
+            if res.is_some() {
+                log::warn!("Multiple existing offers from provider {}", provider_id);
+            }
+        }
+    }
+
+    let now = Utc::now();
+    for mut offer_obj in offers {
+        let offer_id = offer_obj.offer.id.clone();
+        let errors = validate_offer(&offer_obj.offer, rules, now);
+        let outcome = if !errors.is_empty() {
+            IngestOutcome::Rejected { errors }
+        } else if lock.contains(&offer_id).await? {
+            IngestOutcome::AlreadyPresent
+        } else {
+            let provider_id = offer_obj.offer.provider_id;
+            let timestamp = offer_obj.offer.timestamp;
+            let outcome = match by_provider_id.get(&provider_id) {
+                Some((existing_id, existing_timestamp)) if *existing_timestamp >= timestamp => {
+                    IngestOutcome::Outdated {
+                        newer_id: existing_id.clone(),
+                    }
+                }
+                Some((existing_id, _)) => {
+                    lock.remove(existing_id).await?;
+                    IngestOutcome::Replaced {
+                        previous_id: existing_id.clone(),
+                    }
+                }
+                None => IngestOutcome::Added,
+            };
+            if !matches!(outcome, IngestOutcome::Outdated { .. }) {
+                offer_obj.refresh_derived();
+                lock.insert(offer_obj).await?;
+                by_provider_id.insert(provider_id, (offer_id.clone(), timestamp));
+            }
+            outcome
+        };
+        match &outcome {
+            IngestOutcome::Added => report.counts.added += 1,
+            IngestOutcome::Replaced { .. } => {
+                report.counts.added += 1;
+                report.counts.removed += 1;
+            }
+            IngestOutcome::AlreadyPresent => report.counts.already_present += 1,
+            IngestOutcome::Outdated { .. } => report.counts.ignored += 1,
+            IngestOutcome::Rejected { errors } => {
+                log::debug!(
+                    "Offer {} rejected: {}",
+                    offer_id,
+                    errors
+                        .iter()
+                        .map(|error| error.message.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                report.counts.rejected += 1;
+            }
+        }
+        report.results.push(IngestResult { offer_id, outcome });
+    }
+    metrics.record_ingest(report.counts);
+    Ok(report)
+}
+
+#[tokio::test]
+async fn test_ingest_offers_from_sources() {
+    use crate::test_util::golem_offer;
+
+    let from_source = |name: Option<&str>, id: &str, age_secs: i64| {
+        let mut gbo = golem_offer(id, 1);
+        gbo.timestamp = Utc::now() - chrono::Duration::seconds(age_secs);
+        let mut offer_obj = OfferObj::new(gbo);
+        offer_obj.source = name.map(str::to_string);
+        offer_obj
+    };
+    let metrics = Metrics::default();
+    let rules = ValidationConfig::default();
+
+    let mut offers = Offers::default();
+    let report = ingest_offers(
+        &mut offers,
+        &metrics,
+        &rules,
+        vec![from_source(Some("eu"), "old", 60)],
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.counts.added, 1);
+    assert_eq!(report.results[0].outcome, IngestOutcome::Added);
+
+    // the same provider, newer offer from other source replaces the old one
+    let report = ingest_offers(
+        &mut offers,
+        &metrics,
+        &rules,
+        vec![
+            from_source(Some("us"), "new", 0),
+            from_source(Some("us"), "older", 120),
+        ],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        report.counts,
+        IngestCounts {
+            added: 1,
+            removed: 1,
+            ignored: 1,
+            already_present: 0,
+            rejected: 0,
+        }
+    );
+    assert_eq!(
+        report.results[0].outcome,
+        IngestOutcome::Replaced {
+            previous_id: "old".to_string()
+        }
+    );
+    assert_eq!(
+        report.results[1].outcome,
+        IngestOutcome::Outdated {
+            newer_id: "new".to_string()
+        }
+    );
+    let all = offers.all().await.unwrap();
+    assert_eq!(all.len(), 1);
+    assert_eq!(all[0].offer.id, "new");
+    assert_eq!(all[0].source.as_deref(), Some("us"));
+
+    // already known offer is not replaced by the copy from the other source
+    let report = ingest_offers(
+        &mut offers,
+        &metrics,
+        &rules,
+        vec![from_source(Some("eu"), "new", 0)],
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.counts.already_present, 1);
+    assert_eq!(offers.all().await.unwrap()[0].source.as_deref(), Some("us"));
+
+    // pushed offer follows the same rules
+    let mut invalid = from_source(None, "pushed", -60);
+    invalid
+        .offer
+        .properties
+        .set("golem.runtime.name", "".into())
+        .unwrap();
+    let report = ingest_offers(
+        &mut offers,
+        &metrics,
+        &rules,
+        vec![invalid, from_source(None, "pushed", -60)],
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+        report.results[0].outcome,
+        IngestOutcome::Rejected { .. }
+    ));
+    assert_eq!(report.counts.rejected, 1);
+    assert_eq!(report.counts.added, 1);
+    let all = offers.all().await.unwrap();
+    assert_eq!(all.len(), 1);
+    assert_eq!(all[0].offer.id, "pushed");
+    assert_eq!(
+        serde_json::to_value(&report.results[1]).unwrap(),
+        serde_json::json!({"offerId": "pushed", "outcome": "replaced", "previousId": "new"})
+    );
+}
+
+#[test]
+fn test_validate_offer() {
+    use crate::model::offer::base::EXAMPLE_OFFER_JSON;
+
+    let rules = ValidationConfig::default();
+    let mut gbo = decode_offer(EXAMPLE_OFFER_JSON).unwrap();
+    let now = gbo.timestamp + chrono::Duration::minutes(5);
+    assert_eq!(validate_offer(&gbo, &rules, now), Vec::new());
+
+    let failed = |gbo: &GolemBaseOffer, now: DateTime<Utc>| {
+        validate_offer(gbo, &rules, now)
+            .into_iter()
+            .map(|error| (error.rule, error.field))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        failed(&gbo, gbo.expiration),
+        vec![("expiration", "expiration".to_string())]
+    );
+    assert_eq!(
+        failed(&gbo, gbo.timestamp - chrono::Duration::minutes(10)),
+        vec![("clockSkew", "timestamp".to_string())]
+    );
+    let long_lived = gbo.timestamp + chrono::Duration::seconds(rules.max_lifetime_secs);
+    let mut expires_late = gbo.clone();
+    expires_late.expiration = long_lived + chrono::Duration::hours(1);
+    assert_eq!(
+        failed(&expires_late, now),
+        vec![("expiration", "expiration".to_string())]
+    );
+
+    gbo.constraints = "(&(golem.srv.comp.expiration>1)".to_string();
+    gbo.provider_id = NodeId::from([1u8; 20]);
+    gbo.properties
+        .set("golem.runtime.name", " ".into())
+        .unwrap();
+    assert_eq!(
+        failed(&gbo, now),
+        vec![
+            ("constraints", "constraints".to_string()),
+            (
+                "paymentPlatform",
+                "properties.golem.com.payment.platform.erc20-polygon-glm.address".to_string()
+            ),
+            ("runtimeName", "properties.golem.runtime.name".to_string()),
+        ]
+    );
+    gbo.properties
+        .set("golem.com.payment.platform", serde_json::json!({}))
+        .unwrap();
+    assert_eq!(
+        failed(&gbo, now)[1],
+        (
+            "paymentPlatform",
+            "properties.golem.com.payment.platform".to_string()
+        )
+    );
+
+    let error = decode_offer(&EXAMPLE_OFFER_JSON.replace("\"cores\":14", "\"cores\":\"many\""))
+        .unwrap_err();
+    assert_eq!(error.rule, "format");
+    assert_eq!(error.field, "properties.golem.inf.cpu.cores");
+    assert_eq!(decode_offer("[]").unwrap_err().field, "");
+}
@@ -1,25 +1,64 @@
+pub mod arkiv;
+pub mod auth;
+pub mod config;
+pub mod constraints;
+pub mod index;
+pub mod ingest;
+pub mod metrics;
 pub mod model;
 pub mod offers;
+pub mod persistence;
 pub mod rest;
+pub mod scheduler;
+pub mod selection;
 pub mod state;
+pub mod storage;
+pub mod store;
+#[cfg(test)]
+mod test_util;
 
-use crate::offers::download_offers_from_mirror;
+use crate::auth::{admin_validator, AdminIdentity, AdminTokens, SignatureVerifier};
+use crate::config::{Config, CorsConfig, SharedConfig};
+use crate::index::OfferQuery;
+use crate::metrics::metrics;
+use crate::model::offer::pricing::OfferOrder;
+use crate::offers::synchronize_due_sources;
+use crate::persistence::{load_state, restore_state, save_state};
+use crate::rest::admin::{delete_demand, delete_offer, reload_config, reset_counters};
 use crate::rest::demand::add_offer_to_demand::add_offer_to_demand;
 use crate::rest::demand::cancel_demand::demand_cancel;
 use crate::rest::demand::demand_new::demand_new;
 use crate::rest::demand::list_demands::list_demands;
 use crate::rest::demand::pick_offer_to_demand::pick_offer_to_demand;
-use crate::rest::demand::pick_offers_for_all_demands;
+use crate::rest::demand::stream_offers::stream_offers;
 use crate::rest::demand::take_offer_from_queue::take_offer_from_queue;
+use crate::rest::demand::{
+    list_requestor_shares, pick_offers_for_all_demands, release_queued_offers,
+};
 use crate::rest::offer::clean_old_offers::{clean_old_offers, delete_all_offers};
-use crate::rest::offer::list_offers::{list_available_offers, list_offers, list_taken_offers};
+use crate::rest::offer::lease::{confirm_offer, release_expired_leases, release_offer};
+use crate::rest::offer::list_offers::{
+    list_available_offers, list_offer_changes, list_offers, list_taken_offers,
+};
 use crate::rest::offer::push_offer::push_offer;
-use crate::state::{AppState, Demands, Offers};
-use actix_web::{web, App, HttpResponse, HttpServer, Responder};
+use crate::rest::offer::sources::mirror_sources_status;
+use crate::rest::storage_error;
+use crate::selection::{select_offer, SelectionContext};
+use crate::state::{AppState, Demands, OfferObj, Offers};
+use crate::storage::sqlite::SqliteStorage;
+use crate::storage::StorageKind;
+use crate::store::Store;
+use actix_web::dev::Service;
+use actix_web::{web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web_httpauth::middleware::HttpAuthentication;
 use chrono::Utc;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::env;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Instant;
 use structopt::StructOpt;
 pub use ya_client_model::NodeId;
 
@@ -31,6 +70,15 @@ struct FilterAttributes {
     exe_name: Option<String>,
     cpu_threads_min: Option<u32>,
     cpu_threads_max: Option<u32>,
+    cpu_cores_min: Option<u32>,
+    cpu_cores_max: Option<u32>,
+    mem_gib_min: Option<f64>,
+    mem_gib_max: Option<f64>,
+    storage_gib_min: Option<f64>,
+    storage_gib_max: Option<f64>,
+    is_public: Option<bool>,
+    /// Semver requirement on runtime version, e.g. ">=0.1.0, <0.3"
+    runtime_version: Option<VersionReq>,
     provider_group_min: Option<u32>,
     provider_group_max: Option<u32>,
     id_group_min: Option<u32>,
@@ -38,20 +86,173 @@ struct FilterAttributes {
     node_id: Option<NodeId>,
     subnet: Option<String>,
     cpu_architecture: Option<String>,
+    /// Maximum price per usage counter, e.g. `golem.usage.cpu_sec`
+    max_prices: Option<BTreeMap<String, f64>>,
+    /// When not set, first matching offer is returned
+    order: Option<OfferOrder>,
+}
+
+impl FilterAttributes {
+    /// Indexed part of the filter, narrows candidates before [`FilterAttributes::matches`]
+    fn query(&self) -> OfferQuery {
+        OfferQuery {
+            exe_name: self.exe_name.clone(),
+            subnet: self.subnet.clone(),
+            cpu_architecture: self.cpu_architecture.clone(),
+            provider_id: self.node_id,
+            available: true,
+            ..Default::default()
+        }
+    }
+
+    fn matches(&self, offer_obj: &OfferObj) -> bool {
+        if let Some(filter_exe_name) = &self.exe_name {
+            if &offer_obj.attributes.exe_name != filter_exe_name {
+                return false;
+            }
+        }
+        if let Some(filter_cpu_threads_min) = self.cpu_threads_min {
+            if offer_obj.attributes.cpu_threads < filter_cpu_threads_min {
+                return false;
+            }
+        }
+        if let Some(filter_cpu_threads_max) = self.cpu_threads_max {
+            if offer_obj.attributes.cpu_threads > filter_cpu_threads_max {
+                return false;
+            }
+        }
+        if let Some(filter_cpu_cores_min) = self.cpu_cores_min {
+            if offer_obj.attributes.cpu_cores < filter_cpu_cores_min {
+                return false;
+            }
+        }
+        if let Some(filter_cpu_cores_max) = self.cpu_cores_max {
+            if offer_obj.attributes.cpu_cores > filter_cpu_cores_max {
+                return false;
+            }
+        }
+        if let Some(filter_mem_gib_min) = self.mem_gib_min {
+            if offer_obj.attributes.mem_gib < filter_mem_gib_min {
+                return false;
+            }
+        }
+        if let Some(filter_mem_gib_max) = self.mem_gib_max {
+            if offer_obj.attributes.mem_gib > filter_mem_gib_max {
+                return false;
+            }
+        }
+        if let Some(filter_storage_gib_min) = self.storage_gib_min {
+            if offer_obj.attributes.storage_gib < filter_storage_gib_min {
+                return false;
+            }
+        }
+        if let Some(filter_storage_gib_max) = self.storage_gib_max {
+            if offer_obj.attributes.storage_gib > filter_storage_gib_max {
+                return false;
+            }
+        }
+        if let Some(filter_is_public) = self.is_public {
+            if offer_obj.attributes.is_public != filter_is_public {
+                return false;
+            }
+        }
+        if let Some(filter_runtime_version) = &self.runtime_version {
+            match Version::parse(&offer_obj.attributes.runtime_version) {
+                Ok(version) => {
+                    if !filter_runtime_version.matches(&version) {
+                        return false;
+                    }
+                }
+                Err(_) => return false,
+            }
+        }
+        if let Some(filter_node_id) = &self.node_id {
+            if &offer_obj.offer.provider_id != filter_node_id {
+                return false;
+            }
+        }
+        if let Some(filter_subnet) = &self.subnet {
+            if &offer_obj.attributes.subnet != filter_subnet {
+                return false;
+            }
+        }
+        if let Some(filter_provider_group_min) = self.provider_group_min {
+            if offer_obj.attributes.node_id_group < filter_provider_group_min {
+                return false;
+            }
+        }
+        if let Some(filter_provider_group_max) = self.provider_group_max {
+            if offer_obj.attributes.node_id_group > filter_provider_group_max {
+                return false;
+            }
+        }
+        if let Some(filter_id_group_min) = self.id_group_min {
+            if offer_obj.attributes.offer_id_group < filter_id_group_min {
+                return false;
+            }
+        }
+        if let Some(filter_id_group_max) = self.id_group_max {
+            if offer_obj.attributes.offer_id_group > filter_id_group_max {
+                return false;
+            }
+        }
+        if let Some(filter_cpu_architecture) = &self.cpu_architecture {
+            if &offer_obj.attributes.cpu_architecture != filter_cpu_architecture {
+                return false;
+            }
+        }
+        if let Some(max_prices) = &self.max_prices {
+            if !offer_obj.within_max_prices(max_prices) {
+                return false;
+            }
+        }
+        true
+    }
 }
 
 #[test]
 fn test_filter_attributes() {
     use crate::model::offer::attributes::OfferFlatAttributes;
-    use crate::model::offer::base::GolemBaseOffer;
-
-    let offer = "{\"id\":\"00082a0389918034011dbcc885bd3da086eaaa66dceef7e6784386842571854d\",\"properties\":{\"golem\":{\"com\":{\"payment\":{\"debit-notes\":{\"accept-timeout?\":240},\"platform\":{\"erc20-polygon-glm\":{\"address\":\"0xa3bde9e2ef344407afdc931c97fd33d506ec6545\"}},\"protocol\":{\"version\":3}},\"pricing\":{\"model\":{\"@tag\":\"linear\",\"linear\":{\"coeffs\":[1e-9,0.0,0.0]}}},\"scheme\":{\"@tag\":\"payu\",\"payu\":{\"debit-note\":{\"interval-sec?\":120},\"payment-timeout-sec?\":120}},\"usage\":{\"vector\":[\"golem.usage.cpu_sec\",\"golem.usage.duration_sec\"]}},\"inf\":{\"cpu\":{\"architecture\":\"x86_64\",\"cores\":14,\"threads\":1},\"mem\":{\"gib\":42.79507473111153},\"storage\":{\"gib\":3257.801303100586}},\"node\":{\"debug\":{\"subnet\":\"public\"},\"id\":{\"name\":\"brick-54\"},\"net\":{\"is-public\":false}},\"runtime\":{\"name\":\"ya-runtime-cruncher\",\"version\":\"0.1.0\"},\"srv\":{\"caps\":{\"multi-activity\":true,\"payload-manifest\":false}}}},\"constraints\":\"(&\\n  (golem.srv.comp.expiration>1765401640654)\\n  (golem.node.debug.subnet=public)\\n)\",\"providerId\":\"0xa3bde9e2ef344407afdc931c97fd33d506ec6545\",\"expiration\":\"2025-12-11T12:20:45.222028719Z\",\"timestamp\":\"2025-12-11T11:20:45.222028719Z\"}";
+    use crate::test_util::golem_offer;
 
-    let gbo = serde_json::from_str::<GolemBaseOffer>(offer).unwrap();
+    let gbo = golem_offer("offer", 1);
     let attributes = OfferFlatAttributes::from_gbo(&gbo);
     println!("Attributes: {:?}", attributes);
 }
 
+#[test]
+fn test_filter_resources() {
+    use crate::test_util::offer;
+
+    let offer_obj = offer("offer", 1);
+    let check = |filter: serde_json::Value| {
+        let mut filter = filter;
+        filter["requestor_id"] = "0xa3bde9e2ef344407afdc931c97fd33d506ec6545".into();
+        serde_json::from_value::<FilterAttributes>(filter)
+            .unwrap()
+            .matches(&offer_obj)
+    };
+
+    assert!(check(
+        serde_json::json!({"mem_gib_min": 8.0, "cpu_cores_min": 14})
+    ));
+    assert!(!check(serde_json::json!({"mem_gib_min": 64.0})));
+    assert!(!check(serde_json::json!({"storage_gib_max": 1000.0})));
+    assert!(!check(serde_json::json!({"cpu_cores_max": 8})));
+    assert!(check(serde_json::json!({"is_public": false})));
+    assert!(check(
+        serde_json::json!({"runtime_version": ">=0.1.0, <0.2"})
+    ));
+    assert!(!check(serde_json::json!({"runtime_version": "^0.2"})));
+    assert!(
+        serde_json::from_value::<FilterAttributes>(serde_json::json!({
+            "requestor_id": "0xa3bde9e2ef344407afdc931c97fd33d506ec6545",
+            "runtime_version": "not a version"
+        }))
+        .is_err()
+    );
+}
+
 #[derive(Debug, StructOpt, Clone)]
 pub struct CliOptions {
     #[structopt(
@@ -74,9 +275,33 @@ pub struct CliOptions {
         default_value = "data.json"
     )]
     pub file_name: String,
+
+    #[structopt(
+        long = "storage",
+        help = "Storage backend for offers and demands (memory or sqlite)",
+        default_value = "memory"
+    )]
+    pub storage: StorageKind,
+
+    #[structopt(
+        long = "config",
+        help = "TOML configuration file, environment variables override its values"
+    )]
+    pub config: Option<String>,
+
+    #[structopt(
+        long = "db-file",
+        help = "Sqlite database file, used with --storage sqlite",
+        default_value = "offers.sqlite"
+    )]
+    pub db_file: String,
 }
 
-async fn get_if_available(data: web::Data<AppState>, item: String) -> impl Responder {
+async fn get_if_available(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    item: String,
+) -> impl Responder {
     let decode = serde_json::from_str::<FilterAttributes>(&item);
     let filer = match decode {
         Ok(filer) => filer,
@@ -85,130 +310,246 @@ async fn get_if_available(data: web::Data<AppState>, item: String) -> impl Respo
             return HttpResponse::BadRequest().body(format!("Invalid filter format {}", e));
         }
     };
+    if let Err(resp) = data.auth.verify(&req, &item, filer.requestor_id) {
+        return resp;
+    }
+    let query = filer.query();
     let mut lock = data.lock.lock().await;
-    for (_id, offer_obj) in lock.offer_map.iter_mut() {
-        if let Some(filter_exe_name) = &filer.exe_name {
-            if &offer_obj.attributes.exe_name != filter_exe_name {
-                continue;
-            }
-        }
-        if let Some(filter_cpu_threads_min) = filer.cpu_threads_min {
-            if offer_obj.attributes.cpu_threads < filter_cpu_threads_min {
-                continue;
-            }
-        }
-        if let Some(filter_cpu_threads_max) = filer.cpu_threads_max {
-            if offer_obj.attributes.cpu_threads > filter_cpu_threads_max {
-                continue;
-            }
-        }
-        if let Some(filter_node_id) = &filer.node_id {
-            if &offer_obj.offer.provider_id != filter_node_id {
-                continue;
-            }
-        }
-        if let Some(filter_subnet) = &filer.subnet {
-            if &offer_obj.attributes.subnet != filter_subnet {
-                continue;
-            }
+    let found = match filer.order {
+        Some(order) => lock
+            .query(&query, |offer_obj| filer.matches(offer_obj))
+            .await
+            .map(|candidates| {
+                let ctx = SelectionContext {
+                    now: Utc::now(),
+                    last_assigned: lock.last_assigned(),
+                    weights: data.config.get().picker.weights,
+                };
+                let candidates: Vec<&OfferObj> = candidates.iter().map(Arc::as_ref).collect();
+                select_offer(&candidates, order, &ctx).cloned()
+            }),
+        None => {
+            lock.find(&query, |offer_obj| filer.matches(offer_obj))
+                .await
         }
-        if let Some(filter_provider_group_min) = filer.provider_group_min {
-            if offer_obj.attributes.node_id_group < filter_provider_group_min {
-                continue;
-            }
-        }
-        if let Some(filter_provider_group_max) = filer.provider_group_max {
-            if offer_obj.attributes.node_id_group > filter_provider_group_max {
-                continue;
-            }
-        }
-        if let Some(filter_id_group_min) = filer.id_group_min {
-            if offer_obj.attributes.offer_id_group < filter_id_group_min {
-                continue;
-            }
-        }
-        if let Some(filter_id_group_max) = filer.id_group_max {
-            if offer_obj.attributes.offer_id_group > filter_id_group_max {
-                continue;
-            }
-        }
-        if let Some(filter_cpu_architecture) = &filer.cpu_architecture {
-            if &offer_obj.attributes.cpu_architecture != filter_cpu_architecture {
-                continue;
-            }
-        }
-
-        if offer_obj.requestor_id.is_none() {
-            offer_obj.requestor_id = Some(filer.requestor_id);
-            let offer = &offer_obj.offer;
-            return HttpResponse::Ok().json(offer);
+    };
+    let found = match found {
+        Ok(found) => found,
+        Err(e) => return storage_error(e),
+    };
+    if let Some(mut offer_obj) = found {
+        offer_obj.assign(filer.requestor_id);
+        let offer = offer_obj.offer.clone();
+        if let Err(e) = lock.insert(offer_obj).await {
+            return storage_error(e);
         }
+        return HttpResponse::Ok().json(offer);
     }
     HttpResponse::Ok().body("No available offers")
 }
 
-fn clean_old_offers_periodically(data: web::Data<AppState>) {
-    let interval = tokio::time::Duration::from_secs(60);
-    let data_clone = data.clone();
+fn cors(config: &CorsConfig) -> actix_cors::Cors {
+    if config.permissive {
+        return actix_cors::Cors::permissive();
+    }
+    config.allowed_origins.iter().fold(
+        actix_cors::Cors::default()
+            .allow_any_method()
+            .allow_any_header(),
+        |cors, origin| cors.allowed_origin(origin),
+    )
+}
+
+/// Runs the task every interval taken from current config. When the interval is changed
+/// by config reload, the timer is restarted with the new value.
+fn run_periodically<I, T, F>(
+    data: web::Data<AppState>,
+    run_immediately: bool,
+    interval_secs: I,
+    task: T,
+) where
+    I: Fn(&Config) -> f64 + Send + 'static,
+    T: Fn(web::Data<AppState>) -> F + Send + 'static,
+    F: Future<Output = ()> + Send,
+{
+    // tokio timer cannot handle arbitrary large values, long intervals are just disabled tasks
+    let period = |seconds: f64| tokio::time::Duration::from_secs_f64(seconds.min(86400.0));
     tokio::spawn(async move {
-        let mut ticker = tokio::time::interval(interval);
+        let mut config_rx = data.config.subscribe();
+        let mut seconds = interval_secs(&config_rx.borrow_and_update());
+        let start = if run_immediately {
+            tokio::time::Instant::now()
+        } else {
+            tokio::time::Instant::now() + period(seconds)
+        };
+        let mut ticker = tokio::time::interval_at(start, period(seconds));
         loop {
-            ticker.tick().await;
-            clean_old_offers(data_clone.clone()).await;
+            tokio::select! {
+                _ = ticker.tick() => task(data.clone()).await,
+                res = config_rx.changed() => {
+                    if res.is_err() {
+                        return;
+                    }
+                    let new_seconds = interval_secs(&config_rx.borrow_and_update());
+                    if new_seconds != seconds {
+                        seconds = new_seconds;
+                        ticker = tokio::time::interval_at(
+                            tokio::time::Instant::now() + period(seconds),
+                            period(seconds),
+                        );
+                    }
+                }
+            }
         }
     });
 }
 
-fn clean_old_demands_periodically(data: web::Data<AppState>) {
-    let interval = tokio::time::Duration::from_secs(60);
-    let data_clone = data.clone();
-    tokio::spawn(async move {
-        let mut ticker = tokio::time::interval(interval);
-        loop {
-            ticker.tick().await;
-            let mut lock = data_clone.demands.lock().await;
-            let now = Utc::now();
-            lock.demand_map
-                .retain(|_id, demand_obj| demand_obj.demand.expiration_ts.and_utc() > now);
+async fn clean_old_demands(data: web::Data<AppState>) {
+    let config = data.config.get();
+    let mut lock = data.demands.lock().await;
+    let mut offers_lock = data.lock.lock().await;
+    let now = Utc::now();
+    let removed = match lock
+        .retain(|demand_obj| demand_obj.demand.expiration_ts.and_utc() > now)
+        .await
+    {
+        Ok(removed) => removed,
+        Err(e) => {
+            log::error!("Failed to clean old demands: {}", e);
+            return;
         }
-    });
+    };
+    let mut released = 0;
+    for demand_obj in removed.iter() {
+        data.demand_notifier.remove(&demand_obj.demand.id);
+        match release_queued_offers(
+            &mut offers_lock,
+            demand_obj,
+            &data.fair_share,
+            &config.picker,
+        )
+        .await
+        {
+            Ok(count) => released += count,
+            Err(e) => log::error!(
+                "Failed to release offers of demand {}: {}",
+                demand_obj.demand.id,
+                e
+            ),
+        }
+    }
+    if !removed.is_empty() {
+        log::info!(
+            "Removed {} expired demands, {} queued offers returned to the pool",
+            removed.len(),
+            released
+        );
+    }
+}
+
+fn clean_old_offers_periodically(data: web::Data<AppState>) {
+    run_periodically(
+        data,
+        true,
+        |config| config.cleanup.offers_interval_secs,
+        clean_old_offers,
+    );
+}
+
+fn clean_old_demands_periodically(data: web::Data<AppState>) {
+    run_periodically(
+        data,
+        true,
+        |config| config.cleanup.demands_interval_secs,
+        clean_old_demands,
+    );
+}
+
+fn release_expired_leases_periodically(data: web::Data<AppState>) {
+    match data.config.get().lease.ttl_secs {
+        Some(seconds) => log::info!(
+            "Offers not confirmed within {} seconds are returned to the pool",
+            seconds
+        ),
+        None => log::info!("Lease ttl not set, assigned offers are kept until they expire"),
+    }
+    run_periodically(
+        data,
+        true,
+        |_| 10.0,
+        |data| async move {
+            // ttl is read on every tick, so leases can be enabled by config reload
+            if let Some(seconds) = data.config.get().lease.ttl_secs {
+                let ttl = chrono::Duration::milliseconds((seconds * 1000.0) as i64);
+                release_expired_leases(data, ttl).await;
+            }
+        },
+    );
 }
 
 fn synchronize_offers_periodically(data: web::Data<AppState>) {
-    let seconds = env::var("OFFER_MIRROR_SYNC_INTERVAL_SECS")
-        .ok()
-        .and_then(|s| s.parse::<f64>().ok())
-        .unwrap_or(300.0);
-    let interval = tokio::time::Duration::from_secs_f64(seconds);
-    let data_clone = data.clone();
-    tokio::spawn(async move {
-        let mut ticker = tokio::time::interval(interval);
-        loop {
-            ticker.tick().await;
-            let _ = download_offers_from_mirror(data_clone.clone()).await;
-        }
-    });
+    let sources = data.config.get().mirror.all_sources();
+    if sources.is_empty() {
+        log::warn!("No offer mirror sources configured, offers are not synchronized");
+    }
+    // every source has its own interval, checked every second
+    run_periodically(data, true, |_| 1.0, synchronize_due_sources);
+}
+
+fn save_state_periodically(data: web::Data<AppState>, file_name: String) {
+    // not run immediately, no point in saving just loaded state
+    run_periodically(
+        data,
+        false,
+        |config| config.state.save_interval_secs,
+        move |data| {
+            let file_name = file_name.clone();
+            async move {
+                if let Err(e) = save_state(&data, &file_name).await {
+                    log::error!("Failed to save state to {}: {}", file_name, e);
+                }
+            }
+        },
+    );
 }
 
 fn pick_offers_periodically(data: web::Data<AppState>) {
-    let seconds = env::var("PICK_OFFERS_INTERVAL_SECS")
-        .ok()
-        .and_then(|s| s.parse::<f64>().ok())
-        .unwrap_or(30.0);
+    let seconds = data.config.get().picker.interval_secs;
     if seconds >= 1E9 {
         log::warn!(
-            "PICK_OFFERS_INTERVAL_SECS value {} is too high, skipping pick offers periodically",
+            "Pick offers interval {} is too high, skipping pick offers until it is changed",
             seconds
         );
-        return;
     }
-    let interval = tokio::time::Duration::from_secs_f64(seconds);
-    let data_clone = data.clone();
+    run_periodically(
+        data,
+        true,
+        |config| config.picker.interval_secs,
+        |data| async move {
+            if data.config.get().picker.interval_secs < 1E9 {
+                pick_offers_for_all_demands(data).await;
+            }
+        },
+    );
+}
+
+#[cfg(unix)]
+fn reload_config_on_sighup(data: web::Data<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
     tokio::spawn(async move {
-        let mut ticker = tokio::time::interval(interval);
-        loop {
-            ticker.tick().await;
-            pick_offers_for_all_demands(data_clone.clone()).await;
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                log::error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            log::info!("Received SIGHUP, reloading configuration");
+            if let Err(e) = data.config.reload() {
+                log::error!("Failed to reload configuration, keeping current one: {}", e);
+            }
         }
     });
 }
@@ -222,41 +563,134 @@ async fn main() -> std::io::Result<()> {
     );
     env_logger::init();
     let args = CliOptions::from_args();
+    let config = Config::load(args.config.as_deref())
+        .map_err(|e| std::io::Error::other(format!("Invalid configuration: {}", e)))?;
+    if let Some(config_file) = &args.config {
+        log::info!("Loaded configuration from {}", config_file);
+    }
     // Load the queue from file or create a new one
+    let snapshot = match load_state(&args.file_name) {
+        Ok(snapshot) => snapshot.unwrap_or_default(),
+        Err(e) => {
+            log::error!(
+                "Failed to load state from {}, starting with empty state: {}",
+                args.file_name,
+                e
+            );
+            Default::default()
+        }
+    };
+
+    let (offers, demands) = match args.storage {
+        StorageKind::Memory => (Offers::default(), Demands::default()),
+        StorageKind::Sqlite => {
+            let storage = SqliteStorage::connect(&args.db_file)
+                .await
+                .map_err(|e| std::io::Error::other(format!("Failed to open database: {}", e)))?;
+            let offers = Offers::new_sqlite(storage.clone())
+                .await
+                .map_err(|e| std::io::Error::other(format!("Failed to load offers: {}", e)))?;
+            let demands = Demands::new_sqlite(storage);
+            (offers, demands)
+        }
+    };
+    log::info!("Using {} storage for offers and demands", args.storage);
 
     let app_state = AppState {
-        lock: Arc::new(tokio::sync::Mutex::new(Offers::default())),
-        demands: Arc::new(tokio::sync::Mutex::new(Demands::default())),
-        offers_given_to_node: Arc::new(Default::default()),
+        lock: Arc::new(Store::new(offers)),
+        demands: Arc::new(Store::new(demands)),
+        fair_share: Arc::new(Default::default()),
+        metrics: Arc::new(Default::default()),
+        demand_notifier: Arc::new(Default::default()),
+        auth: Arc::new(SignatureVerifier::from_config(&config.auth)),
+        admin_tokens: Arc::new(AdminTokens::from_config(&config.auth)),
+        config: Arc::new(SharedConfig::new(config, args.config.clone())),
+        mirror_status: Arc::new(Default::default()),
     };
+    if let Err(e) = restore_state(&app_state, snapshot).await {
+        log::error!("Failed to restore state from {}: {}", args.file_name, e);
+    }
     log::info!("Downloading initial offers...");
 
     clean_old_offers_periodically(web::Data::new(app_state.clone()));
     clean_old_demands_periodically(web::Data::new(app_state.clone()));
+    release_expired_leases_periodically(web::Data::new(app_state.clone()));
     synchronize_offers_periodically(web::Data::new(app_state.clone()));
     pick_offers_periodically(web::Data::new(app_state.clone()));
+    save_state_periodically(web::Data::new(app_state.clone()), args.file_name.clone());
+    #[cfg(unix)]
+    reload_config_on_sighup(web::Data::new(app_state.clone()));
 
     log::info!(
         "Starting Offer Server at http://{}:{}",
         &args.http_addr,
         &args.http_port
     );
-    HttpServer::new(move || {
-        //let auth = HttpAuthentication::with_fn(validator);
+    let server_state = app_state.clone();
+    let workers = app_state.config.get().server.workers;
+    let res = HttpServer::new(move || {
+        let admin_tokens = server_state.admin_tokens.clone();
+        let admin_scope = web::scope("/admin")
+            .route("/offers/clear", web::post().to(delete_all_offers))
+            .route("/offer/{offer_id}", web::delete().to(delete_offer))
+            .route("/demand/{demand_id}", web::delete().to(delete_demand))
+            .route("/counters/reset", web::post().to(reset_counters))
+            .route("/config/reload", web::post().to(reload_config))
+            .wrap_fn(move |req, srv| {
+                let admin_tokens = admin_tokens.clone();
+                let admin = req
+                    .extensions()
+                    .get::<AdminIdentity>()
+                    .map(|admin| admin.0.clone())
+                    .unwrap_or_default();
+                let method = req.method().to_string();
+                let path = req.path().to_string();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await;
+                    let status = match &res {
+                        Ok(resp) => resp.status().as_u16(),
+                        Err(e) => e.as_response_error().status_code().as_u16(),
+                    };
+                    admin_tokens.audit(&admin, &method, &path, status);
+                    res
+                }
+            })
+            .wrap(HttpAuthentication::with_fn(admin_validator));
 
+        let metrics_state = server_state.metrics.clone();
         App::new()
-            .app_data(web::Data::new(app_state.clone()))
+            .app_data(web::Data::new(server_state.clone()))
             .wrap(actix_web::middleware::Logger::default())
-            .wrap(actix_cors::Cors::permissive())
+            .wrap_fn(move |req, srv| {
+                let perf_start = Instant::now();
+                let method = req.method().to_string();
+                let endpoint = req
+                    .match_pattern()
+                    .unwrap_or_else(|| "unmatched".to_string());
+                let metrics_state = metrics_state.clone();
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await;
+                    metrics_state.record_request(&method, &endpoint, perf_start.elapsed());
+                    res
+                }
+            })
+            .wrap(cors(&server_state.config.get().server.cors))
             .route("/provider/offer/new", web::post().to(push_offer))
             .route("/offers/list", web::get().to(list_offers))
-            .route("/offers/clear", web::post().to(delete_all_offers))
             .route("/offers/list/taken", web::get().to(list_taken_offers))
             .route(
                 "/offers/list/available",
                 web::get().to(list_available_offers),
             )
+            .route("/offers/changes", web::get().to(list_offer_changes))
+            .route("/offers/sources", web::get().to(mirror_sources_status))
+            .service(admin_scope)
             .route("/offer/take", web::post().to(get_if_available))
+            .route("/requestor/offer/confirm", web::post().to(confirm_offer))
+            .route("/requestor/offer/release", web::post().to(release_offer))
+            .route("/metrics", web::get().to(metrics))
             .route(
                 "/version",
                 web::get().to(|| async { HttpResponse::Ok().body(env!("CARGO_PKG_VERSION")) }),
@@ -264,6 +698,7 @@ async fn main() -> std::io::Result<()> {
             .route("/requestor/demand/new", web::post().to(demand_new))
             .route("/requestor/demand/cancel", web::post().to(demand_cancel))
             .route("/requestor/demands/list", web::get().to(list_demands))
+            .route("/requestor/shares", web::get().to(list_requestor_shares))
             .route(
                 "/requestor/demand/append-offer",
                 web::post().to(add_offer_to_demand),
@@ -276,9 +711,20 @@ async fn main() -> std::io::Result<()> {
                 "/requestor/demand/take-from-queue",
                 web::post().to(take_offer_from_queue),
             )
+            .route(
+                "/requestor/demand/stream/{demand_id}",
+                web::get().to(stream_offers),
+            )
     })
     .bind(format!("{}:{}", args.http_addr, args.http_port))?
-    .workers(4)
+    .workers(workers)
     .run()
-    .await
+    .await;
+
+    // server stopped (SIGINT/SIGTERM), store state for the next run
+    log::info!("Saving state to {}", args.file_name);
+    if let Err(e) = save_state(&app_state, &args.file_name).await {
+        log::error!("Failed to save state to {}: {}", args.file_name, e);
+    }
+    res
 }
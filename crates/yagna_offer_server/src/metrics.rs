@@ -0,0 +1,321 @@
+use crate::rest::storage_error;
+use crate::state::{AppState, DemandObj};
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const LATENCY_BUCKETS: [f64; 9] = [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0, 5.0];
+
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, le) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if value <= le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+    pick_ticks: u64,
+    picks_last_tick: u64,
+    picks: BTreeMap<&'static str, u64>,
+    mirror_syncs: BTreeMap<&'static str, u64>,
+    mirror_last_duration: f64,
+    mirror_last_success: bool,
+    ingested: BTreeMap<&'static str, u64>,
+    requests: BTreeMap<(String, String), Histogram>,
+}
+
+/// Counters collected by the matcher, exposed on /metrics in Prometheus text format.
+/// Gauges describing current state (offers, demands) are computed when scraped.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    inner: Mutex<MetricsInner>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IngestCounts {
+    pub added: u64,
+    pub removed: u64,
+    pub ignored: u64,
+    pub already_present: u64,
+    pub rejected: u64,
+}
+
+impl Metrics {
+    fn inner(&self) -> std::sync::MutexGuard<'_, MetricsInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Result of single pick tick: picked, empty (no offer found) or error
+    pub fn record_pick_tick(&self, result: &'static str, picked: u64) {
+        let mut inner = self.inner();
+        inner.pick_ticks += 1;
+        inner.picks_last_tick = picked;
+        *inner.picks.entry(result).or_default() += 1;
+    }
+
+    pub fn record_mirror_sync(&self, duration: Duration, success: bool) {
+        let mut inner = self.inner();
+        let result = if success { "success" } else { "error" };
+        *inner.mirror_syncs.entry(result).or_default() += 1;
+        inner.mirror_last_duration = duration.as_secs_f64();
+        inner.mirror_last_success = success;
+    }
+
+    pub fn record_ingest(&self, counts: IngestCounts) {
+        let mut inner = self.inner();
+        for (kind, count) in [
+            ("added", counts.added),
+            ("removed", counts.removed),
+            ("ignored", counts.ignored),
+            ("already_present", counts.already_present),
+            ("rejected", counts.rejected),
+        ] {
+            *inner.ingested.entry(kind).or_default() += count;
+        }
+    }
+
+    pub fn record_request(&self, method: &str, endpoint: &str, duration: Duration) {
+        self.inner()
+            .requests
+            .entry((method.to_string(), endpoint.to_string()))
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    fn render(&self, out: &mut String) {
+        let inner = self.inner();
+
+        header(
+            out,
+            "matcher_pick_ticks_total",
+            "counter",
+            "Number of pick ticks run",
+        );
+        let _ = writeln!(out, "matcher_pick_ticks_total {}", inner.pick_ticks);
+        header(
+            out,
+            "matcher_picks_total",
+            "counter",
+            "Pick ticks by result",
+        );
+        for (result, count) in inner.picks.iter() {
+            let _ = writeln!(
+                out,
+                "matcher_picks_total{{result=\"{}\"}} {}",
+                result, count
+            );
+        }
+        header(
+            out,
+            "matcher_picks_last_tick",
+            "gauge",
+            "Offers picked in the last tick",
+        );
+        let _ = writeln!(out, "matcher_picks_last_tick {}", inner.picks_last_tick);
+
+        header(
+            out,
+            "matcher_mirror_syncs_total",
+            "counter",
+            "Mirror synchronizations by result",
+        );
+        for (result, count) in inner.mirror_syncs.iter() {
+            let _ = writeln!(
+                out,
+                "matcher_mirror_syncs_total{{result=\"{}\"}} {}",
+                result, count
+            );
+        }
+        header(
+            out,
+            "matcher_mirror_sync_duration_seconds",
+            "gauge",
+            "Duration of the last mirror synchronization",
+        );
+        let _ = writeln!(
+            out,
+            "matcher_mirror_sync_duration_seconds {}",
+            inner.mirror_last_duration
+        );
+        header(
+            out,
+            "matcher_mirror_sync_success",
+            "gauge",
+            "1 if the last mirror synchronization succeeded",
+        );
+        let _ = writeln!(
+            out,
+            "matcher_mirror_sync_success {}",
+            u8::from(inner.mirror_last_success)
+        );
+
+        header(
+            out,
+            "matcher_offers_ingested_total",
+            "counter",
+            "Offers pushed or received from mirrors by outcome",
+        );
+        for (kind, count) in inner.ingested.iter() {
+            let _ = writeln!(
+                out,
+                "matcher_offers_ingested_total{{kind=\"{}\"}} {}",
+                kind, count
+            );
+        }
+
+        header(
+            out,
+            "matcher_http_request_duration_seconds",
+            "histogram",
+            "HTTP request latencies per endpoint",
+        );
+        for ((method, endpoint), histogram) in inner.requests.iter() {
+            let labels = format!(
+                "method=\"{}\",endpoint=\"{}\"",
+                escape(method),
+                escape(endpoint)
+            );
+            for (le, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "matcher_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "matcher_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "matcher_http_request_duration_seconds_sum{{{}}} {}",
+                labels, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "matcher_http_request_duration_seconds_count{{{}}} {}",
+                labels, histogram.count
+            );
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+async fn render_state(data: &AppState, out: &mut String) -> anyhow::Result<()> {
+    let demands = data.demands.snapshot().await?;
+    let (available, taken) = data.lock.count_by_state().await?;
+
+    header(out, "matcher_offers", "gauge", "Offers by state");
+    let _ = writeln!(out, "matcher_offers{{state=\"available\"}} {}", available);
+    let _ = writeln!(out, "matcher_offers{{state=\"taken\"}} {}", taken);
+
+    let mut per_net: BTreeMap<&str, u64> = BTreeMap::new();
+    for demand_obj in demands.iter() {
+        let net = demand_obj
+            .demand
+            .central_net_address
+            .as_deref()
+            .unwrap_or("");
+        *per_net.entry(net).or_default() += 1;
+    }
+    header(out, "matcher_demands", "gauge", "Demands per central net");
+    for (net, count) in per_net {
+        let _ = writeln!(
+            out,
+            "matcher_demands{{central_net=\"{}\"}} {}",
+            escape(net),
+            count
+        );
+    }
+
+    let demands: Vec<&DemandObj> = demands.iter().collect();
+    let shares = data
+        .fair_share
+        .shares(&demands, &data.config.get().picker, Utc::now());
+    header(
+        out,
+        "matcher_offers_given_to_node",
+        "gauge",
+        "Offers given to requestor node in central net, decaying over picker.share_window_secs",
+    );
+    for share in shares {
+        let _ = writeln!(
+            out,
+            "matcher_offers_given_to_node{{requestor=\"{}\",central_net=\"{}\"}} {}",
+            escape(&share.node_id),
+            escape(&share.central_net),
+            share.usage
+        );
+    }
+    Ok(())
+}
+
+pub async fn metrics(data: web::Data<AppState>) -> HttpResponse {
+    let mut out = String::new();
+    if let Err(e) = render_state(&data, &mut out).await {
+        return storage_error(e);
+    }
+    data.metrics.render(&mut out);
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(out)
+}
+
+#[test]
+fn test_metrics_render() {
+    let metrics = Metrics::default();
+    metrics.record_pick_tick("picked", 3);
+    metrics.record_pick_tick("empty", 0);
+    metrics.record_mirror_sync(Duration::from_millis(250), true);
+    metrics.record_ingest(IngestCounts {
+        added: 3,
+        ignored: 1,
+        ..Default::default()
+    });
+    metrics.record_request("POST", "/offer/take", Duration::from_millis(20));
+
+    let mut out = String::new();
+    metrics.render(&mut out);
+    assert!(out.contains("matcher_pick_ticks_total 2\n"));
+    assert!(out.contains("matcher_picks_total{result=\"picked\"} 1\n"));
+    assert!(out.contains("matcher_picks_last_tick 0\n"));
+    assert!(out.contains("matcher_mirror_syncs_total{result=\"success\"} 1\n"));
+    assert!(out.contains("matcher_mirror_sync_duration_seconds 0.25\n"));
+    assert!(out.contains("matcher_offers_ingested_total{kind=\"added\"} 3\n"));
+    assert!(out.contains(
+        "matcher_http_request_duration_seconds_bucket{method=\"POST\",endpoint=\"/offer/take\",le=\"0.01\"} 0\n"
+    ));
+    assert!(out.contains(
+        "matcher_http_request_duration_seconds_bucket{method=\"POST\",endpoint=\"/offer/take\",le=\"0.025\"} 1\n"
+    ));
+}
@@ -1,5 +1,7 @@
+use crate::model::offer::pricing::OfferOrder;
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use ya_client_model::NodeId;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -17,6 +19,18 @@ pub struct DemandSubscription {
     pub expiration_ts: NaiveDateTime,
     /// Filter by central net address
     pub central_net_address: Option<String>,
+    /// Maximum price per usage counter, e.g. `golem.usage.cpu_sec`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_prices: Option<BTreeMap<String, f64>>,
+    /// Overrides default order in which offers are picked for this demand
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub offer_order: Option<OfferOrder>,
+    /// Offers the picker keeps queued for this demand, topped up every tick
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_depth: Option<usize>,
+    /// Total offers assigned to this demand after which the picker stops
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_offers: Option<u64>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -0,0 +1,36 @@
+use serde_json::{Map, Value};
+
+/// Flattens nested property objects into dotted keys, e.g. `golem.inf.cpu.threads`
+pub fn flatten(value: Value) -> Map<String, Value> {
+    let mut map = Map::new();
+    flatten_inner(String::new(), &mut map, value);
+    map
+}
+pub const PROPERTY_TAG: &str = "@tag";
+
+fn flatten_inner(prefix: String, result: &mut Map<String, Value>, value: Value) {
+    match value {
+        Value::Object(m) => {
+            if m.is_empty() {
+                // Important to keep this value in case we want to un-flatten later
+                // and get the same structure.
+                result.insert(prefix, Value::Object(Map::new()));
+            } else {
+                for (k, v) in m.into_iter() {
+                    if k.as_str() == PROPERTY_TAG {
+                        result.insert(prefix.clone(), v);
+                        continue;
+                    }
+                    let p = match prefix.is_empty() {
+                        true => k,
+                        _ => format!("{}.{}", prefix, k),
+                    };
+                    flatten_inner(p, result, v);
+                }
+            }
+        }
+        v => {
+            result.insert(prefix, v);
+        }
+    }
+}
@@ -1,2 +1,3 @@
 pub mod demand;
+pub mod flatten;
 pub mod offer;
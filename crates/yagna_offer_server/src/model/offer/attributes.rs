@@ -15,6 +15,16 @@ pub struct OfferFlatAttributes {
     pub node_name: String,
     pub node_id_group: u32,
     pub offer_id_group: u32,
+    #[serde(default)]
+    pub cpu_cores: u32,
+    #[serde(default)]
+    pub mem_gib: f64,
+    #[serde(default)]
+    pub storage_gib: f64,
+    #[serde(default)]
+    pub is_public: bool,
+    #[serde(default)]
+    pub runtime_version: String,
 }
 static STATE: Mutex<(u64, u64)> = Mutex::new((0, 0));
 // (last_hour, random_value)
@@ -77,6 +87,11 @@ impl OfferFlatAttributes {
             cpu_architecture: gbo.properties.golem.inf.cpu.architecture.clone(),
             cpu_threads: gbo.properties.golem.inf.cpu.threads,
             offer_id_group,
+            cpu_cores: gbo.properties.golem.inf.cpu.cores,
+            mem_gib: gbo.properties.golem.inf.mem.gib,
+            storage_gib: gbo.properties.golem.inf.storage.gib,
+            is_public: gbo.properties.golem.node.net.is_public,
+            runtime_version: gbo.properties.golem.runtime.version.clone(),
         }
     }
 }
@@ -1,3 +1,4 @@
 pub mod attributes;
 pub mod base;
+pub mod pricing;
 pub mod properties;
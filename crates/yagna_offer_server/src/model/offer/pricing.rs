@@ -0,0 +1,142 @@
+use crate::model::offer::properties::PricingModel;
+use crate::state::OfferObj;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+pub const USAGE_CPU_SEC: &str = "golem.usage.cpu_sec";
+pub const USAGE_DURATION_SEC: &str = "golem.usage.duration_sec";
+
+/// Order in which matching offers are handed out, see [`crate::selection`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OfferOrder {
+    /// Most recently published offer first
+    #[default]
+    Newest,
+    /// Lowest estimated hourly cost first, see [`OfferObj::price_per_hour`]
+    Cheapest,
+    /// Any matching offer, chosen uniformly
+    Random,
+    /// Offer of the provider that waits longest since its last assignment
+    LeastRecentlyAssigned,
+    /// Highest score combining price, freshness and resources, see [`crate::config::ScoreWeights`]
+    Weighted,
+}
+
+impl FromStr for OfferOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().replace(['-', '_'], "").as_str() {
+            "newest" => Ok(OfferOrder::Newest),
+            "cheapest" => Ok(OfferOrder::Cheapest),
+            "random" => Ok(OfferOrder::Random),
+            "leastrecentlyassigned" => Ok(OfferOrder::LeastRecentlyAssigned),
+            "weighted" => Ok(OfferOrder::Weighted),
+            _ => Err(anyhow::anyhow!(
+                "Unknown offer order {}, expected newest, cheapest, random, leastRecentlyAssigned or weighted",
+                s
+            )),
+        }
+    }
+}
+
+impl OfferObj {
+    /// None if the offer uses pricing model not known to the matcher
+    fn linear_coeffs(&self) -> Option<&[f64]> {
+        match &self.offer.properties.golem.com.pricing.model {
+            PricingModel::Linear { linear } => Some(&linear.coeffs),
+            PricingModel::Other => None,
+        }
+    }
+
+    /// Price for one unit of the usage counter, coefficient at the same position as
+    /// the counter in usage vector. None if the offer does not charge for that counter.
+    pub fn usage_price(&self, usage_counter: &str) -> Option<f64> {
+        let coeffs = self.linear_coeffs()?;
+        let idx = self
+            .offer
+            .properties
+            .golem
+            .com
+            .usage
+            .vector
+            .iter()
+            .position(|counter| counter == usage_counter)?;
+        coeffs.get(idx).copied()
+    }
+
+    /// Fixed price charged at start of the activity, last coefficient after usage prices
+    pub fn start_price(&self) -> f64 {
+        let usage_len = self.offer.properties.golem.com.usage.vector.len();
+        self.linear_coeffs()
+            .and_then(|coeffs| coeffs.get(usage_len).copied())
+            .unwrap_or(0.0)
+    }
+
+    /// Estimated cost of one hour of work, with all offered threads fully used.
+    /// Offers with unknown pricing model are treated as the most expensive ones.
+    pub fn price_per_hour(&self) -> f64 {
+        if self.linear_coeffs().is_none() {
+            return f64::INFINITY;
+        }
+        let threads = self.offer.properties.golem.inf.cpu.threads as f64;
+        self.start_price()
+            + self.usage_price(USAGE_DURATION_SEC).unwrap_or(0.0) * 3600.0
+            + self.usage_price(USAGE_CPU_SEC).unwrap_or(0.0) * 3600.0 * threads
+    }
+
+    /// Checks max price for each usage counter, counters not charged by the offer always pass.
+    /// Price of offers with unknown pricing model cannot be checked, so they never pass.
+    pub fn within_max_prices(&self, max_prices: &BTreeMap<String, f64>) -> bool {
+        if self.linear_coeffs().is_none() {
+            return max_prices.is_empty();
+        }
+        max_prices.iter().all(|(usage_counter, max_price)| {
+            self.usage_price(usage_counter)
+                .map(|price| price <= *max_price)
+                .unwrap_or(true)
+        })
+    }
+}
+
+#[test]
+fn test_linear_pricing() {
+    use crate::test_util::golem_offer;
+
+    let mut gbo = golem_offer("offer", 1);
+    gbo.properties
+        .set(
+            "golem.com.pricing.model.linear.coeffs",
+            serde_json::json!([0.0001, 0.00002, 0.5]),
+        )
+        .unwrap();
+    gbo.properties
+        .set("golem.inf.cpu.threads", serde_json::json!(4))
+        .unwrap();
+    let offer_obj = OfferObj::new(gbo.clone());
+
+    assert_eq!(offer_obj.usage_price(USAGE_CPU_SEC), Some(0.0001));
+    assert_eq!(offer_obj.usage_price(USAGE_DURATION_SEC), Some(0.00002));
+    assert_eq!(offer_obj.usage_price("golem.usage.gib"), None);
+    assert_eq!(offer_obj.start_price(), 0.5);
+    let expected = 0.5 + 0.00002 * 3600.0 + 0.0001 * 3600.0 * 4.0;
+    assert!((offer_obj.price_per_hour() - expected).abs() < 1e-12);
+
+    let mut max_prices = BTreeMap::new();
+    max_prices.insert(USAGE_CPU_SEC.to_string(), 0.0001);
+    max_prices.insert("golem.usage.gib".to_string(), 0.0);
+    assert!(offer_obj.within_max_prices(&max_prices));
+    max_prices.insert(USAGE_DURATION_SEC.to_string(), 0.00001);
+    assert!(!offer_obj.within_max_prices(&max_prices));
+
+    gbo.properties
+        .set("golem.com.pricing.model.@tag", "fixed".into())
+        .unwrap();
+    let offer_obj = OfferObj::new(gbo);
+    assert_eq!(offer_obj.usage_price(USAGE_CPU_SEC), None);
+    assert_eq!(offer_obj.price_per_hour(), f64::INFINITY);
+    assert!(!offer_obj.within_max_prices(&max_prices));
+    assert!(offer_obj.within_max_prices(&BTreeMap::new()));
+}
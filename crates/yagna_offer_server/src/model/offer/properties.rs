@@ -1,11 +1,79 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::ops::Deref;
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// Offer properties exactly as published by the provider, together with typed view
+/// of the properties the matcher understands. Serialized as the original JSON, so
+/// properties unknown to the matcher are passed to requestors and mirrors unchanged.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OfferProperties {
+    raw: Value,
+    typed: Properties,
+}
+
+impl OfferProperties {
+    pub fn new(raw: Value) -> Result<Self, serde_path_to_error::Error<serde_json::Error>> {
+        let typed = serde_path_to_error::deserialize(&raw)?;
+        Ok(Self { raw, typed })
+    }
+
+    pub fn raw(&self) -> &Value {
+        &self.raw
+    }
+
+    /// Sets property given by dot separated path, e.g. `golem.inf.cpu.threads`
+    pub fn set(&mut self, path: &str, value: Value) -> anyhow::Result<()> {
+        let mut raw = self.raw.clone();
+        let mut target = &mut raw;
+        for key in path.split('.') {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            target = target
+                .as_object_mut()
+                .expect("Has to be object")
+                .entry(key)
+                .or_insert(Value::Null);
+        }
+        *target = value;
+        *self = Self::new(raw).map_err(|e| anyhow::anyhow!("{}: {}", e.path(), e.inner()))?;
+        Ok(())
+    }
+}
+
+impl Deref for OfferProperties {
+    type Target = Properties;
+
+    fn deref(&self) -> &Self::Target {
+        &self.typed
+    }
+}
+
+impl Serialize for OfferProperties {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.raw.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for OfferProperties {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = Value::deserialize(deserializer)?;
+        Self::new(raw)
+            .map_err(|e| serde::de::Error::custom(format!("{} at {}", e.inner(), e.path())))
+    }
+}
+
+/// Typed view of offer properties. Missing properties get default values and unknown
+/// ones are ignored, they are still available in [`OfferProperties::raw`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Properties {
     pub golem: GolemProperties,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct GolemProperties {
     pub com: Com,
     pub inf: Inf,
@@ -16,7 +84,8 @@ pub struct GolemProperties {
 
 // --- Communication (com) ---
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Com {
     pub payment: Payment,
     pub pricing: Pricing,
@@ -24,7 +93,8 @@ pub struct Com {
     pub usage: Usage,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Payment {
     #[serde(rename = "debit-notes")]
     pub debit_notes: DebitNotes,
@@ -32,57 +102,93 @@ pub struct Payment {
     pub protocol: Protocol,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct DebitNotes {
     // The key in JSON literally contains the question mark
     #[serde(rename = "accept-timeout?")]
     pub accept_timeout: u64,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Erc20Platform {
     pub address: String,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Platform {
     #[serde(rename = "erc20-polygon-glm")]
     pub erc20_polygon_glm: Option<Erc20Platform>,
     #[serde(rename = "erc20-hoodi-tglm")]
     pub erc20_hoodi_tglm: Option<Erc20Platform>,
+    /// Platforms not known to the matcher, by name
+    #[serde(flatten)]
+    pub other: BTreeMap<String, Value>,
+}
+
+impl Platform {
+    /// Names of all payment platforms of the offer with their addresses, if given
+    pub fn addresses(&self) -> Vec<(&str, Option<&str>)> {
+        let mut addresses = Vec::new();
+        for (name, platform) in [
+            ("erc20-polygon-glm", &self.erc20_polygon_glm),
+            ("erc20-hoodi-tglm", &self.erc20_hoodi_tglm),
+        ] {
+            if let Some(platform) = platform {
+                addresses.push((name, Some(platform.address.as_str())));
+            }
+        }
+        for (name, platform) in self.other.iter() {
+            addresses.push((name.as_str(), platform["address"].as_str()));
+        }
+        addresses
+    }
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Protocol {
     pub version: u32,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Pricing {
     pub model: PricingModel,
 }
 
 // The JSON uses "@tag" to determine which variant this is
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "@tag")]
 pub enum PricingModel {
     #[serde(rename = "linear")]
     Linear { linear: LinearPricing },
+    /// Pricing model not known to the matcher, the offer has no price
+    #[default]
+    #[serde(other)]
+    Other,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct LinearPricing {
     pub coeffs: Vec<f64>,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "@tag")]
 pub enum Scheme {
     #[serde(rename = "payu")]
     Payu { payu: PayuScheme },
+    #[default]
+    #[serde(other)]
+    Other,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PayuScheme {
     #[serde(rename = "debit-note")]
     pub debit_note: PayuDebitNote,
@@ -90,63 +196,73 @@ pub struct PayuScheme {
     pub payment_timeout_sec: u64,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PayuDebitNote {
     #[serde(rename = "interval-sec?")]
     pub interval_sec: u64,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Usage {
     pub vector: Vec<String>,
 }
 
 // --- Infrastructure (inf) ---
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Inf {
     pub cpu: Cpu,
     pub mem: Mem,
     pub storage: Storage,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Cpu {
     pub architecture: String,
     pub cores: u32,
     pub threads: u32,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Mem {
     pub gib: f64,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Storage {
     pub gib: f64,
 }
 
 // --- Node (node) ---
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Node {
     pub debug: Option<NodeDebug>,
     pub id: NodeName,
     pub net: NodeNet,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct NodeDebug {
     pub subnet: String,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct NodeName {
     pub name: String,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct NodeNet {
     #[serde(rename = "is-public")]
     pub is_public: bool,
@@ -154,7 +270,8 @@ pub struct NodeNet {
 
 // --- Runtime (runtime) ---
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Runtime {
     pub name: String,
     pub version: String,
@@ -162,15 +279,62 @@ pub struct Runtime {
 
 // --- Service (srv) ---
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Srv {
     pub caps: Caps,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Caps {
     #[serde(rename = "multi-activity")]
     pub multi_activity: bool,
     #[serde(rename = "payload-manifest")]
     pub payload_manifest: bool,
 }
+
+#[test]
+fn test_lenient_offer_properties() {
+    use crate::model::offer::base::{GolemBaseOffer, EXAMPLE_OFFER_JSON};
+    use crate::model::offer::pricing::USAGE_CPU_SEC;
+    use crate::state::OfferObj;
+
+    let mut offer = serde_json::from_str::<Value>(EXAMPLE_OFFER_JSON).unwrap();
+    let golem = &mut offer["properties"]["golem"];
+    golem["com"]["payment"]["platform"]["erc20-mainnet-glm"] =
+        serde_json::json!({"address": "0xa3bde9e2ef344407afdc931c97fd33d506ec6545"});
+    golem["com"]["pricing"]["model"] =
+        serde_json::json!({"@tag": "fixed", "fixed": {"price": 1.0}});
+    golem["node"].as_object_mut().unwrap().remove("debug");
+    golem["inf"]["gpu"] = serde_json::json!({"model": "RTX 4090"});
+
+    let gbo = serde_json::from_value::<GolemBaseOffer>(offer.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&gbo).unwrap(), offer);
+    let golem = &gbo.properties.golem;
+    assert_eq!(golem.com.pricing.model, PricingModel::Other);
+    assert!(golem.node.debug.is_none());
+    assert_eq!(golem.inf.cpu.cores, 14);
+    assert_eq!(
+        golem.com.payment.platform.addresses(),
+        vec![
+            (
+                "erc20-polygon-glm",
+                Some("0xa3bde9e2ef344407afdc931c97fd33d506ec6545")
+            ),
+            (
+                "erc20-mainnet-glm",
+                Some("0xa3bde9e2ef344407afdc931c97fd33d506ec6545")
+            ),
+        ]
+    );
+    let offer_obj = OfferObj::new(gbo);
+    assert_eq!(offer_obj.attributes.subnet, "public");
+    assert_eq!(offer_obj.usage_price(USAGE_CPU_SEC), None);
+
+    // known properties still have to have the right type
+    let mut offer = serde_json::from_str::<Value>(EXAMPLE_OFFER_JSON).unwrap();
+    offer["properties"]["golem"]["inf"]["cpu"]["cores"] = "many".into();
+    let err = serde_json::from_value::<GolemBaseOffer>(offer).unwrap_err();
+    assert!(err.to_string().contains("golem.inf.cpu.cores"));
+}
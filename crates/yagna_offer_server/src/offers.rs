@@ -1,100 +1,320 @@
-use crate::state::OfferObj;
+use crate::arkiv;
+use crate::config::{MirrorSource, SourceKind};
+use crate::ingest::ingest_offers;
+use crate::metrics::IngestCounts;
+use crate::state::{OfferChanges, OfferObj};
 use crate::AppState;
 use actix_web::web;
-use std::collections::HashMap;
-use std::time::Instant;
-
-pub async fn download_offers_from_mirror(data: web::Data<AppState>) -> anyhow::Result<()> {
-    let url = match std::env::var("OFFER_SOURCE_URL") {
-        Ok(url) => url,
-        Err(_) => {
-            log::warn!("INITIAL_OFFERS_URL not set, skipping download offers");
-            return Ok(());
-        }
-    };
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Position in the change feed of the source, if it is another matcher
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedCursor {
+    pub epoch: String,
+    pub seq: u64,
+}
+
+/// Synchronization state of a single mirror source
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceStatus {
+    pub url: String,
+    /// Set when the source provides change feed, next sync downloads only changes after it
+    pub cursor: Option<FeedCursor>,
+    pub last_attempt: Option<DateTime<Utc>>,
+    pub last_success: Option<DateTime<Utc>>,
+    /// Error of the last attempt, cleared after successful one
+    pub last_error: Option<String>,
+    pub last_duration_ms: Option<f64>,
+    /// Number of offers returned by the source in the last successful sync
+    pub received: usize,
+    /// Result of merging offers of the last successful sync
+    pub last_counts: IngestCounts,
+    pub successes: u64,
+    pub failures: u64,
+    #[serde(skip)]
+    in_progress: bool,
+}
 
-    log::info!("Downloading initial offers from {}", url);
+/// Status of all configured mirror sources, exposed on /offers/sources
+#[derive(Debug, Default)]
+pub struct MirrorStatus {
+    sources: Mutex<BTreeMap<String, SourceStatus>>,
+}
 
-    let response = match reqwest::get(&url).await {
-        Ok(resp) => resp,
-        Err(e) => {
-            log::error!("Failed to download offers: {}", e);
-            return Err(e.into());
+impl MirrorStatus {
+    fn sources(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, SourceStatus>> {
+        self.sources.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Marks source as being synchronized if its interval passed since the last attempt
+    /// and previous sync is finished. Returns false if the source is not due.
+    fn try_start(&self, source: &MirrorSource, interval_secs: f64, now: DateTime<Utc>) -> bool {
+        let mut sources = self.sources();
+        let status = sources.entry(source.name.clone()).or_default();
+        status.url = source.url.clone();
+        if status.in_progress {
+            return false;
         }
-    };
+        let interval = chrono::Duration::milliseconds((interval_secs * 1000.0) as i64);
+        if let Some(last_attempt) = status.last_attempt {
+            if last_attempt + interval > now {
+                return false;
+            }
+        }
+        status.in_progress = true;
+        status.last_attempt = Some(now);
+        true
+    }
+
+    fn cursor(&self, name: &str) -> Option<FeedCursor> {
+        self.sources()
+            .get(name)
+            .and_then(|status| status.cursor.clone())
+    }
 
-    let text = match response.text().await {
-        Ok(t) => t,
-        Err(e) => {
-            log::error!("Failed to read response body: {}", e);
-            return Err(e.into());
+    fn finish(&self, name: &str, duration: Duration, res: &anyhow::Result<SyncResult>) {
+        let mut sources = self.sources();
+        let status = sources.entry(name.to_string()).or_default();
+        status.in_progress = false;
+        status.last_duration_ms = Some(duration.as_secs_f64() * 1000.0);
+        match res {
+            Ok(result) => {
+                status.last_success = status.last_attempt;
+                status.last_error = None;
+                status.received = result.received;
+                status.last_counts = result.counts;
+                status.cursor = result.cursor.clone();
+                status.successes += 1;
+            }
+            Err(e) => {
+                status.last_error = Some(e.to_string());
+                status.failures += 1;
+            }
         }
-    };
-    let perf_start = Instant::now();
+    }
+
+    /// Forgets sources removed from configuration
+    fn retain(&self, names: &[String]) {
+        self.sources().retain(|name, _| names.contains(name));
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, SourceStatus> {
+        self.sources().clone()
+    }
+}
 
-    let offers: Vec<OfferObj> = match serde_json::from_str::<Vec<OfferObj>>(&text) {
-        Ok(offers) => offers,
-        Err(e) => {
-            log::error!("Failed to parse offers: {}", e);
-            return Err(e.into());
+/// Starts synchronization of every source whose interval elapsed. Sources are synchronized
+/// in separate tasks, so a slow or unreachable source does not delay the others.
+pub async fn synchronize_due_sources(data: web::Data<AppState>) {
+    let config = data.config.get();
+    let sources = config.mirror.all_sources();
+    data.mirror_status.retain(
+        &sources
+            .iter()
+            .map(|source| source.name.clone())
+            .collect::<Vec<_>>(),
+    );
+    let now = Utc::now();
+    for source in sources {
+        let interval_secs = config.mirror.interval_secs(&source);
+        if data.mirror_status.try_start(&source, interval_secs, now) {
+            tokio::spawn(download_offers_from_mirror(data.clone(), source));
         }
-    };
+    }
+}
 
-    if offers.is_empty() {
-        log::warn!("No valid offers downloaded");
-        return Ok(());
+pub async fn download_offers_from_mirror(data: web::Data<AppState>, source: MirrorSource) {
+    log::info!("Downloading offers from {} ({})", source.name, source.url);
+
+    let perf_start = Instant::now();
+    let res = sync_offers_from_source(&data, &source).await;
+    if let Err(e) = &res {
+        log::error!("Failed to synchronize offers from {}: {}", source.name, e);
     }
+    data.metrics
+        .record_mirror_sync(perf_start.elapsed(), res.is_ok());
+    data.mirror_status
+        .finish(&source.name, perf_start.elapsed(), &res);
+}
 
-    let mut lock = data.lock.lock().await;
+/// Url of the change feed if the source is another matcher (its offer list endpoint)
+fn changes_url(url: &str) -> Option<String> {
+    url.strip_suffix("/offers/list")
+        .map(|base| format!("{}/offers/changes", base))
+}
 
-    //build map of existing by provider_id
-    let mut by_provider_id = HashMap::new();
+fn request(source: &MirrorSource, url: &str) -> anyhow::Result<reqwest::RequestBuilder> {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs_f64(source.timeout_secs))
+        .build()?;
+    let mut request = client.get(url);
+    if let Some(auth_header) = &source.auth_header {
+        request = request.header(reqwest::header::AUTHORIZATION, auth_header);
+    }
+    Ok(request)
+}
 
-    for offer in lock.offer_map.iter() {
-        let res = by_provider_id.insert(offer.1.offer.provider_id, offer.1.clone());
-        if res.is_some() {
-            log::warn!(
-                "Multiple existing offers from provider {}",
-                offer.1.offer.provider_id
-            );
-        }
+async fn fetch_offers(source: &MirrorSource) -> anyhow::Result<Vec<OfferObj>> {
+    let response = request(source, &source.url)?
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to download offers: {}", e))?
+        .error_for_status()?;
+    let text = response
+        .text()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read response body: {}", e))?;
+    serde_json::from_str::<Vec<OfferObj>>(&text)
+        .map_err(|e| anyhow::anyhow!("Failed to parse offers: {}", e))
+}
+
+/// Downloads changes after the cursor, None if the source does not provide change feed
+async fn fetch_changes(
+    source: &MirrorSource,
+    url: &str,
+    cursor: Option<&FeedCursor>,
+) -> anyhow::Result<Option<OfferChanges>> {
+    let mut request = request(source, url)?;
+    if let Some(cursor) = cursor {
+        request = request.query(&[
+            ("since", cursor.seq.to_string()),
+            ("epoch", cursor.epoch.clone()),
+        ]);
     }
+    let response = request
+        .send()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to download offer changes: {}", e))?;
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let text = response
+        .error_for_status()?
+        .text()
+        .await
+        .map_err(|e| anyhow::anyhow!("Failed to read response body: {}", e))?;
+    serde_json::from_str::<OfferChanges>(&text)
+        .map(Some)
+        .map_err(|e| anyhow::anyhow!("Failed to parse offer changes: {}", e))
+}
 
-    let mut added = 0;
-    let mut removed = 0;
-    let mut already_present = 0;
-    let mut ignored = 0;
-    for offer in offers {
-        if lock.offer_map.contains_key(&offer.offer.id) {
-            already_present += 1;
-            continue;
+struct SyncResult {
+    received: usize,
+    counts: IngestCounts,
+    cursor: Option<FeedCursor>,
+}
+
+/// Downloads offers from other offer server. Matchers are asked only for changes since
+/// the previous sync, others return full list every time. Returns offers, ids of removed
+/// offers and position in the change feed.
+async fn fetch_from_offer_server(
+    data: &AppState,
+    source: &MirrorSource,
+) -> anyhow::Result<(Vec<OfferObj>, Vec<String>, Option<FeedCursor>)> {
+    let changes = match changes_url(&source.url) {
+        Some(url) => {
+            let cursor = data.mirror_status.cursor(&source.name);
+            let changes = fetch_changes(source, &url, cursor.as_ref()).await?;
+            if changes.is_none() {
+                log::info!(
+                    "Source {} does not provide change feed, downloading all offers",
+                    source.name
+                );
+            }
+            changes
         }
-        let mut to_remove = None;
-
-        if by_provider_id.contains_key(&offer.offer.provider_id) {
-            let by_provider_offer = by_provider_id
-                .get(&offer.offer.provider_id)
-                .expect("Has to contain that");
-            if by_provider_offer.offer.timestamp < offer.offer.timestamp {
-                //great, new offer is newer than older one
-                to_remove = Some(by_provider_offer.offer.id.clone());
-                by_provider_id.insert(offer.offer.provider_id, offer.clone());
-            } else {
-                //skip, older offer
-                ignored += 1;
-                continue;
+        None => None,
+    };
+    match changes {
+        Some(changes) => {
+            if changes.reset {
+                log::info!(
+                    "Synchronizing all offers from {}, epoch {}",
+                    source.name,
+                    changes.epoch
+                );
             }
-        } else {
-            by_provider_id.insert(offer.offer.provider_id, offer.clone());
+            let cursor = FeedCursor {
+                epoch: changes.epoch,
+                seq: changes.seq,
+            };
+            let removed = changes
+                .removed
+                .into_iter()
+                .map(|tombstone| tombstone.id)
+                .collect();
+            Ok((changes.offers, removed, Some(cursor)))
         }
+        None => Ok((fetch_offers(source).await?, Vec::new(), None)),
+    }
+}
 
-        if let Some(remove_id) = to_remove {
-            lock.offer_map.remove(&remove_id);
-            removed += 1;
+/// Downloads offers from the source and merges them. Offers removed at the source
+/// are removed here too, unless they are already assigned to some requestor.
+async fn sync_offers_from_source(
+    data: &AppState,
+    source: &MirrorSource,
+) -> anyhow::Result<SyncResult> {
+    // offers which failed to decode, counted as rejected by ingestion
+    let mut undecodable = IngestCounts::default();
+    let (mut offers, removed, cursor) = match source.kind {
+        SourceKind::Offers => fetch_from_offer_server(data, source).await?,
+        SourceKind::Arkiv => {
+            let known = data.lock.lock().await.all().await?;
+            let arkiv_offers = arkiv::fetch_offers(source, &known).await?;
+            undecodable.rejected = arkiv_offers.rejected;
+            (arkiv_offers.offers, arkiv_offers.removed, None)
         }
-        lock.offer_map.insert(offer.offer.id.clone(), offer);
-        added += 1;
+    };
+    data.metrics.record_ingest(undecodable);
+    let received = offers.len();
+    if offers.is_empty() && removed.is_empty() {
+        if cursor.is_none() {
+            log::warn!("No valid offers downloaded from {}", source.name);
+        }
+        return Ok(SyncResult {
+            received,
+            counts: undecodable,
+            cursor,
+        });
+    }
+    for offer in offers.iter_mut() {
+        offer.source = Some(source.name.clone());
     }
+
+    let rules = data.config.get().validation.clone();
+    let perf_start = Instant::now();
+    let counts = {
+        let mut lock = data.lock.lock().await;
+        let mut counts = ingest_offers(&mut lock, &data.metrics, &rules, offers)
+            .await?
+            .counts;
+        let mut removed_at_source = 0;
+        // offers assigned here are kept, they are cleaned up when they expire
+        for id in removed {
+            let from_source = lock.get(&id).await?.is_some_and(|offer_obj| {
+                offer_obj.source.as_deref() == Some(source.name.as_str())
+                    && offer_obj.requestor_id.is_none()
+            });
+            if from_source {
+                lock.remove(&id).await?;
+                removed_at_source += 1;
+            }
+        }
+        data.metrics.record_ingest(IngestCounts {
+            removed: removed_at_source,
+            ..Default::default()
+        });
+        counts.removed += removed_at_source;
+        counts.rejected += undecodable.rejected;
+        counts
+    };
     if perf_start.elapsed().as_secs_f64() > 0.01 {
         log::warn!(
             "Insert offers took too long: {:.2} ms",
@@ -108,11 +328,156 @@ pub async fn download_offers_from_mirror(data: web::Data<AppState>) -> anyhow::R
     }
 
     log::info!(
-        "Loaded {} new offers, there was {} already existing, removed {} older offers, ignored {} outdated offers",
-        added,
-        already_present,
-        removed,
-        ignored
+        "Loaded {} new offers from {}, there was {} already existing, removed {} older offers, ignored {} outdated offers, rejected {} invalid offers",
+        counts.added,
+        source.name,
+        counts.already_present,
+        counts.removed,
+        counts.ignored,
+        counts.rejected
     );
-    Ok(())
+    Ok(SyncResult {
+        received,
+        counts,
+        cursor,
+    })
+}
+
+#[tokio::test]
+async fn test_mirror_source_status() {
+    use crate::test_util::{app_state, offer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let body = serde_json::to_string(&vec![offer("offer", 1)]).unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // answers only requests carrying the expected token
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut buf = vec![0u8; 4096];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+            let response = if request.starts_with("get /offers/changes") {
+                "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    .to_string()
+            } else if request.contains("authorization: bearer secret") {
+                format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+            } else {
+                "HTTP/1.1 401 Unauthorized\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    .to_string()
+            };
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+
+    let data = app_state();
+    let mut source = MirrorSource::new("local", &format!("http://{}/offers/list", addr));
+
+    assert!(data.mirror_status.try_start(&source, 60.0, Utc::now()));
+    download_offers_from_mirror(data.clone(), source.clone()).await;
+    let status = data.mirror_status.snapshot()["local"].clone();
+    assert!(status.last_error.unwrap().contains("401"));
+    assert!(status.last_success.is_none());
+    assert_eq!(status.failures, 1);
+    // not due yet
+    assert!(!data.mirror_status.try_start(&source, 60.0, Utc::now()));
+
+    source.auth_header = Some("Bearer secret".to_string());
+    let later = Utc::now() + chrono::Duration::seconds(61);
+    assert!(data.mirror_status.try_start(&source, 60.0, later));
+    download_offers_from_mirror(data.clone(), source).await;
+    let status = data.mirror_status.snapshot()["local"].clone();
+    assert!(status.last_error.is_none());
+    assert_eq!(status.last_success, Some(later));
+    assert_eq!(status.received, 1);
+    assert_eq!(status.last_counts.added, 1);
+    assert!(status.cursor.is_none());
+    let offers = data.lock.lock().await.all().await.unwrap();
+    assert_eq!(offers[0].source.as_deref(), Some("local"));
+
+    data.mirror_status.retain(&[]);
+    assert!(data.mirror_status.snapshot().is_empty());
+}
+
+#[actix_web::test]
+async fn test_mirror_change_feed() {
+    use crate::rest::offer::list_offers::{list_offer_changes, list_offers};
+    use crate::test_util::{app_state, offer};
+
+    let upstream = app_state();
+    for (id, provider) in [("a", 1), ("b", 2)] {
+        upstream
+            .lock
+            .lock()
+            .await
+            .insert(offer(id, provider))
+            .await
+            .unwrap();
+    }
+    let server_state = upstream.clone();
+    let server = actix_web::HttpServer::new(move || {
+        actix_web::App::new()
+            .app_data(server_state.clone())
+            .route("/offers/list", web::get().to(list_offers))
+            .route("/offers/changes", web::get().to(list_offer_changes))
+    })
+    .workers(1)
+    .bind("127.0.0.1:0")
+    .unwrap();
+    let addr = server.addrs()[0];
+    let handle = server.run();
+    let server_handle = handle.handle();
+    actix_web::rt::spawn(handle);
+
+    let data = app_state();
+    let source = MirrorSource::new("matcher", &format!("http://{}/offers/list", addr));
+    let sync = |data: web::Data<AppState>, source: MirrorSource, day: i64| async move {
+        assert!(data.mirror_status.try_start(
+            &source,
+            1.0,
+            Utc::now() + chrono::Duration::days(day)
+        ));
+        download_offers_from_mirror(data.clone(), source).await;
+        data.mirror_status.snapshot()["matcher"].clone()
+    };
+
+    // first sync gets everything
+    let status = sync(data.clone(), source.clone(), 1).await;
+    assert!(status.last_error.is_none(), "{:?}", status.last_error);
+    assert_eq!(status.cursor.as_ref().unwrap().seq, 2);
+    assert_eq!(status.last_counts.added, 2);
+
+    // then only changes, including removal
+    {
+        let mut lock = upstream.lock.lock().await;
+        lock.remove("a").await.unwrap();
+        lock.insert(offer("c", 3)).await.unwrap();
+    }
+    let status = sync(data.clone(), source.clone(), 2).await;
+    assert_eq!(status.received, 1);
+    assert_eq!(status.last_counts.added, 1);
+    assert_eq!(status.last_counts.removed, 1);
+    assert_eq!(status.cursor.as_ref().unwrap().seq, 4);
+    let ids: Vec<String> = data
+        .lock
+        .lock()
+        .await
+        .all()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|offer_obj| offer_obj.offer.id)
+        .collect();
+    assert_eq!(ids, vec!["b", "c"]);
+
+    // nothing changed
+    let status = sync(data.clone(), source, 3).await;
+    assert_eq!(status.received, 0);
+    assert_eq!(status.cursor.unwrap().seq, 4);
+
+    server_handle.stop(true).await;
 }
@@ -0,0 +1,205 @@
+use crate::scheduler::FairShareUsage;
+use crate::state::{AppState, DemandObj, OfferObj};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::Instant;
+
+/// Offers and demands are stored only when their storage is not persistent on its own,
+/// fair-share usage is always stored
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StateSnapshot {
+    pub saved_at: Option<DateTime<Utc>>,
+    pub offers: Vec<OfferObj>,
+    pub demands: Vec<DemandObj>,
+    #[serde(default)]
+    pub fair_share: FairShareUsage,
+}
+
+impl StateSnapshot {
+    /// Removes offers and demands that expired while the server was down,
+    /// together with queue entries pointing to offers that are gone.
+    /// Returns number of removed offers and demands.
+    pub fn drop_expired(&mut self, now: DateTime<Utc>) -> (usize, usize) {
+        let offers_before = self.offers.len();
+        self.offers
+            .retain(|offer_obj| offer_obj.offer.expiration > now);
+
+        let demands_before = self.demands.len();
+        self.demands
+            .retain(|demand_obj| demand_obj.demand.expiration_ts.and_utc() > now);
+
+        let offer_ids: HashSet<&str> = self.offers.iter().map(|o| o.offer.id.as_str()).collect();
+        for demand_obj in self.demands.iter_mut() {
+            demand_obj
+                .offer_list
+                .retain(|offer_id| offer_ids.contains(offer_id.as_str()));
+        }
+
+        (
+            offers_before - self.offers.len(),
+            demands_before - self.demands.len(),
+        )
+    }
+}
+
+/// Borrowed form of [`StateSnapshot`], so published snapshots are saved without copying
+#[derive(Serialize)]
+struct StateSnapshotRef<'a> {
+    saved_at: Option<DateTime<Utc>>,
+    offers: Vec<&'a OfferObj>,
+    demands: Vec<&'a DemandObj>,
+    fair_share: FairShareUsage,
+}
+
+pub async fn save_state(data: &AppState, file_name: &str) -> anyhow::Result<()> {
+    let perf_start = Instant::now();
+    // read without the locks, so demands can be published a batch later than offers.
+    // Their queue entries pointing to offers missing in the file are dropped on load.
+    let offers = match data.lock.is_persistent() {
+        true => None,
+        false => Some(data.lock.snapshot().await?),
+    };
+    let demands = match data.demands.is_persistent() {
+        true => None,
+        false => Some(data.demands.snapshot().await?),
+    };
+    let now = Utc::now();
+    let window_secs = data.config.get().picker.share_window_secs;
+    let snapshot = StateSnapshotRef {
+        saved_at: Some(now),
+        offers: offers
+            .as_ref()
+            .map(|offers| offers.filter(|_| true))
+            .unwrap_or_default(),
+        demands: demands
+            .as_ref()
+            .map(|demands| demands.filter(|_| true))
+            .unwrap_or_default(),
+        fair_share: data.fair_share.usage(now, window_secs),
+    };
+
+    let serialized = serde_json::to_string(&snapshot)?;
+    // write to temporary file first, so crash during write does not corrupt last snapshot
+    let tmp_file_name = format!("{}.tmp", file_name);
+    tokio::fs::write(&tmp_file_name, serialized).await?;
+    tokio::fs::rename(&tmp_file_name, file_name).await?;
+
+    log::debug!(
+        "Saved {} offers and {} demands to {} in {:.2} ms",
+        snapshot.offers.len(),
+        snapshot.demands.len(),
+        file_name,
+        perf_start.elapsed().as_secs_f64() * 1000.0
+    );
+    Ok(())
+}
+
+pub fn load_state(file_name: &str) -> anyhow::Result<Option<StateSnapshot>> {
+    if !Path::new(file_name).exists() {
+        log::info!(
+            "State file {} not found, starting with empty state",
+            file_name
+        );
+        return Ok(None);
+    }
+    let contents = std::fs::read_to_string(file_name)?;
+    let mut snapshot = serde_json::from_str::<StateSnapshot>(&contents)?;
+
+    let (removed_offers, removed_demands) = snapshot.drop_expired(Utc::now());
+    log::info!(
+        "Loaded {} offers and {} demands from {} (saved at {}), dropped {} expired offers and {} expired demands",
+        snapshot.offers.len(),
+        snapshot.demands.len(),
+        file_name,
+        snapshot
+            .saved_at
+            .map(|ts| ts.to_rfc3339())
+            .unwrap_or_else(|| "unknown".to_string()),
+        removed_offers,
+        removed_demands
+    );
+    Ok(Some(snapshot))
+}
+
+/// Puts loaded offers and demands into the stores, so switching from memory
+/// to sqlite storage carries the state over. Fair-share usage continues decaying
+/// from the time it was saved, so the downtime counts as idle time.
+pub async fn restore_state(data: &AppState, snapshot: StateSnapshot) -> anyhow::Result<()> {
+    data.fair_share.restore(snapshot.fair_share);
+    let mut demands = data.demands.lock().await;
+    let mut offers = data.lock.lock().await;
+    for offer_obj in snapshot.offers {
+        offers.insert(offer_obj).await?;
+    }
+    for demand_obj in snapshot.demands {
+        demands.insert(demand_obj).await?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_drop_expired_from_snapshot() {
+    use crate::test_util::{demand, golem_offer, offer};
+    use std::collections::VecDeque;
+
+    let mut expired = golem_offer("expired", 1);
+    expired.expiration = Utc::now() - chrono::Duration::minutes(1);
+
+    let mut snapshot = StateSnapshot::default();
+    snapshot.offers.push(OfferObj::new(expired));
+    snapshot.offers.push(offer("valid", 2));
+    snapshot.demands.push(DemandObj {
+        demand: demand("demand", 0x11, Utc::now() + chrono::Duration::hours(1)),
+        offer_list: VecDeque::from(vec!["expired".to_string(), "valid".to_string()]),
+        allocated: 2,
+    });
+
+    assert_eq!(snapshot.drop_expired(Utc::now()), (1, 0));
+    assert_eq!(snapshot.offers[0].offer.id, "valid");
+    assert_eq!(
+        snapshot.demands[0].offer_list,
+        VecDeque::from(vec!["valid".to_string()])
+    );
+}
+
+#[tokio::test]
+async fn test_fair_share_usage_survives_restart() {
+    use crate::test_util::app_state;
+
+    let file_name = std::env::temp_dir().join(format!(
+        "offer-server-state-{}-{}.json",
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    ));
+    let file_name = file_name.to_str().unwrap();
+    let window_secs = 3600.0;
+    let assigned_at = Utc::now() - chrono::Duration::minutes(10);
+
+    let data = app_state();
+    data.fair_share
+        .record("net-a", "node-1", assigned_at, window_secs);
+    data.fair_share
+        .record("net-a", "node-1", assigned_at, window_secs);
+    data.fair_share
+        .record("net-b", "node-2", assigned_at, window_secs);
+    save_state(&data, file_name).await.unwrap();
+
+    let restarted = app_state();
+    restore_state(&restarted, load_state(file_name).unwrap().unwrap())
+        .await
+        .unwrap();
+    std::fs::remove_file(file_name).ok();
+
+    let now = Utc::now();
+    let saved = data.fair_share.usage(now, window_secs);
+    assert_eq!(saved.requestors.len(), 2);
+    assert_eq!(restarted.fair_share.usage(now, window_secs), saved);
+    // assigned 10 minutes ago, e^(-1/6) of it left, and it keeps decaying over the downtime
+    let requestor = &saved.requestors[0];
+    let later = now + chrono::Duration::hours(1);
+    let before = requestor.usage.at(now, window_secs);
+    assert!(before > 1.6 && before < 1.8, "{}", before);
+    assert!(requestor.usage.at(later, window_secs) < before / 2.0);
+}
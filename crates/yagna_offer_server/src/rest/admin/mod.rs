@@ -0,0 +1,140 @@
+use crate::rest::demand::release_queued_offers;
+use crate::rest::storage_error;
+use crate::state::AppState;
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use ya_client_model::NodeId;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResetCounters {
+    /// Reset only counters of this requestor node, all counters if not given
+    pub node_id: Option<NodeId>,
+}
+
+/// Deletes the offer, taking it out of the queue of the demand it is assigned to
+pub async fn delete_offer(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let config = data.config.get();
+    let mut lock = data.demands.lock().await;
+    let mut offers_lock = data.lock.lock().await;
+    let offer_obj = match offers_lock.get(&path).await {
+        Ok(Some(offer_obj)) => offer_obj,
+        Ok(None) => return HttpResponse::NotFound().body("Offer not found"),
+        Err(e) => return storage_error(e),
+    };
+    if let Some(requestor_id) = offer_obj.requestor_id {
+        let demand_obj = match lock.find_by_node(requestor_id).await {
+            Ok(demand_obj) => demand_obj,
+            Err(e) => return storage_error(e),
+        };
+        // queued offer is taken back from the demand, as when the demand releases it
+        if let Some(mut demand_obj) = demand_obj {
+            if let Some(pos) = demand_obj.offer_list.iter().position(|id| *id == *path) {
+                demand_obj.offer_list.remove(pos);
+                data.fair_share
+                    .released(&demand_obj, &offer_obj, Utc::now(), &config.picker);
+                if let Err(e) = lock.insert(demand_obj).await {
+                    return storage_error(e);
+                }
+            }
+        }
+    }
+    match offers_lock.remove(&path).await {
+        Ok(_) => HttpResponse::Ok().body("Offer deleted successfully"),
+        Err(e) => storage_error(e),
+    }
+}
+
+pub async fn delete_demand(data: web::Data<AppState>, path: web::Path<String>) -> HttpResponse {
+    let config = data.config.get();
+    let mut lock = data.demands.lock().await;
+    let mut offers_lock = data.lock.lock().await;
+    let demand_obj = match lock.remove(&path).await {
+        Ok(Some(demand_obj)) => demand_obj,
+        Ok(None) => return HttpResponse::NotFound().body("Demand not found"),
+        Err(e) => return storage_error(e),
+    };
+    data.demand_notifier.remove(&demand_obj.demand.id);
+    match release_queued_offers(
+        &mut offers_lock,
+        &demand_obj,
+        &data.fair_share,
+        &config.picker,
+    )
+    .await
+    {
+        Ok(released) => HttpResponse::Ok().body(format!(
+            "Demand deleted successfully, released {} offers",
+            released
+        )),
+        Err(e) => storage_error(e),
+    }
+}
+
+pub async fn reset_counters(
+    data: web::Data<AppState>,
+    query: web::Query<ResetCounters>,
+) -> HttpResponse {
+    let node_id = query.node_id.map(|node_id| node_id.to_string());
+    let count = data.fair_share.reset(node_id.as_deref());
+    match node_id {
+        Some(_) if count == 0 => HttpResponse::NotFound().body("No counter for the node"),
+        Some(node_id) => HttpResponse::Ok().body(format!(
+            "Reset {} counters of node {}, one per central net",
+            count, node_id
+        )),
+        None => HttpResponse::Ok().body(format!("Reset {} counters", count)),
+    }
+}
+
+/// Reloads configuration file and environment, responds with list of changed settings
+pub async fn reload_config(data: web::Data<AppState>) -> HttpResponse {
+    match data.config.reload() {
+        Ok(changes) => HttpResponse::Ok().json(changes),
+        Err(e) => {
+            log::error!("Failed to reload configuration, keeping current one: {}", e);
+            HttpResponse::BadRequest().body(format!("Invalid configuration: {}", e))
+        }
+    }
+}
+
+#[actix_web::test]
+async fn test_delete_offer_removes_it_from_demand_queue() {
+    use crate::state::DemandObj;
+    use crate::test_util::{app_state, demand, offer};
+    use actix_web::http::StatusCode;
+
+    let data = app_state();
+    let mut demand = demand("demand", 0x11, Utc::now() + chrono::Duration::hours(1));
+    demand.central_net_address = Some("net".to_string());
+    let mut demand_obj = DemandObj::new(demand);
+    for id in ["deleted", "kept"] {
+        let mut offer_obj = offer(id, 1);
+        offer_obj.assign(demand_obj.demand.node_id);
+        demand_obj.push_offer(id.to_string());
+        data.fair_share
+            .assigned(&demand_obj, Utc::now(), &data.config.get().picker);
+        data.lock.lock().await.insert(offer_obj).await.unwrap();
+    }
+    data.demands.lock().await.insert(demand_obj).await.unwrap();
+
+    let resp = delete_offer(data.clone(), web::Path::from("deleted".to_string())).await;
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = delete_offer(data.clone(), web::Path::from("deleted".to_string())).await;
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+    let demand_obj = data
+        .demands
+        .lock()
+        .await
+        .get("demand")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(demand_obj.offer_list, ["kept"]);
+    let window_secs = data.config.get().picker.share_window_secs;
+    let usage = data.fair_share.usage(Utc::now(), window_secs);
+    let requestor = usage.requestors.first().unwrap();
+    assert!((requestor.usage.at(Utc::now(), window_secs) - 1.0).abs() < 0.01);
+}
@@ -1,8 +1,10 @@
-use crate::state::{AppState, DemandObj};
-use actix_web::{web, HttpResponse};
+use crate::constraints::DemandMatcher;
+use crate::rest::demand::find_demand;
+use crate::rest::storage_error;
+use crate::state::AppState;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use std::str::FromStr;
-use ya_client_model::NodeId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -11,7 +13,11 @@ pub struct AddOfferToDemand {
     pub offer_id: String,
 }
 
-pub async fn add_offer_to_demand(data: web::Data<AppState>, body: String) -> HttpResponse {
+pub async fn add_offer_to_demand(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: String,
+) -> HttpResponse {
     let decoded = serde_json::from_str::<AddOfferToDemand>(&body);
     let add_offer = match decoded {
         Ok(filer) => filer,
@@ -23,48 +29,138 @@ pub async fn add_offer_to_demand(data: web::Data<AppState>, body: String) -> Htt
     let demand_id = add_offer.demand_id;
     let offer_id = add_offer.offer_id;
 
+    // signature is recovered without holding the locks, it would stall all demand traffic
+    let node_id = match find_demand(&*data.demands.lock().await, &demand_id).await {
+        Ok(demand_obj) => demand_obj.demand.node_id,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = data.auth.verify(&req, &body, node_id) {
+        return resp;
+    }
+
     let mut lock = data.demands.lock().await;
     let mut offers_lock = data.lock.lock().await;
 
-    let offer = offers_lock.offer_map.get_mut(&offer_id);
-
-    let offer = match offer {
-        Some(offer) => offer,
-        None => {
+    let mut offer = match offers_lock.get(&offer_id).await {
+        Ok(Some(offer)) => offer,
+        Ok(None) => {
             return HttpResponse::NotFound().body("Offer not found");
         }
+        Err(e) => return storage_error(e),
     };
 
-    let get_demand = match lock.demand_map.contains_key(&demand_id) {
-        true => lock.demand_map.get_mut(&demand_id),
-        false => {
-            let node_id = match NodeId::from_str(&demand_id) {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid offer ID format or not found");
-                }
-            };
-            let mut get_demand: Option<&mut DemandObj> = None;
-            for (_, v) in lock.demand_map.iter_mut() {
-                if v.demand.node_id == node_id {
-                    get_demand = Some(v);
-                    break;
-                }
-            }
-            get_demand
-        }
-    };
-
-    let demand_obj = match get_demand {
-        Some(demand) => demand,
-        None => {
-            return HttpResponse::NotFound().body("Demand not found");
-        }
+    let mut demand_obj = match find_demand(&lock, &demand_id).await {
+        Ok(demand) => demand,
+        Err(resp) => return resp,
     };
     if offer.requestor_id.is_some() {
         return HttpResponse::Conflict().body("Offer is already taken");
     }
-    offer.requestor_id = Some(demand_obj.demand.node_id);
-    demand_obj.offer_list.push_back(offer.offer.id.clone());
+    let config = data.config.get();
+    if !demand_obj.wants_offers(config.picker.queue_depth) {
+        return HttpResponse::Conflict()
+            .body("Demand queue is full or the demand reached its maximum number of offers");
+    }
+    match DemandMatcher::new(&demand_obj.demand) {
+        Ok(matcher) => {
+            if !matcher.matches(offer.matching()) {
+                return HttpResponse::BadRequest()
+                    .body("Offer does not match demand constraints or demand properties");
+            }
+        }
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    }
+    offer.assign(demand_obj.demand.node_id);
+    demand_obj.push_offer(offer.offer.id.clone());
+    if let Err(e) = offers_lock.insert(offer).await {
+        return storage_error(e);
+    }
+    data.fair_share
+        .assigned(&demand_obj, Utc::now(), &config.picker);
+    let demand_id = demand_obj.demand.id.clone();
+    if let Err(e) = lock.insert(demand_obj).await {
+        return storage_error(e);
+    }
+    data.demand_notifier.notify(&demand_id);
     HttpResponse::Ok().body("Offer added to demand successfully")
 }
+
+#[actix_web::test]
+async fn test_append_offer_requires_signature() {
+    use crate::auth::SignatureVerifier;
+    use crate::rest::demand::pick_offer_to_demand::pick_offer_to_demand;
+    use crate::state::DemandObj;
+    use crate::test_util::{app_state, demand, offer};
+    use actix_web::http::StatusCode;
+    use std::sync::Arc;
+
+    let mut state = AppState::clone(&app_state());
+    state.auth = Arc::new(SignatureVerifier::new(true, 60));
+    let data = web::Data::new(state);
+    let demand = demand("demand", 0x11, Utc::now() + chrono::Duration::hours(1));
+    data.demands
+        .lock()
+        .await
+        .insert(DemandObj::new(demand))
+        .await
+        .unwrap();
+    data.lock
+        .lock()
+        .await
+        .insert(offer("offer", 1))
+        .await
+        .unwrap();
+
+    let unsigned = || actix_web::test::TestRequest::post().to_http_request();
+    let body = r#"{"demandId": "demand", "offerId": "offer"}"#.to_string();
+    let resp = add_offer_to_demand(data.clone(), unsigned(), body).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+    let body = r#"{"demandId": "0x1111111111111111111111111111111111111111"}"#.to_string();
+    let resp = pick_offer_to_demand(data.clone(), unsigned(), body).await;
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    let demand_obj = data
+        .demands
+        .lock()
+        .await
+        .get("demand")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(demand_obj.offer_list.is_empty());
+    let offer_obj = data.lock.lock().await.get("offer").await.unwrap().unwrap();
+    assert!(offer_obj.requestor_id.is_none());
+}
+
+#[actix_web::test]
+async fn test_append_offer_to_full_demand() {
+    use crate::rest::demand::pick_offer_to_demand::pick_offer_to_demand;
+    use crate::state::DemandObj;
+    use crate::test_util::{app_state, demand, offer};
+    use actix_web::http::StatusCode;
+
+    let data = app_state();
+    let mut demand = demand("demand", 0x11, Utc::now() + chrono::Duration::hours(1));
+    demand.max_offers = Some(1);
+    let mut demand_obj = DemandObj::new(demand);
+    demand_obj.push_offer("taken".to_string());
+    demand_obj.offer_list.clear();
+    data.demands.lock().await.insert(demand_obj).await.unwrap();
+    data.lock
+        .lock()
+        .await
+        .insert(offer("offer", 1))
+        .await
+        .unwrap();
+
+    let request = || actix_web::test::TestRequest::post().to_http_request();
+    let body = r#"{"demandId": "demand", "offerId": "offer"}"#.to_string();
+    let resp = add_offer_to_demand(data.clone(), request(), body).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+    let body = r#"{"demandId": "0x1111111111111111111111111111111111111111"}"#.to_string();
+    let resp = pick_offer_to_demand(data.clone(), request(), body).await;
+    assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+    let offer_obj = data.lock.lock().await.get("offer").await.unwrap().unwrap();
+    assert!(offer_obj.requestor_id.is_none());
+}
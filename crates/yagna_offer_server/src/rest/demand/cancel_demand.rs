@@ -1,8 +1,14 @@
 use crate::model::demand::base::DemandCancellation;
+use crate::rest::demand::release_queued_offers;
+use crate::rest::storage_error;
 use crate::state::AppState;
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 
-pub async fn demand_cancel(data: web::Data<AppState>, item: String) -> HttpResponse {
+pub async fn demand_cancel(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    item: String,
+) -> HttpResponse {
     let decode = serde_json::from_str::<DemandCancellation>(&item);
 
     let cancellation = match decode {
@@ -13,10 +19,44 @@ pub async fn demand_cancel(data: web::Data<AppState>, item: String) -> HttpRespo
         }
     };
 
+    // signature is recovered without holding the locks, it would stall all demand traffic
+    let node_id = match data.demands.lock().await.get(&cancellation.demand_id).await {
+        Ok(Some(demand_obj)) => demand_obj.demand.node_id,
+        Ok(None) => return HttpResponse::NotFound().body("Demand not found"),
+        Err(e) => return storage_error(e),
+    };
+    if let Err(resp) = data.auth.verify(&req, &item, node_id) {
+        return resp;
+    }
+
+    let config = data.config.get();
     let mut lock = data.demands.lock().await;
-    if lock.demand_map.remove(&cancellation.demand_id).is_some() {
-        HttpResponse::Ok().body("Demand cancelled successfully")
-    } else {
-        HttpResponse::NotFound().body("Demand not found")
+    let mut offers_lock = data.lock.lock().await;
+    let demand_obj = match lock.remove(&cancellation.demand_id).await {
+        Ok(Some(demand_obj)) => demand_obj,
+        Ok(None) => return HttpResponse::NotFound().body("Demand not found"),
+        Err(e) => return storage_error(e),
+    };
+    data.demand_notifier.remove(&demand_obj.demand.id);
+    match release_queued_offers(
+        &mut offers_lock,
+        &demand_obj,
+        &data.fair_share,
+        &config.picker,
+    )
+    .await
+    {
+        Ok(released) => {
+            log::info!(
+                "Demand {} cancelled, {} queued offers returned to the pool",
+                demand_obj.demand.id,
+                released
+            );
+            HttpResponse::Ok().body(format!(
+                "Demand cancelled successfully, released {} offers",
+                released
+            ))
+        }
+        Err(e) => storage_error(e),
     }
 }
@@ -1,9 +1,11 @@
+use crate::constraints::DemandMatcher;
 use crate::model::demand::base::DemandSubscription;
+use crate::rest::storage_error;
 use crate::state::{AppState, DemandObj};
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use std::collections::VecDeque;
 
-pub async fn demand_new(data: web::Data<AppState>, item: String) -> HttpResponse {
+pub async fn demand_new(data: web::Data<AppState>, req: HttpRequest, item: String) -> HttpResponse {
     let decode = serde_json::from_str::<DemandSubscription>(&item);
 
     let demand = match decode {
@@ -14,19 +16,37 @@ pub async fn demand_new(data: web::Data<AppState>, item: String) -> HttpResponse
             return HttpResponse::BadRequest().body(format!("Invalid filter format {}", e));
         }
     };
+    if demand.queue_depth == Some(0) {
+        return HttpResponse::BadRequest().body("queueDepth has to be at least 1");
+    }
+    if demand.max_offers == Some(0) {
+        return HttpResponse::BadRequest().body("maxOffers has to be at least 1");
+    }
+    // demand that cannot be matched would never get any offer
+    if let Err(e) = DemandMatcher::new(&demand) {
+        return HttpResponse::BadRequest().body(format!("Invalid demand {}", e));
+    }
+    if let Err(resp) = data.auth.verify(&req, &item, demand.node_id) {
+        return resp;
+    }
     let mut lock = data.demands.lock().await;
 
-    if lock.demand_map.contains_key(&demand.id) {
-        return HttpResponse::Conflict().body("Demand with the same id already exists");
+    match lock.contains(&demand.id).await {
+        Ok(true) => {
+            return HttpResponse::Conflict().body("Demand with the same id already exists");
+        }
+        Ok(false) => {}
+        Err(e) => return storage_error(e),
     }
 
     // find existing demand from the same node
-    let last_demand = lock
-        .demand_map
-        .values()
-        .find(|v| v.demand.node_id == demand.node_id);
+    let last_demand = match lock.find_by_node(demand.node_id).await {
+        Ok(last_demand) => last_demand,
+        Err(e) => return storage_error(e),
+    };
 
     let mut copy_offer_list = VecDeque::new();
+    let mut replaced_id = None;
     if let Some(existing_demand) = last_demand {
         log::warn!(
             "Replacing existing demand {} from node {} with new demand {}",
@@ -34,20 +54,91 @@ pub async fn demand_new(data: web::Data<AppState>, item: String) -> HttpResponse
             existing_demand.demand.node_id,
             demand.id
         );
-        copy_offer_list = existing_demand.offer_list.clone();
+        copy_offer_list = existing_demand.offer_list;
+        replaced_id = Some(existing_demand.demand.id);
     }
 
     // Remove existing demand from the same node, including last_demand found above.
-    lock.demand_map
-        .retain(|_, v| v.demand.node_id != demand.node_id);
+    if let Err(e) = lock.retain(|v| v.demand.node_id != demand.node_id).await {
+        return storage_error(e);
+    }
+    if let Some(replaced_id) = replaced_id {
+        // waiters of the replaced demand respond instead of waiting for offers it never gets
+        data.demand_notifier.remove(&replaced_id);
+    }
 
-    let _ = lock.demand_map.insert(
-        demand.id.clone(),
-        DemandObj {
+    let res = lock
+        .insert(DemandObj {
             demand: demand.clone(),
+            // offers carried over from the replaced demand count against its maxOffers
+            allocated: copy_offer_list.len() as u64,
             offer_list: copy_offer_list,
-        },
-    );
+        })
+        .await;
+    match res {
+        Ok(()) => HttpResponse::Ok().json(demand),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[actix_web::test]
+async fn test_demand_settings_validated() {
+    use crate::test_util::{app_state, demand};
+
+    let data = app_state();
+    let demand = |settings: serde_json::Value| {
+        let demand = demand(
+            "demand",
+            0x11,
+            chrono::Utc::now() + chrono::Duration::hours(1),
+        );
+        let mut demand = serde_json::to_value(demand).unwrap();
+        demand
+            .as_object_mut()
+            .unwrap()
+            .extend(settings.as_object().unwrap().clone());
+        demand.to_string()
+    };
+    let req = actix_web::test::TestRequest::default().to_http_request();
+
+    for settings in [
+        serde_json::json!({ "queueDepth": 0 }),
+        serde_json::json!({ "maxOffers": 0 }),
+        serde_json::json!({ "constraints": "(golem.inf.mem.gib>=" }),
+        serde_json::json!({ "properties": "{\"golem.srv" }),
+    ] {
+        let resp = demand_new(data.clone(), req.clone(), demand(settings)).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+    }
+    assert!(data.demands.snapshot().await.unwrap().is_empty());
+
+    let settings = serde_json::json!({ "queueDepth": 5, "maxOffers": 50 });
+    let resp = demand_new(data.clone(), req, demand(settings)).await;
+    assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+    let snapshot = data.demands.snapshot().await.unwrap();
+    let stored = snapshot.iter().next().unwrap();
+    assert_eq!(stored.demand.queue_depth, Some(5));
+    assert_eq!(stored.demand.max_offers, Some(50));
+}
+
+#[actix_web::test]
+async fn test_replaced_demand_waiters_released() {
+    use crate::test_util::{app_state, demand};
+    use std::sync::Arc;
+
+    let data = app_state();
+    let req = actix_web::test::TestRequest::default().to_http_request();
+    let expiration = chrono::Utc::now() + chrono::Duration::hours(1);
+    let old = serde_json::to_string(&demand("old", 0x11, expiration)).unwrap();
+    let resp = demand_new(data.clone(), req.clone(), old).await;
+    assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+    let waiter = data.demand_notifier.get("old");
 
-    HttpResponse::Ok().json(demand)
+    let new = serde_json::to_string(&demand("new", 0x11, expiration)).unwrap();
+    let resp = demand_new(data.clone(), req, new).await;
+    assert_eq!(resp.status(), actix_web::http::StatusCode::OK);
+    assert!(!Arc::ptr_eq(&waiter, &data.demand_notifier.get("old")));
+    let snapshot = data.demands.snapshot().await.unwrap();
+    assert_eq!(snapshot.len(), 1);
+    assert!(snapshot.get("new").is_some());
 }
@@ -1,8 +1,10 @@
-use crate::state::{AppState, DemandObj};
+use crate::rest::storage_error;
+use crate::state::AppState;
 use actix_web::{web, HttpResponse};
 
 pub async fn list_demands(data: web::Data<AppState>) -> HttpResponse {
-    let lock = data.demands.lock().await;
-    let demands: Vec<&DemandObj> = lock.demand_map.values().collect();
-    HttpResponse::Ok().json(demands)
+    match data.demands.snapshot().await {
+        Ok(snapshot) => HttpResponse::Ok().json(snapshot.filter(|_| true)),
+        Err(e) => storage_error(e),
+    }
 }
@@ -3,119 +3,224 @@ pub mod cancel_demand;
 pub mod demand_new;
 pub mod list_demands;
 pub mod pick_offer_to_demand;
+pub mod stream_offers;
 pub mod take_offer_from_queue;
 
+use crate::config::PickerConfig;
 use crate::rest::demand::pick_offer_to_demand::{local_pick_offer_to_demand, PickOfferToDemand};
-use crate::state::{AppState, DemandObj};
-use actix_web::web;
+use crate::rest::storage_error;
+use crate::scheduler::FairShare;
+use crate::state::{AppState, DemandObj, Demands, Offers};
+use actix_web::{web, HttpResponse};
+use chrono::Utc;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::env;
-use std::sync::atomic::{AtomicI32, AtomicI64};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI32, AtomicI64, Ordering};
+use ya_client_model::NodeId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct TakeOfferFromQueue {
     pub demand_id: String,
     pub limit_size: Option<usize>,
+    /// Hold the request until offers are queued for the demand, at most this long
+    pub wait_ms: Option<u64>,
 }
 
-static NO_PICKED_OFFERS: AtomicI32 = AtomicI32::new(0);
-static LAST_LOG_TIME: AtomicI64 = AtomicI64::new(0);
-static LAST_CENTRAL_NET: AtomicI64 = AtomicI64::new(0);
-
-pub async fn pick_offers_for_all_demands(data: web::Data<AppState>) {
-    let demands: Vec<DemandObj> = {
-        let lock = data.demands.lock().await;
-        lock.demand_map.values().cloned().collect()
+/// Finds demand by its id, or by node id of the requestor if there is no demand with such id
+pub async fn find_demand(demands: &Demands, demand_id: &str) -> Result<DemandObj, HttpResponse> {
+    match demands.get(demand_id).await {
+        Ok(Some(demand_obj)) => return Ok(demand_obj),
+        Ok(None) => {}
+        Err(e) => return Err(storage_error(e)),
+    }
+    let node_id = match NodeId::from_str(demand_id) {
+        Ok(id) => id,
+        Err(_) => {
+            return Err(HttpResponse::BadRequest().body("Invalid offer ID format or not found"));
+        }
     };
+    match demands.find_by_node(node_id).await {
+        Ok(Some(demand_obj)) => Ok(demand_obj),
+        Ok(None) => Err(HttpResponse::NotFound().body("Demand not found")),
+        Err(e) => Err(storage_error(e)),
+    }
+}
 
-    let mut central_nets = HashMap::new();
-    {
-        let _lock = data.offers_given_to_node.lock().await;
-        for demand in demands.iter() {
-            if let Some(net_address) = &demand.demand.central_net_address {
-                central_nets.insert(net_address.clone(), true);
-            }
+/// Returns offers queued for the demand but not yet delivered back to the pool,
+/// taking them back from fair-share usage of the requestor
+pub async fn release_queued_offers(
+    offers: &mut Offers,
+    demand_obj: &DemandObj,
+    fair_share: &FairShare,
+    config: &PickerConfig,
+) -> anyhow::Result<usize> {
+    let node_id = demand_obj.demand.node_id;
+    let now = Utc::now();
+    let mut released = 0;
+    for offer_id in demand_obj.offer_list.iter() {
+        let Some(mut offer_obj) = offers.get(offer_id).await? else {
+            continue;
+        };
+        if offer_obj.requestor_id != Some(node_id) {
+            continue;
         }
+        fair_share.released(demand_obj, &offer_obj, now, config);
+        offer_obj.release();
+        offers.insert(offer_obj).await?;
+        released += 1;
     }
+    Ok(released)
+}
 
-    let mut central_nets: Vec<String> = central_nets.keys().cloned().collect();
-    central_nets.sort();
+static NO_PICKED_OFFERS: AtomicI32 = AtomicI32::new(0);
+static LAST_LOG_TIME: AtomicI64 = AtomicI64::new(0);
 
-    let current_net = LAST_CENTRAL_NET.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-    let net_selected = if central_nets.is_empty() {
+/// Tops up queues of demands in central nets to their target depth, up to the budget
+/// of the tick, in order chosen by the fair-share scheduler, see [`crate::scheduler::FairShare`]
+pub async fn pick_offers_for_all_demands(data: web::Data<AppState>) {
+    let config = data.config.get();
+    let snapshot = match data.demands.snapshot().await {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            log::warn!("Failed to read demands for picking offers: {}", e);
+            data.metrics.record_pick_tick("error", 0);
+            return;
+        }
+    };
+    if !snapshot
+        .iter()
+        .any(|demand_obj| demand_obj.demand.central_net_address.is_some())
+    {
         log::info!("No central nets found for picking offers");
+        data.metrics.record_pick_tick("idle", 0);
         return;
-    } else {
-        let index = (current_net as usize) % central_nets.len();
-        let net_id = &central_nets[index];
-        log::info!("Picking offers for central net id: {}", net_id);
-        net_id.clone()
-    };
+    }
 
-    let mut sort_by_given = Vec::new();
-    {
-        let lock = data.offers_given_to_node.lock().await;
-        for demand in demands.iter() {
-            if demand
-                .demand
-                .central_net_address
-                .as_ref()
-                .map(|cn| cn.as_str() != net_selected)
-                .unwrap_or(true)
-            {
-                continue;
+    let mut exhausted = HashSet::new();
+    let mut picked = 0;
+    let mut failed = false;
+    while picked < config.picker.budget_per_tick {
+        let now = Utc::now();
+        // every pick publishes a new snapshot, with the queue of the demand one offer longer
+        let snapshot = match data.demands.snapshot().await {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                log::warn!("Failed to read demands for picking offers: {}", e);
+                failed = true;
+                break;
+            }
+        };
+        let demands: Vec<&DemandObj> = snapshot.iter().collect();
+        let Some(pick) = data
+            .fair_share
+            .next_demand(&demands, &exhausted, &config.picker, now)
+        else {
+            break;
+        };
+        log::debug!(
+            "Picking offer for demand {} of node {} in central net {}",
+            pick.demand_id,
+            pick.node_id,
+            pick.central_net
+        );
+        let pick_offer = PickOfferToDemand {
+            demand_id: pick.demand_id.clone(),
+        };
+        match local_pick_offer_to_demand(data.clone(), pick_offer, Some(pick.central_net.clone()))
+            .await
+        {
+            Ok(true) => picked += 1,
+            Ok(false) => {
+                log::debug!(
+                    "No available offers found to pick for demand {}",
+                    pick.demand_id
+                );
+                exhausted.insert(pick.demand_id);
             }
-            if let Some(count) = lock.get(&demand.demand.node_id.to_string()) {
-                sort_by_given.push((demand.demand.id.clone(), *count));
-            } else {
-                sort_by_given.push((demand.demand.id.clone(), 0));
+            Err(e) => {
+                log::warn!("Failed to pick offer for demand {}: {}", pick.demand_id, e);
+                failed = true;
+                exhausted.insert(pick.demand_id);
             }
         }
     }
 
-    sort_by_given.sort_by_key(|k| k.1);
+    let total = NO_PICKED_OFFERS.fetch_add(picked as i32, Ordering::SeqCst) + picked as i32;
+    let current_time = Utc::now().timestamp_millis();
+    let last_log_time = LAST_LOG_TIME.load(Ordering::SeqCst);
+    if current_time - last_log_time > (config.picker.log_every_secs * 1000.0) as i64 {
+        log::info!(
+            "Picked {} offers in the last tick, {} offers so far",
+            picked,
+            total
+        );
+        LAST_LOG_TIME.store(current_time, Ordering::SeqCst);
+    }
+    let result = match (picked, failed) {
+        (0, true) => "error",
+        (0, false) => "empty",
+        _ => "picked",
+    };
+    data.metrics.record_pick_tick(result, picked as u64);
+}
 
-    let log_every_sec: f64 = env::var("LOG_EVERY_SEC")
-        .unwrap_or_else(|_| "10".to_string())
-        .parse()
-        .unwrap_or(10.0);
+/// Fair-share state of requestors having demands in central nets
+pub async fn list_requestor_shares(data: web::Data<AppState>) -> HttpResponse {
+    let config = data.config.get();
+    let snapshot = match data.demands.snapshot().await {
+        Ok(snapshot) => snapshot,
+        Err(e) => return storage_error(e),
+    };
+    let demands: Vec<&DemandObj> = snapshot.iter().collect();
+    HttpResponse::Ok().json(data.fair_share.shares(&demands, &config.picker, Utc::now()))
+}
 
-    let no_picked_offers = &NO_PICKED_OFFERS;
-    if let Some(pair) = sort_by_given.first() {
-        let pick_offer = PickOfferToDemand {
-            demand_id: pair.0.clone(),
-        };
-        log::debug!(
-            "Picking offer for node {}, that already received: {} offers",
-            pair.0,
-            pair.1
-        );
-        let last_log_time = LAST_LOG_TIME.load(std::sync::atomic::Ordering::SeqCst);
-        let val = no_picked_offers.load(std::sync::atomic::Ordering::SeqCst);
+#[tokio::test]
+async fn test_release_queued_offers() {
+    use crate::test_util::{demand, offer};
+    use std::collections::VecDeque;
 
-        let current_time = chrono::Utc::now().timestamp_millis();
-        if current_time - last_log_time > (log_every_sec * 1000.0) as i64 {
-            log::info!(
-                "Picked offers for {} demands so far, currently at node {} that received {} offers",
-                val,
-                pair.0,
-                pair.1
-            );
-            LAST_LOG_TIME.store(current_time, std::sync::atomic::Ordering::SeqCst);
-        }
-        match local_pick_offer_to_demand(data.clone(), pick_offer, Some(net_selected)).await {
-            Ok(found) => {
-                if !found {
-                    log::debug!("No available offers found to pick for demand {}", pair.0);
-                } else {
-                    no_picked_offers.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                }
-            }
-            Err(e) => {
-                log::warn!("Failed to pick offer for demand: {}", e);
-            }
+    let mut demand = demand("demand", 0x11, Utc::now() + chrono::Duration::hours(1));
+    demand.central_net_address = Some("net-a".to_string());
+    let node_id = demand.node_id;
+    let other_id = NodeId::from([2; 20]);
+
+    let mut offers = Offers::default();
+    let mut offer_list = VecDeque::new();
+    for (idx, requestor_id) in [Some(node_id), Some(node_id), Some(other_id), None]
+        .into_iter()
+        .enumerate()
+    {
+        let mut offer_obj = offer(&format!("offer-{}", idx), 1);
+        if let Some(requestor_id) = requestor_id {
+            offer_obj.assign(requestor_id);
         }
+        offer_list.push_back(offer_obj.offer.id.clone());
+        offers.insert(offer_obj).await.unwrap();
+    }
+    offer_list.push_back("missing".to_string());
+    let demand_obj = DemandObj {
+        demand,
+        offer_list,
+        allocated: 5,
+    };
+
+    let fair_share = FairShare::default();
+    let config = PickerConfig::default();
+    for _ in 0..3 {
+        fair_share.assigned(&demand_obj, Utc::now(), &config);
     }
+    let released = release_queued_offers(&mut offers, &demand_obj, &fair_share, &config)
+        .await
+        .unwrap();
+
+    assert_eq!(released, 2);
+    // only the offer delivered before still counts in the fair share
+    let shares = fair_share.shares(&[&demand_obj], &config, Utc::now());
+    assert!((shares[0].usage - 1.0).abs() < 1e-3, "{:?}", shares);
+    assert_eq!(offers.filter_available(|_| true).await.unwrap().len(), 3);
+    let other = offers.get("offer-2").await.unwrap().unwrap();
+    assert_eq!(other.requestor_id, Some(other_id));
 }
@@ -1,11 +1,16 @@
-use crate::state::{AppState, DemandObj};
-use actix_web::{web, HttpResponse};
+use crate::config::PickerConfig;
+use crate::constraints::DemandMatcher;
+use crate::index::OfferQuery;
+use crate::rest::demand::find_demand;
+use crate::rest::storage_error;
+use crate::selection::{select_offer, SelectionContext};
+use crate::state::{AppState, DemandObj, OfferObj, Offers};
+use actix_web::{web, HttpRequest, HttpResponse};
 use anyhow::bail;
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::env;
-use std::ops::Sub;
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Instant;
 use ya_client_model::NodeId;
 
@@ -15,7 +20,11 @@ pub struct PickOfferToDemand {
     pub demand_id: String,
 }
 
-pub async fn pick_offer_to_demand(data: web::Data<AppState>, body: String) -> HttpResponse {
+pub async fn pick_offer_to_demand(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: String,
+) -> HttpResponse {
     let decoded = serde_json::from_str::<PickOfferToDemand>(&body);
 
     let add_offer = match decoded {
@@ -27,75 +36,133 @@ pub async fn pick_offer_to_demand(data: web::Data<AppState>, body: String) -> Ht
     };
     let demand_id = add_offer.demand_id;
 
+    // signature is recovered without holding the locks, it would stall all demand traffic
+    let node_id = match find_demand(&*data.demands.lock().await, &demand_id).await {
+        Ok(demand_obj) => demand_obj.demand.node_id,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = data.auth.verify(&req, &body, node_id) {
+        return resp;
+    }
+
     let mut lock = data.demands.lock().await;
     let mut offers_lock = data.lock.lock().await;
 
-    let get_demand = match lock.demand_map.contains_key(&demand_id) {
-        true => lock.demand_map.get_mut(&demand_id),
-        false => {
-            let node_id = match NodeId::from_str(&demand_id) {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid offer ID format or not found");
-                }
-            };
-            let mut get_demand: Option<&mut DemandObj> = None;
-            for (_, v) in lock.demand_map.iter_mut() {
-                if v.demand.node_id == node_id {
-                    get_demand = Some(v);
-                    break;
-                }
-            }
-            get_demand
-        }
+    let mut demand_obj = match find_demand(&lock, &demand_id).await {
+        Ok(demand) => demand,
+        Err(resp) => return resp,
     };
+    let config = data.config.get();
+    if !demand_obj.wants_offers(config.picker.queue_depth) {
+        return HttpResponse::Conflict()
+            .body("Demand queue is full or the demand reached its maximum number of offers");
+    }
 
-    let demand_obj = match get_demand {
-        Some(demand) => demand,
-        None => {
-            return HttpResponse::NotFound().body("Demand not found");
-        }
+    let matcher = match DemandMatcher::new(&demand_obj.demand) {
+        Ok(matcher) => matcher,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
     };
 
-    let mut name_filter = "";
-    let mut selected_offer_id = None;
-
-    //@todo, this works only in TESTNET !!, fix until go to prod
-    if let Some(addr) = demand_obj.demand.central_net_address.as_ref() {
-        name_filter = addr.split(".").next().unwrap_or("N/A");
-    }
+    let now = Utc::now();
+    let central_net_filter = demand_obj.demand.central_net_address.clone();
+    let selected_offer = select_offer_for_demand(
+        &offers_lock,
+        &demand_obj,
+        &matcher,
+        &config.picker,
+        central_net_filter.as_deref(),
+        now,
+    )
+    .await;
 
-    for offer_pair in offers_lock.offer_map.iter_mut() {
-        let offer = offer_pair.1;
-
-        if !offer
-            .offer
-            .properties
-            .golem
-            .node
-            .id
-            .name
-            .contains(name_filter)
-        {
-            continue;
+    let mut offer = match selected_offer {
+        Ok(Some(offer)) => offer,
+        Ok(None) => {
+            return HttpResponse::NotFound().body("No available offers found");
         }
+        Err(e) => return storage_error(e),
+    };
 
-        if offer.requestor_id.is_none() {
-            selected_offer_id = Some(offer);
-            break;
-        }
+    offer.assign(demand_obj.demand.node_id);
+    demand_obj.push_offer(offer.offer.id.clone());
+    if let Err(e) = offers_lock.insert(offer).await {
+        return storage_error(e);
+    }
+    data.fair_share.assigned(&demand_obj, now, &config.picker);
+    let demand_id = demand_obj.demand.id.clone();
+    if let Err(e) = lock.insert(demand_obj).await {
+        return storage_error(e);
     }
+    data.demand_notifier.notify(&demand_id);
+    HttpResponse::Ok().body("Offer added to demand successfully")
+}
 
-    let offer = match selected_offer_id {
-        Some(offer) => offer,
-        None => {
-            return HttpResponse::NotFound().body("No available offers found");
+/// Available unexpired offer matching the demand, chosen by the order of the demand
+/// or the configured one. Offers are limited to nodes named `...<group>-...` if the
+/// group is configured, otherwise to node groups named in `central_net_filter`,
+/// no limit for local central nets.
+async fn select_offer_for_demand(
+    offers: &Offers,
+    demand_obj: &DemandObj,
+    matcher: &DemandMatcher,
+    picker: &PickerConfig,
+    central_net_filter: Option<&str>,
+    now: DateTime<Utc>,
+) -> anyhow::Result<Option<OfferObj>> {
+    //used in integration tests, group can be anywhere in the node name,
+    //so it is checked per offer instead of through the node group index
+    let group_prefix = picker
+        .offer_group
+        .as_ref()
+        .map(|group| format!("{}-", group));
+    let node_groups = if group_prefix.is_some() {
+        None
+    } else if let Some(central_net_filter) = central_net_filter {
+        match central_net_filter.contains("127.0.0.1") {
+            true => None,
+            false => Some(
+                offers
+                    .node_groups()
+                    .await?
+                    .into_iter()
+                    .filter(|name_group| central_net_filter.contains(name_group.as_str()))
+                    .collect(),
+            ),
         }
+    } else {
+        None
+    };
+    let query = OfferQuery {
+        node_groups,
+        available: true,
+        not_expired_at: Some(now),
+        ..Default::default()
     };
+    let candidates = offers
+        .query(&query, |offer| {
+            if let Some(group_prefix) = group_prefix.as_ref() {
+                if !offer.attributes.node_name.contains(group_prefix.as_str()) {
+                    return false;
+                }
+            }
+            if let Some(max_prices) = demand_obj.demand.max_prices.as_ref() {
+                if !offer.within_max_prices(max_prices) {
+                    return false;
+                }
+            }
+            matcher.matches(offer.matching())
+        })
+        .await?;
 
-    offer.requestor_id = Some(demand_obj.demand.node_id);
-    demand_obj.offer_list.push_back(offer.offer.id.clone());
-    HttpResponse::Ok().body("Offer added to demand successfully")
+    let order = demand_obj.demand.offer_order.unwrap_or(picker.order);
+    let ctx = SelectionContext {
+        now,
+        last_assigned: offers.last_assigned(),
+        weights: picker.weights,
+    };
+    let candidates: Vec<&OfferObj> = candidates.iter().map(Arc::as_ref).collect();
+    // only the winner is cloned, candidates are shared with the store
+    Ok(select_offer(&candidates, order, &ctx).cloned())
 }
 
 pub async fn local_pick_offer_to_demand(
@@ -109,101 +176,60 @@ pub async fn local_pick_offer_to_demand(
 
         let mut lock = data.demands.lock().await;
         let mut offers_lock = data.lock.lock().await;
-        let mut given_lock = data.offers_given_to_node.lock().await;
 
-        let get_demand = match lock.demand_map.contains_key(&demand_id) {
-            true => lock.demand_map.get_mut(&demand_id),
-            false => {
+        let get_demand = match lock.get(&demand_id).await? {
+            Some(demand_obj) => Some(demand_obj),
+            None => {
                 let node_id = match NodeId::from_str(&demand_id) {
                     Ok(id) => id,
                     Err(_) => {
                         bail!("Invalid offer ID format or not found");
                     }
                 };
-                let mut get_demand: Option<&mut DemandObj> = None;
-                for (_, v) in lock.demand_map.iter_mut() {
-                    if v.demand.node_id == node_id {
-                        get_demand = Some(v);
-                        break;
-                    }
-                }
-                get_demand
+                lock.find_by_node(node_id).await?
             }
         };
 
-        let demand_obj = match get_demand {
+        let mut demand_obj = match get_demand {
             Some(demand) => demand,
             None => {
                 bail!("Demand not found");
             }
         };
-        // most recent, unexpired, and unassigned offer from the collection.
-        // The use of newest_one as a baseline timestamp ensures that only the most recent valid
-        // offer is chosen during the iteration.
-        let mut selected_offer_id = None;
-        let mut newest_one = Utc::now().sub(chrono::Duration::days(365 * 100));
-        for offer_pair in offers_lock.offer_map.iter_mut() {
-            let offer = offer_pair.1;
-            if offer.offer.expiration.naive_utc() < Utc::now().naive_utc() {
-                // expired
-                continue;
-            }
-            if offer.requestor_id.is_some() {
-                // already assigned
-                continue;
-            }
-            let name_group = offer
-                .attributes
-                .node_name
-                .split("-")
-                .next()
-                .unwrap_or("N/A");
-
 
-            //used in integration tests
-            let group = env::var("OFFER_GROUP").ok();
+        let config = data.config.get();
+        // queue could have been topped up since the scheduler looked at it
+        if !demand_obj.wants_offers(config.picker.queue_depth) {
+            return Ok(false);
+        }
 
-            if let Some(group) = group.as_ref() {
-                if !offer.attributes.node_name.contains((group.to_string() + "-").as_str()) {
-                    continue;
-                }
-            } else {
-                if let Some(central_net_filter) = central_net_filter.as_ref() {
-                    if !central_net_filter.contains("127.0.0.1")
-                        && !central_net_filter.contains(name_group)
-                    {
-                        continue;
-                    }
-                }
-            }
+        let matcher = DemandMatcher::new(&demand_obj.demand)?;
 
-            if offer.offer.timestamp > newest_one && offer.offer.timestamp < Utc::now() {
-                // new good candidate
-                newest_one = offer.offer.timestamp;
-                selected_offer_id = Some(offer);
-            }
-        }
+        let now = Utc::now();
+        let selected_offer = select_offer_for_demand(
+            &offers_lock,
+            &demand_obj,
+            &matcher,
+            &config.picker,
+            central_net_filter.as_deref(),
+            now,
+        )
+        .await?;
 
-        let offer = match selected_offer_id {
+        let mut offer = match selected_offer {
             Some(offer) => offer,
             None => {
                 return Ok(false);
             }
         };
 
-        offer.requestor_id = Some(demand_obj.demand.node_id);
-        demand_obj.offer_list.push_back(offer.offer.id.clone());
-        let val = given_lock
-            .get(&demand_obj.demand.node_id.to_string())
-            .cloned();
-        match val {
-            Some(count) => {
-                given_lock.insert(demand_obj.demand.node_id.to_string(), count + 1);
-            }
-            None => {
-                given_lock.insert(demand_obj.demand.node_id.to_string(), 1);
-            }
-        }
+        offer.assign(demand_obj.demand.node_id);
+        demand_obj.push_offer(offer.offer.id.clone());
+        offers_lock.insert(offer).await?;
+        data.fair_share.assigned(&demand_obj, now, &config.picker);
+        let demand_id = demand_obj.demand.id.clone();
+        lock.insert(demand_obj).await?;
+        data.demand_notifier.notify(&demand_id);
     }
     if perf_start.elapsed().as_secs_f64() > 0.01 {
         log::warn!(
@@ -218,3 +244,171 @@ pub async fn local_pick_offer_to_demand(
     }
     Ok(true)
 }
+
+/// Picks of a demand matching every offer, in memory and in sqlite,
+/// run with `cargo test --release -- --ignored bench_pick_offer_to_demand --nocapture`
+#[tokio::test]
+#[ignore]
+async fn bench_pick_offer_to_demand() {
+    use crate::state::{DemandObj, Demands, Offers};
+    use crate::store::Store;
+    use crate::test_util::{app_state_with, demand, fill_market, remove_sqlite, sqlite_storage};
+    use std::time::Duration;
+
+    const OFFER_COUNT: usize = 100_000;
+    const PICKS: u32 = 100;
+
+    let now = Utc::now();
+    let mut demand = demand("demand", 0x11, now + chrono::Duration::hours(1));
+    demand.properties =
+        r#"{"golem.srv.comp.expiration": 1865401640654, "golem.node.debug.subnet": "public"}"#
+            .to_string();
+    demand.constraints = "(&(golem.inf.mem.gib>=8)(golem.runtime.name=ya-runtime-*))".to_string();
+    let (storage, db_file) = sqlite_storage("bench").await;
+    for (name, mut offers, demands) in [
+        ("memory", Offers::default(), Demands::default()),
+        (
+            "sqlite",
+            Offers::new_sqlite(storage.clone()).await.unwrap(),
+            Demands::new_sqlite(storage),
+        ),
+    ] {
+        fill_market(&mut offers, OFFER_COUNT, now).await;
+        let data = app_state_with(Store::new(offers), Store::new(demands));
+        data.demands
+            .lock()
+            .await
+            .insert(DemandObj::new(demand.clone()))
+            .await
+            .unwrap();
+
+        let mut picks = Duration::ZERO;
+        for _ in 0..PICKS {
+            let pick = PickOfferToDemand {
+                demand_id: "demand".to_string(),
+            };
+            let start = Instant::now();
+            assert!(local_pick_offer_to_demand(data.clone(), pick, None)
+                .await
+                .unwrap());
+            picks += start.elapsed();
+        }
+        println!(
+            "{}: {} offers, {:?} per pick",
+            name,
+            OFFER_COUNT,
+            picks / PICKS
+        );
+    }
+    remove_sqlite(&db_file);
+}
+
+#[actix_web::test]
+async fn test_pick_offer_to_demand_newest_unexpired() {
+    use crate::test_util::{app_state, demand, offer};
+    use actix_web::http::StatusCode;
+
+    let data = app_state();
+    let now = Utc::now();
+    let mut demand = demand("demand", 0x11, now + chrono::Duration::hours(1));
+    // example offer constrains the demand
+    demand.properties =
+        r#"{"golem.srv.comp.expiration": 1865401640654, "golem.node.debug.subnet": "public"}"#
+            .to_string();
+    data.demands
+        .lock()
+        .await
+        .insert(DemandObj::new(demand))
+        .await
+        .unwrap();
+    for (id, provider, published, expires) in [
+        ("older", 1, -10, 60),
+        ("newer", 2, -1, 60),
+        ("expired", 3, 0, -1),
+    ] {
+        let mut offer_obj = offer(id, provider);
+        offer_obj.offer.timestamp = now + chrono::Duration::minutes(published);
+        offer_obj.offer.expiration = now + chrono::Duration::minutes(expires);
+        data.lock.lock().await.insert(offer_obj).await.unwrap();
+    }
+
+    let pick = || async {
+        let request = actix_web::test::TestRequest::post().to_http_request();
+        let body = r#"{"demandId": "demand"}"#.to_string();
+        pick_offer_to_demand(data.clone(), request, body)
+            .await
+            .status()
+    };
+    assert_eq!(pick().await, StatusCode::OK);
+    assert_eq!(pick().await, StatusCode::OK);
+    assert_eq!(pick().await, StatusCode::NOT_FOUND);
+
+    let demand_obj = data
+        .demands
+        .lock()
+        .await
+        .get("demand")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(demand_obj.offer_list, ["newer", "older"]);
+    let expired = data
+        .lock
+        .lock()
+        .await
+        .get("expired")
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(expired.requestor_id.is_none());
+}
+
+#[tokio::test]
+async fn test_offer_group_matches_anywhere_in_node_name() {
+    use crate::test_util::{app_state, demand, node_offer};
+
+    let mut data = AppState::clone(&app_state());
+    let mut config = data.config.get().as_ref().clone();
+    config.picker.offer_group = Some("brick".to_string());
+    data.config = Arc::new(crate::config::SharedConfig::new(config, None));
+    let data = web::Data::new(data);
+    let mut demand = demand("demand", 0x11, Utc::now() + chrono::Duration::hours(1));
+    demand.properties =
+        r#"{"golem.srv.comp.expiration": 1865401640654, "golem.node.debug.subnet": "public"}"#
+            .to_string();
+    data.demands
+        .lock()
+        .await
+        .insert(DemandObj::new(demand))
+        .await
+        .unwrap();
+    for (id, provider, node_name) in [
+        ("first", 1, "brick-1"),
+        ("second", 2, "lab-brick-2"),
+        ("bricks", 3, "bricks-3"),
+        ("other", 4, "other-4"),
+    ] {
+        let offer_obj = node_offer(id, provider, node_name);
+        data.lock.lock().await.insert(offer_obj).await.unwrap();
+    }
+
+    for _ in 0..3 {
+        let pick = PickOfferToDemand {
+            demand_id: "demand".to_string(),
+        };
+        local_pick_offer_to_demand(data.clone(), pick, Some("other.net".to_string()))
+            .await
+            .unwrap();
+    }
+    let demand_obj = data
+        .demands
+        .lock()
+        .await
+        .get("demand")
+        .await
+        .unwrap()
+        .unwrap();
+    let mut picked: Vec<&str> = demand_obj.offer_list.iter().map(String::as_str).collect();
+    picked.sort();
+    assert_eq!(picked, ["first", "second"]);
+}
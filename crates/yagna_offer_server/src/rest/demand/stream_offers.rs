@@ -0,0 +1,125 @@
+use crate::rest::demand::find_demand;
+use crate::rest::demand::take_offer_from_queue::take_offers;
+use crate::state::AppState;
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Comment line is sent when nothing was queued for that long, keeps proxies from closing the connection
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+struct StreamState {
+    data: web::Data<AppState>,
+    demand_id: String,
+    notify: Arc<Notify>,
+}
+
+/// Server-sent events stream of offers queued for the demand (by demand id or node id).
+/// Every offer is sent as `offer` event with the same json as take-from-queue returns.
+/// Offers are taken from the queue one by one, when the client is ready to receive next one,
+/// so the offer is marked delivered only after the previous event was written out.
+/// The stream ends when the demand is cancelled or expires.
+pub async fn stream_offers(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> HttpResponse {
+    // signature is recovered without holding the lock, it would stall all demand traffic
+    let demand_obj = match find_demand(&*data.demands.lock().await, &path).await {
+        Ok(demand_obj) => demand_obj,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = data.auth.verify(&req, "", demand_obj.demand.node_id) {
+        return resp;
+    }
+    let demand_id = demand_obj.demand.id;
+    log::info!("Requestor subscribed to offers of demand {}", demand_id);
+    let state = StreamState {
+        notify: data.demand_notifier.get(&demand_id),
+        data,
+        demand_id,
+    };
+
+    let stream = futures_util::stream::unfold(state, |state| async move {
+        let notify = state.notify.clone();
+        loop {
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            match take_offers(&state.data, &state.demand_id, 1).await {
+                Ok((_, offers)) => {
+                    if let Some(offer) = offers.into_iter().next() {
+                        let event = format!(
+                            "event: offer\ndata: {}\n\n",
+                            serde_json::to_string(&offer).unwrap_or_default()
+                        );
+                        return Some((Ok::<_, actix_web::Error>(Bytes::from(event)), state));
+                    }
+                }
+                Err(_) => {
+                    log::info!("Demand {} is gone, closing offer stream", state.demand_id);
+                    return None;
+                }
+            }
+            if tokio::time::timeout(KEEP_ALIVE, notified).await.is_err() {
+                return Some((Ok(Bytes::from_static(b": keep-alive\n\n")), state));
+            }
+        }
+    });
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream)
+}
+
+#[tokio::test]
+async fn test_stream_offers_until_demand_cancelled() {
+    use crate::state::DemandObj;
+    use crate::test_util::{app_state, demand, offer};
+
+    let data = app_state();
+    let demand = demand(
+        "demand",
+        0x11,
+        chrono::Utc::now() + chrono::Duration::hours(1),
+    );
+    data.demands
+        .lock()
+        .await
+        .insert(DemandObj::new(demand))
+        .await
+        .unwrap();
+
+    let resp = stream_offers(
+        data.clone(),
+        actix_web::test::TestRequest::default().to_http_request(),
+        web::Path::from("0x1111111111111111111111111111111111111111".to_string()),
+    )
+    .await;
+
+    let pusher = data.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        {
+            let mut lock = pusher.demands.lock().await;
+            let mut offers_lock = pusher.lock.lock().await;
+            let mut demand_obj = lock.get("demand").await.unwrap().unwrap();
+            demand_obj.push_offer("offer".to_string());
+            offers_lock.insert(offer("offer", 0xa3)).await.unwrap();
+            lock.insert(demand_obj).await.unwrap();
+            pusher.demand_notifier.notify("demand");
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        pusher.demands.lock().await.remove("demand").await.unwrap();
+        pusher.demand_notifier.remove("demand");
+    });
+
+    let bytes = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+    let body = String::from_utf8(bytes.to_vec()).unwrap();
+    assert_eq!(body.matches("event: offer\n").count(), 1);
+    assert!(body.contains("\"node_id\":\"0xa3a3a3a3a3a3a3a3a3a3a3a3a3a3a3a3a3a3a3a3\""));
+}
@@ -1,10 +1,11 @@
-use crate::rest::demand::TakeOfferFromQueue;
-use crate::state::{AppState, DemandObj};
-use actix_web::{web, HttpResponse};
+use crate::model::flatten::flatten;
+use crate::rest::demand::{find_demand, TakeOfferFromQueue};
+use crate::rest::storage_error;
+use crate::state::AppState;
+use actix_web::{web, HttpRequest, HttpResponse};
 use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
-use serde_json::{Map, Value};
-use std::str::FromStr;
+use std::time::Duration;
 use ya_client_model::NodeId;
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -25,94 +26,35 @@ pub struct ModelOffer {
     /// Time when Offer expires; set by Provider.
     pub expiration_ts: NaiveDateTime,
 }
-pub fn flatten(value: Value) -> Map<String, Value> {
-    let mut map = Map::new();
-    flatten_inner(String::new(), &mut map, value);
-    map
-}
-pub const PROPERTY_TAG: &str = "@tag";
 
-fn flatten_inner(prefix: String, result: &mut Map<String, Value>, value: Value) {
-    match value {
-        Value::Object(m) => {
-            if m.is_empty() {
-                // Important to keep this value in case we want to un-flatten later
-                // and get the same structure.
-                result.insert(prefix, Value::Object(Map::new()));
-            } else {
-                for (k, v) in m.into_iter() {
-                    if k.as_str() == PROPERTY_TAG {
-                        result.insert(prefix.clone(), v);
-                        continue;
-                    }
-                    let p = match prefix.is_empty() {
-                        true => k,
-                        _ => format!("{}.{}", prefix, k),
-                    };
-                    flatten_inner(p, result, v);
-                }
-            }
-        }
-        v => {
-            result.insert(prefix, v);
-        }
-    }
-}
-
-pub async fn take_offer_from_queue(data: web::Data<AppState>, body: String) -> HttpResponse {
-    let decoded = serde_json::from_str::<TakeOfferFromQueue>(&body);
-    let take_offer = match decoded {
-        Ok(filer) => filer,
-        Err(e) => {
-            log::error!("Error decoding take offer from queue: {}", e);
-            return HttpResponse::BadRequest().body(format!("Invalid format {}", e));
-        }
-    };
-    let demand_id = take_offer.demand_id;
+/// Upper limit of waitMs, long polling requests are not held longer than that
+const MAX_WAIT_MS: u64 = 120_000;
 
+/// Pops up to limit_size offers from the demand queue, returns id of the demand and the offers
+pub async fn take_offers(
+    data: &AppState,
+    demand_id: &str,
+    limit_size: usize,
+) -> Result<(String, Vec<ModelOffer>), HttpResponse> {
     let mut lock = data.demands.lock().await;
     let offers_lock = data.lock.lock().await;
 
-    let get_demand = match lock.demand_map.contains_key(&demand_id) {
-        true => lock.demand_map.get_mut(&demand_id),
-        false => {
-            let node_id = match NodeId::from_str(&demand_id) {
-                Ok(id) => id,
-                Err(_) => {
-                    return HttpResponse::BadRequest().body("Invalid offer ID format or not found");
-                }
-            };
-            let mut get_demand: Option<&mut DemandObj> = None;
-            for (_, v) in lock.demand_map.iter_mut() {
-                if v.demand.node_id == node_id {
-                    get_demand = Some(v);
-                    break;
-                }
-            }
-            get_demand
-        }
-    };
-    let demand_obj = match get_demand {
-        Some(demand) => demand,
-        None => {
-            return HttpResponse::NotFound().body("Demand not found");
-        }
-    };
+    let mut demand_obj = find_demand(&lock, demand_id).await?;
     let mut resp = Vec::new();
-    let limit_size = take_offer.limit_size.unwrap_or(50);
     loop {
         if resp.len() >= limit_size {
             break;
         }
         match demand_obj.offer_list.pop_front() {
             Some(offer_id) => {
-                let offer = offers_lock.offer_map.get(&offer_id);
+                let offer = offers_lock.get(&offer_id).await.map_err(storage_error)?;
                 match offer {
                     Some(offer) => {
                         let converted_offer = ModelOffer {
                             id: offer.offer.id.clone(),
+                            // original JSON, with properties unknown to the matcher
                             properties: serde_json::to_string(&flatten(
-                                serde_json::to_value(offer.offer.properties.clone()).unwrap(),
+                                offer.offer.properties.raw().clone(),
                             ))
                             .unwrap(),
                             constraints: offer.offer.constraints.clone(),
@@ -131,5 +73,123 @@ pub async fn take_offer_from_queue(data: web::Data<AppState>, body: String) -> H
             None => break,
         }
     }
-    HttpResponse::Ok().json(resp)
+    let demand_id = demand_obj.demand.id.clone();
+    lock.insert(demand_obj).await.map_err(storage_error)?;
+    Ok((demand_id, resp))
+}
+
+pub async fn take_offer_from_queue(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: String,
+) -> HttpResponse {
+    let decoded = serde_json::from_str::<TakeOfferFromQueue>(&body);
+    let take_offer = match decoded {
+        Ok(filer) => filer,
+        Err(e) => {
+            log::error!("Error decoding take offer from queue: {}", e);
+            return HttpResponse::BadRequest().body(format!("Invalid format {}", e));
+        }
+    };
+    // signature is recovered without holding the lock, it would stall all demand traffic
+    let node_id = match find_demand(&*data.demands.lock().await, &take_offer.demand_id).await {
+        Ok(demand_obj) => demand_obj.demand.node_id,
+        Err(resp) => return resp,
+    };
+    if let Err(resp) = data.auth.verify(&req, &body, node_id) {
+        return resp;
+    }
+    let limit_size = take_offer.limit_size.unwrap_or(50);
+    let wait_ms = take_offer.wait_ms.unwrap_or(0).min(MAX_WAIT_MS);
+    let deadline = tokio::time::Instant::now() + Duration::from_millis(wait_ms);
+
+    let (demand_id, resp) = match take_offers(&data, &take_offer.demand_id, limit_size).await {
+        Ok(taken) => taken,
+        Err(resp) => return resp,
+    };
+    if !resp.is_empty() || wait_ms == 0 {
+        return HttpResponse::Ok().json(resp);
+    }
+
+    let notify = data.demand_notifier.get(&demand_id);
+    loop {
+        // register for notification before checking the queue, so that offer
+        // pushed in between is not missed
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        match take_offers(&data, &demand_id, limit_size).await {
+            Ok((_, resp)) if !resp.is_empty() => return HttpResponse::Ok().json(resp),
+            Ok(_) => {}
+            Err(resp) => return resp,
+        }
+        if tokio::time::timeout_at(deadline, notified).await.is_err() {
+            return HttpResponse::Ok().json(Vec::<ModelOffer>::new());
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_take_offer_from_queue_waits_for_offer() {
+    use crate::state::{DemandObj, OfferObj};
+    use crate::test_util::{app_state, demand, golem_offer};
+
+    let data = app_state();
+    let demand = demand(
+        "demand",
+        0x11,
+        chrono::Utc::now() + chrono::Duration::hours(1),
+    );
+    data.demands
+        .lock()
+        .await
+        .insert(DemandObj::new(demand))
+        .await
+        .unwrap();
+
+    let body = r#"{"demandId": "demand", "waitMs": 50}"#.to_string();
+    let resp = take_offer_from_queue(
+        data.clone(),
+        actix_web::test::TestRequest::default().to_http_request(),
+        body,
+    )
+    .await;
+    let bytes = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(&bytes[..], b"[]");
+
+    let pusher = data.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut lock = pusher.demands.lock().await;
+        let mut offers_lock = pusher.lock.lock().await;
+        let mut gbo = golem_offer("offer", 1);
+        gbo.properties
+            .set("golem.inf.gpu.model", "RTX 4090".into())
+            .unwrap();
+        let mut demand_obj = lock.get("demand").await.unwrap().unwrap();
+        demand_obj.push_offer(gbo.id.clone());
+        offers_lock.insert(OfferObj::new(gbo)).await.unwrap();
+        lock.insert(demand_obj).await.unwrap();
+        pusher.demand_notifier.notify("demand");
+    });
+
+    let body = r#"{"demandId": "demand", "waitMs": 5000}"#.to_string();
+    let started = std::time::Instant::now();
+    let resp = take_offer_from_queue(
+        data.clone(),
+        actix_web::test::TestRequest::default().to_http_request(),
+        body,
+    )
+    .await;
+    assert!(started.elapsed() < Duration::from_secs(5));
+    let bytes = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+    let offers: Vec<ModelOffer> = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(offers.len(), 1);
+    // properties unknown to the matcher reach the requestor
+    let properties =
+        serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(&offers[0].properties)
+            .unwrap();
+    assert_eq!(properties["golem.inf.gpu.model"], "RTX 4090");
+    assert_eq!(properties["golem.com.pricing.model"], "linear");
 }
@@ -1,2 +1,10 @@
+pub mod admin;
 pub mod demand;
 pub mod offer;
+
+use actix_web::HttpResponse;
+
+pub fn storage_error(e: anyhow::Error) -> HttpResponse {
+    log::error!("Storage error: {}", e);
+    HttpResponse::InternalServerError().body(format!("Storage error {}", e))
+}
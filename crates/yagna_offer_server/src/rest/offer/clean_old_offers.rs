@@ -1,17 +1,34 @@
+use crate::rest::storage_error;
 use crate::state::AppState;
 use actix_web::{web, HttpResponse};
 use chrono::Utc;
 
 pub async fn clean_old_offers(data: web::Data<AppState>) {
+    let config = data.config.get();
     let mut lock = data.lock.lock().await;
     let now = Utc::now();
-    lock.offer_map.retain(|_id, offer_obj| {
-        offer_obj.offer.expiration > (now - chrono::Duration::minutes(60))
-    });
+    let grace = chrono::Duration::seconds(config.cleanup.offer_expiry_grace_secs);
+    let res = lock.remove_expired(now - grace).await;
+    if let Err(e) = res {
+        log::error!("Failed to clean old offers: {}", e);
+    }
+    drop(lock);
+
+    // removed rows are not visible through the stores, no lock is needed
+    if let Some(storage) = data.lock.storage() {
+        let retention = chrono::Duration::seconds(config.cleanup.history_retention_secs);
+        match storage.prune_history(now - retention).await {
+            Ok(0) => {}
+            Ok(pruned) => log::info!("Pruned {} removed offers and demands", pruned),
+            Err(e) => log::error!("Failed to prune removed offers and demands: {}", e),
+        }
+    }
 }
 
 pub async fn delete_all_offers(data: web::Data<AppState>) -> HttpResponse {
     let mut lock = data.lock.lock().await;
-    lock.offer_map.clear();
-    HttpResponse::Ok().body("All offers deleted successfully")
+    match lock.clear().await {
+        Ok(()) => HttpResponse::Ok().body("All offers deleted successfully"),
+        Err(e) => storage_error(e),
+    }
 }
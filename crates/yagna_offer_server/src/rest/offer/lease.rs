@@ -0,0 +1,199 @@
+use crate::config::PickerConfig;
+use crate::rest::storage_error;
+use crate::scheduler::FairShare;
+use crate::state::{AppState, Demands, OfferObj, Offers};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use ya_client_model::NodeId;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfferLease {
+    pub offer_id: String,
+    pub requestor_id: NodeId,
+}
+
+/// Removes the offer from the queue of requestor demand (if not delivered yet)
+/// and returns it to the pool of available offers. The offer is taken back from
+/// fair-share usage of the demand, if the demand is gone its usage just decays.
+pub async fn release_offer_from_demand(
+    demands: &mut Demands,
+    offers: &mut Offers,
+    mut offer_obj: OfferObj,
+    fair_share: &FairShare,
+    config: &PickerConfig,
+) -> anyhow::Result<()> {
+    if let Some(requestor_id) = offer_obj.requestor_id {
+        if let Some(mut demand_obj) = demands.find_by_node(requestor_id).await? {
+            fair_share.released(&demand_obj, &offer_obj, Utc::now(), config);
+            let queued = demand_obj.offer_list.len();
+            demand_obj
+                .offer_list
+                .retain(|offer_id| offer_id != &offer_obj.offer.id);
+            if demand_obj.offer_list.len() != queued {
+                demands.insert(demand_obj).await?;
+            }
+        }
+    }
+    offer_obj.release();
+    offers.insert(offer_obj).await
+}
+
+pub async fn release_expired_leases(data: web::Data<AppState>, ttl: chrono::Duration) {
+    let config = data.config.get();
+    let mut lock = data.demands.lock().await;
+    let mut offers_lock = data.lock.lock().await;
+    let now = Utc::now();
+
+    let expired = match offers_lock
+        .filter(|offer_obj| offer_obj.lease_expired(now, ttl))
+        .await
+    {
+        Ok(expired) => expired,
+        Err(e) => {
+            log::error!("Failed to load offers for lease check: {}", e);
+            return;
+        }
+    };
+    let mut released = 0;
+    for offer_obj in expired {
+        let offer_id = offer_obj.offer.id.clone();
+        let res = release_offer_from_demand(
+            &mut lock,
+            &mut offers_lock,
+            offer_obj,
+            &data.fair_share,
+            &config.picker,
+        )
+        .await;
+        match res {
+            Ok(()) => released += 1,
+            Err(e) => log::error!("Failed to release offer {}: {}", offer_id, e),
+        }
+    }
+    if released > 0 {
+        log::info!(
+            "Released {} offers not confirmed within lease time",
+            released
+        );
+    }
+}
+
+async fn get_leased_offer(offers: &Offers, lease: &OfferLease) -> Result<OfferObj, HttpResponse> {
+    let offer_obj = match offers.get(&lease.offer_id).await {
+        Ok(Some(offer_obj)) => offer_obj,
+        Ok(None) => return Err(HttpResponse::NotFound().body("Offer not found")),
+        Err(e) => return Err(storage_error(e)),
+    };
+    match offer_obj.requestor_id {
+        Some(requestor_id) if requestor_id == lease.requestor_id => Ok(offer_obj),
+        Some(_) => Err(HttpResponse::Conflict().body("Offer is taken by another requestor")),
+        None => Err(HttpResponse::Conflict().body("Offer is not taken")),
+    }
+}
+
+pub async fn confirm_offer(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: String,
+) -> HttpResponse {
+    let lease = match serde_json::from_str::<OfferLease>(&body) {
+        Ok(lease) => lease,
+        Err(e) => {
+            log::error!("Error decoding offer confirmation: {}", e);
+            return HttpResponse::BadRequest().body(format!("Invalid format {}", e));
+        }
+    };
+    if let Err(resp) = data.auth.verify(&req, &body, lease.requestor_id) {
+        return resp;
+    }
+
+    let mut offers_lock = data.lock.lock().await;
+    let mut offer_obj = match get_leased_offer(&offers_lock, &lease).await {
+        Ok(offer_obj) => offer_obj,
+        Err(resp) => return resp,
+    };
+    if offer_obj.confirmed_at.is_some() {
+        return HttpResponse::Ok().body("Offer already confirmed");
+    }
+    offer_obj.confirmed_at = Some(Utc::now());
+    match offers_lock.insert(offer_obj).await {
+        Ok(()) => HttpResponse::Ok().body("Offer confirmed"),
+        Err(e) => storage_error(e),
+    }
+}
+
+pub async fn release_offer(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    body: String,
+) -> HttpResponse {
+    let lease = match serde_json::from_str::<OfferLease>(&body) {
+        Ok(lease) => lease,
+        Err(e) => {
+            log::error!("Error decoding offer release: {}", e);
+            return HttpResponse::BadRequest().body(format!("Invalid format {}", e));
+        }
+    };
+    if let Err(resp) = data.auth.verify(&req, &body, lease.requestor_id) {
+        return resp;
+    }
+
+    let mut lock = data.demands.lock().await;
+    let mut offers_lock = data.lock.lock().await;
+    let offer_obj = match get_leased_offer(&offers_lock, &lease).await {
+        Ok(offer_obj) => offer_obj,
+        Err(resp) => return resp,
+    };
+    let config = data.config.get();
+    let res = release_offer_from_demand(
+        &mut lock,
+        &mut offers_lock,
+        offer_obj,
+        &data.fair_share,
+        &config.picker,
+    )
+    .await;
+    match res {
+        Ok(()) => HttpResponse::Ok().body("Offer released"),
+        Err(e) => storage_error(e),
+    }
+}
+
+#[actix_web::test]
+async fn test_expired_lease_taken_back_from_fair_share() {
+    use crate::state::DemandObj;
+    use crate::test_util::{app_state, demand, offer};
+
+    let data = app_state();
+    let mut demand = demand("demand", 0x11, Utc::now() + chrono::Duration::hours(1));
+    demand.central_net_address = Some("net-a".to_string());
+    let mut demand_obj = DemandObj::new(demand);
+    let mut offer_obj = offer("offer", 1);
+    offer_obj.assign(demand_obj.demand.node_id);
+    demand_obj.push_offer(offer_obj.offer.id.clone());
+    let config = data.config.get();
+    data.fair_share
+        .assigned(&demand_obj, Utc::now(), &config.picker);
+    data.lock.lock().await.insert(offer_obj).await.unwrap();
+    data.demands.lock().await.insert(demand_obj).await.unwrap();
+
+    release_expired_leases(data.clone(), chrono::Duration::seconds(-1)).await;
+
+    let offers = data.lock.snapshot().await.unwrap();
+    assert!(offers
+        .iter()
+        .all(|offer_obj| offer_obj.requestor_id.is_none()));
+    assert!(data
+        .demands
+        .snapshot()
+        .await
+        .unwrap()
+        .iter()
+        .all(|d| d.offer_list.is_empty()));
+    let demands = data.demands.snapshot().await.unwrap();
+    let demands: Vec<&DemandObj> = demands.iter().collect();
+    let shares = data.fair_share.shares(&demands, &config.picker, Utc::now());
+    assert!(shares[0].usage < 1e-3, "{:?}", shares);
+}
@@ -1,28 +1,53 @@
+use crate::rest::storage_error;
 use crate::state::{AppState, OfferObj};
 use actix_web::{web, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+// listings are served from the published snapshot, so they never wait for the picker
+async fn list_filtered<F: Fn(&OfferObj) -> bool>(
+    data: web::Data<AppState>,
+    predicate: F,
+) -> HttpResponse {
+    match data.lock.snapshot().await {
+        Ok(snapshot) => HttpResponse::Ok().json(snapshot.filter(predicate)),
+        Err(e) => storage_error(e),
+    }
+}
 
 pub async fn list_offers(data: web::Data<AppState>) -> impl Responder {
-    let lock = data.lock.lock().await;
-    let offers: Vec<&OfferObj> = lock.offer_map.values().collect();
-    HttpResponse::Ok().json(offers)
+    list_filtered(data, |_| true).await
 }
 
 pub async fn list_taken_offers(data: web::Data<AppState>) -> impl Responder {
-    let lock = data.lock.lock().await;
-    let offers: Vec<&OfferObj> = lock
-        .offer_map
-        .values()
-        .filter(|offer_obj| offer_obj.requestor_id.is_some())
-        .collect();
-    HttpResponse::Ok().json(offers)
+    list_filtered(data, |offer_obj| offer_obj.requestor_id.is_some()).await
 }
 
 pub async fn list_available_offers(data: web::Data<AppState>) -> impl Responder {
-    let lock = data.lock.lock().await;
-    let offers: Vec<&OfferObj> = lock
-        .offer_map
-        .values()
-        .filter(|offer_obj| offer_obj.requestor_id.is_none())
-        .collect();
-    HttpResponse::Ok().json(offers)
+    list_filtered(data, |offer_obj| offer_obj.requestor_id.is_none()).await
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfferChangesQuery {
+    /// Sequence number returned by the previous request, 0 to get all offers
+    #[serde(default)]
+    pub since: u64,
+    /// Epoch returned by the previous request, all offers are returned if it does not match
+    pub epoch: Option<String>,
+}
+
+/// Change feed for mirrors: offers added or updated and tombstones of offers removed
+/// since the given sequence number. Served from the published feed, like listings.
+pub async fn list_offer_changes(
+    data: web::Data<AppState>,
+    query: web::Query<OfferChangesQuery>,
+) -> impl Responder {
+    match data
+        .lock
+        .changes_since(query.epoch.as_deref(), query.since)
+        .await
+    {
+        Ok(changes) => HttpResponse::Ok().json(changes),
+        Err(e) => storage_error(e),
+    }
 }
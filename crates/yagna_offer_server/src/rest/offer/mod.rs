@@ -1,3 +1,5 @@
 pub mod clean_old_offers;
+pub mod lease;
 pub mod list_offers;
 pub mod push_offer;
+pub mod sources;
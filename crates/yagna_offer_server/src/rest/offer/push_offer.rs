@@ -1,33 +1,41 @@
-use crate::model::offer::attributes::OfferFlatAttributes;
-use crate::model::offer::base::GolemBaseOffer;
+use crate::ingest::{decode_offer, ingest_offers, IngestOutcome};
+use crate::rest::storage_error;
 use crate::state::{AppState, OfferObj};
-use actix_web::{web, HttpResponse, Responder};
-use chrono::Utc;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 
-pub async fn push_offer(data: web::Data<AppState>, item: String) -> impl Responder {
-    let decode = serde_json::from_str::<GolemBaseOffer>(&item);
-    let offer = match decode {
+/// Responds with [`crate::ingest::IngestResult`] of the offer. Rejected offers get 400
+/// with every failed validation rule and path of the field, so operators can fix their nodes.
+pub async fn push_offer(
+    data: web::Data<AppState>,
+    req: HttpRequest,
+    item: String,
+) -> impl Responder {
+    let offer = match decode_offer(&item) {
         Ok(offer) => offer,
-        Err(e) => {
-            log::error!("Error decoding offer: {}", e);
-            return HttpResponse::BadRequest().body("Invalid offer format");
+        Err(error) => {
+            log::error!("Error decoding offer at {}: {}", error.field, error.message);
+            return HttpResponse::BadRequest().json(serde_json::json!({
+                "outcome": "rejected",
+                "errors": [error],
+            }));
         }
     };
+    if let Err(resp) = data.auth.verify(&req, &item, offer.provider_id) {
+        return resp;
+    }
 
+    let rules = data.config.get().validation.clone();
     let mut lock = data.lock.lock().await;
-    if lock.offer_map.contains_key(&offer.id) {
-        let id = &offer.id;
-        return HttpResponse::Ok().body(format!("Offer {id} already registered"));
+    let report =
+        match ingest_offers(&mut lock, &data.metrics, &rules, vec![OfferObj::new(offer)]).await {
+            Ok(report) => report,
+            Err(e) => return storage_error(e),
+        };
+    match report.results.into_iter().next() {
+        Some(result) if matches!(result.outcome, IngestOutcome::Rejected { .. }) => {
+            HttpResponse::BadRequest().json(result)
+        }
+        Some(result) => HttpResponse::Ok().json(result),
+        None => HttpResponse::InternalServerError().body("Offer was not processed"),
     }
-    let attributes = OfferFlatAttributes::from_gbo(&offer);
-    lock.offer_map.insert(
-        offer.id.clone(),
-        OfferObj {
-            offer,
-            pushed_at: Utc::now(),
-            requestor_id: None,
-            attributes,
-        },
-    );
-    HttpResponse::Ok().body("Offer added to the queue")
 }
@@ -0,0 +1,7 @@
+use crate::state::AppState;
+use actix_web::{web, HttpResponse, Responder};
+
+/// Last synchronization result of every configured mirror source, by source name
+pub async fn mirror_sources_status(data: web::Data<AppState>) -> impl Responder {
+    HttpResponse::Ok().json(data.mirror_status.snapshot())
+}
@@ -0,0 +1,501 @@
+use crate::config::PickerConfig;
+use crate::state::{DemandObj, OfferObj};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hash;
+
+/// Number of assignments decaying exponentially, by factor e every window
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Usage {
+    value: f64,
+    updated_at: DateTime<Utc>,
+}
+
+impl Usage {
+    fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            value: 0.0,
+            updated_at: now,
+        }
+    }
+
+    /// Value decayed to `now`
+    pub fn at(&self, now: DateTime<Utc>, window_secs: f64) -> f64 {
+        let elapsed = (now - self.updated_at).num_milliseconds().max(0) as f64 / 1000.0;
+        self.value * (-elapsed / window_secs).exp()
+    }
+
+    fn add(&mut self, amount: f64, now: DateTime<Utc>, window_secs: f64) {
+        *self = Self {
+            value: (self.at(now, window_secs) + amount).max(0.0),
+            updated_at: now,
+        };
+    }
+}
+
+fn usage_at<K: Hash + Eq>(
+    usages: &HashMap<K, Usage>,
+    key: &K,
+    now: DateTime<Utc>,
+    window_secs: f64,
+) -> f64 {
+    usages
+        .get(key)
+        .map(|usage| usage.at(now, window_secs))
+        .unwrap_or(0.0)
+}
+
+/// Requestor node within a central net
+type RequestorKey = (String, String);
+
+#[derive(Debug, Default)]
+struct FairShareInner {
+    /// Requestor active in several nets is charged separately in each of them
+    requestors: HashMap<RequestorKey, Usage>,
+    nets: HashMap<String, Usage>,
+}
+
+/// Usage of a requestor node in a central net, as saved in the state file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestorUsage {
+    pub central_net: String,
+    pub node_id: String,
+    pub usage: Usage,
+}
+
+/// Fair-share counters saved with the state, so restart does not reset fairness.
+/// Usage keeps the time of its last update and decays over the downtime when read.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FairShareUsage {
+    pub requestors: Vec<RequestorUsage>,
+    pub nets: BTreeMap<String, Usage>,
+}
+
+/// Share of a requestor node in offers assigned within the decay window,
+/// response of /requestor/shares
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestorShare {
+    pub node_id: String,
+    pub central_net: String,
+    pub weight: f64,
+    /// Decayed number of offers assigned to the requestor
+    pub usage: f64,
+    /// Fraction of all (decayed) assignments that went to the requestor
+    pub share: f64,
+    /// Fraction the requestor is entitled to, given weights of its net and of itself
+    pub target_share: f64,
+    /// Positive when the requestor received less than its target share
+    pub deficit: f64,
+}
+
+/// Demand chosen to receive the next offer
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pick {
+    pub central_net: String,
+    pub node_id: String,
+    pub demand_id: String,
+}
+
+/// Weighted fair-share accounting of the periodic picker. Central nets get offers
+/// in proportion to their weights, requestors of a net share its offers in proportion
+/// to their weights. Usage decays over time, so past assignments are gradually forgotten.
+#[derive(Debug, Default)]
+pub struct FairShare {
+    inner: std::sync::Mutex<FairShareInner>,
+}
+
+fn weight(weights: &BTreeMap<String, f64>, key: &str) -> f64 {
+    weights.get(key).copied().unwrap_or(1.0)
+}
+
+/// Demands taking part in scheduling, grouped by central net and requestor node
+fn active(demands: &[&DemandObj]) -> BTreeMap<String, BTreeMap<String, Vec<String>>> {
+    let mut nets: BTreeMap<String, BTreeMap<String, Vec<String>>> = BTreeMap::new();
+    for demand_obj in demands {
+        let Some(net) = demand_obj.demand.central_net_address.as_ref() else {
+            continue;
+        };
+        nets.entry(net.clone())
+            .or_default()
+            .entry(demand_obj.demand.node_id.to_string())
+            .or_default()
+            .push(demand_obj.demand.id.clone());
+    }
+    nets
+}
+
+impl FairShare {
+    fn inner(&self) -> std::sync::MutexGuard<'_, FairShareInner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Records offer assigned to the requestor node in the central net
+    pub fn record(&self, net: &str, node_id: &str, now: DateTime<Utc>, window_secs: f64) {
+        let mut inner = self.inner();
+        inner
+            .requestors
+            .entry((net.to_string(), node_id.to_string()))
+            .or_insert(Usage::new(now))
+            .add(1.0, now, window_secs);
+        inner
+            .nets
+            .entry(net.to_string())
+            .or_insert(Usage::new(now))
+            .add(1.0, now, window_secs);
+    }
+
+    /// Takes back offer assigned at `assigned_at` and returned to the pool since,
+    /// only the part of it that did not decay yet is subtracted
+    pub fn unrecord(
+        &self,
+        net: &str,
+        node_id: &str,
+        assigned_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+        window_secs: f64,
+    ) {
+        let remaining = Usage {
+            value: 1.0,
+            updated_at: assigned_at,
+        }
+        .at(now, window_secs);
+        let mut inner = self.inner();
+        if let Some(usage) = inner
+            .requestors
+            .get_mut(&(net.to_string(), node_id.to_string()))
+        {
+            usage.add(-remaining, now, window_secs);
+        }
+        if let Some(usage) = inner.nets.get_mut(net) {
+            usage.add(-remaining, now, window_secs);
+        }
+    }
+
+    /// Records offer assigned to the demand, demands outside central nets are not scheduled
+    pub fn assigned(&self, demand_obj: &DemandObj, now: DateTime<Utc>, config: &PickerConfig) {
+        if let Some(net) = demand_obj.demand.central_net_address.as_deref() {
+            let node_id = demand_obj.demand.node_id.to_string();
+            self.record(net, &node_id, now, config.share_window_secs);
+        }
+    }
+
+    /// Takes back offer of the demand returned to the pool, call before the offer is released
+    pub fn released(
+        &self,
+        demand_obj: &DemandObj,
+        offer_obj: &OfferObj,
+        now: DateTime<Utc>,
+        config: &PickerConfig,
+    ) {
+        let net = demand_obj.demand.central_net_address.as_deref();
+        if let (Some(net), Some(assigned_at)) = (net, offer_obj.assigned_at) {
+            let node_id = demand_obj.demand.node_id.to_string();
+            self.unrecord(net, &node_id, assigned_at, now, config.share_window_secs);
+        }
+    }
+
+    /// Counters to be saved, ones decayed to nothing are left out
+    pub fn usage(&self, now: DateTime<Utc>, window_secs: f64) -> FairShareUsage {
+        let inner = self.inner();
+        let significant = |usage: &&Usage| usage.at(now, window_secs) > 1e-6;
+        let mut requestors: Vec<RequestorUsage> = inner
+            .requestors
+            .iter()
+            .filter(|(_, usage)| significant(usage))
+            .map(|((net, node_id), usage)| RequestorUsage {
+                central_net: net.clone(),
+                node_id: node_id.clone(),
+                usage: *usage,
+            })
+            .collect();
+        requestors.sort_by(|a, b| (&a.central_net, &a.node_id).cmp(&(&b.central_net, &b.node_id)));
+        FairShareUsage {
+            requestors,
+            nets: inner
+                .nets
+                .iter()
+                .filter(|(_, usage)| significant(usage))
+                .map(|(net, usage)| (net.clone(), *usage))
+                .collect(),
+        }
+    }
+
+    /// Replaces counters with saved ones
+    pub fn restore(&self, usage: FairShareUsage) {
+        let mut inner = self.inner();
+        inner.requestors = usage
+            .requestors
+            .into_iter()
+            .map(|requestor| ((requestor.central_net, requestor.node_id), requestor.usage))
+            .collect();
+        inner.nets = usage.nets.into_iter().collect();
+    }
+
+    /// Forgets usage of the requestor node in all nets, or of all requestors and nets.
+    /// Returns number of forgotten requestor counters.
+    pub fn reset(&self, node_id: Option<&str>) -> usize {
+        let mut inner = self.inner();
+        let before = inner.requestors.len();
+        match node_id {
+            Some(node_id) => inner.requestors.retain(|(_, node), _| node != node_id),
+            None => *inner = FairShareInner::default(),
+        }
+        before - inner.requestors.len()
+    }
+
+    /// Demand to receive the next offer: the net with the lowest usage per weight,
+    /// then its requestor with the lowest usage per weight, then its first demand.
+    /// Demands in `exhausted` (no matching offers in this tick) are skipped, as well as
+    /// demands with full queues or reached allocation, see [`DemandObj::wants_offers`].
+    pub fn next_demand(
+        &self,
+        demands: &[&DemandObj],
+        exhausted: &HashSet<String>,
+        config: &PickerConfig,
+        now: DateTime<Utc>,
+    ) -> Option<Pick> {
+        let inner = self.inner();
+        let window_secs = config.share_window_secs;
+        let lowest = |candidates: Vec<(f64, String)>| {
+            candidates
+                .into_iter()
+                .fold(None, |best: Option<(f64, String)>, candidate| match best {
+                    Some(best) if best.0 <= candidate.0 => Some(best),
+                    _ => Some(candidate),
+                })
+                .map(|(_, key)| key)
+        };
+
+        let wanting: Vec<&DemandObj> = demands
+            .iter()
+            .copied()
+            .filter(|demand_obj| demand_obj.wants_offers(config.queue_depth))
+            .collect();
+        let mut nets = active(&wanting);
+        for requestors in nets.values_mut() {
+            for demand_ids in requestors.values_mut() {
+                demand_ids.retain(|demand_id| !exhausted.contains(demand_id));
+            }
+            requestors.retain(|_, demand_ids| !demand_ids.is_empty());
+        }
+        nets.retain(|_, requestors| !requestors.is_empty());
+
+        let net = lowest(
+            nets.keys()
+                .map(|net| {
+                    let used = usage_at(&inner.nets, net, now, window_secs)
+                        / weight(&config.net_weights, net);
+                    (used, net.clone())
+                })
+                .collect(),
+        )?;
+        let requestors = &nets[&net];
+        let node_id = lowest(
+            requestors
+                .keys()
+                .map(|node_id| {
+                    let key = (net.clone(), node_id.clone());
+                    let used = usage_at(&inner.requestors, &key, now, window_secs)
+                        / weight(&config.requestor_weights, node_id);
+                    (used, node_id.clone())
+                })
+                .collect(),
+        )?;
+        let demand_id = requestors[&node_id].first()?.clone();
+        Some(Pick {
+            central_net: net,
+            node_id,
+            demand_id,
+        })
+    }
+
+    /// Current and target shares of requestors having demands in central nets
+    pub fn shares(
+        &self,
+        demands: &[&DemandObj],
+        config: &PickerConfig,
+        now: DateTime<Utc>,
+    ) -> Vec<RequestorShare> {
+        let inner = self.inner();
+        let nets = active(demands);
+        let usage = |net: &str, node_id: &str| {
+            let key = (net.to_string(), node_id.to_string());
+            usage_at(&inner.requestors, &key, now, config.share_window_secs)
+        };
+        let total_net_weight: f64 = nets
+            .keys()
+            .map(|net| weight(&config.net_weights, net))
+            .sum();
+        let total_usage: f64 = nets
+            .iter()
+            .flat_map(|(net, requestors)| requestors.keys().map(|node_id| usage(net, node_id)))
+            .sum();
+
+        let mut shares = Vec::new();
+        for (net, requestors) in nets.iter() {
+            let net_share = weight(&config.net_weights, net) / total_net_weight;
+            let total_requestor_weight: f64 = requestors
+                .keys()
+                .map(|node_id| weight(&config.requestor_weights, node_id))
+                .sum();
+            for node_id in requestors.keys() {
+                let requestor_weight = weight(&config.requestor_weights, node_id);
+                let usage = usage(net, node_id);
+                let share = match total_usage > 0.0 {
+                    true => usage / total_usage,
+                    false => 0.0,
+                };
+                let target_share = net_share * requestor_weight / total_requestor_weight;
+                shares.push(RequestorShare {
+                    node_id: node_id.clone(),
+                    central_net: net.clone(),
+                    weight: requestor_weight,
+                    usage,
+                    share,
+                    target_share,
+                    deficit: target_share - share,
+                });
+            }
+        }
+        shares
+    }
+}
+
+#[test]
+fn test_fair_share_scheduling() {
+    use crate::test_util::demand;
+
+    let demand = |id: &str, node: u8, net: Option<&str>| {
+        let mut demand = demand(id, node, Utc::now() + chrono::Duration::hours(1));
+        demand.central_net_address = net.map(str::to_string);
+        DemandObj::new(demand)
+    };
+    let demands = [
+        demand("a1", 1, Some("net-a")),
+        demand("a2", 2, Some("net-a")),
+        demand("b1", 3, Some("net-b")),
+        demand("none", 4, None),
+    ];
+    let demands: Vec<&DemandObj> = demands.iter().collect();
+    let node = |node: u8| ya_client_model::NodeId::from([node; 20]).to_string();
+
+    let mut config = PickerConfig::default();
+    config.net_weights.insert("net-a".to_string(), 3.0);
+    config.requestor_weights.insert(node(1), 2.0);
+    let fair_share = FairShare::default();
+    let now = Utc::now();
+
+    // run the scheduler long enough, assignments follow the weights
+    let mut given: HashMap<String, u32> = HashMap::new();
+    for _ in 0..120 {
+        let pick = fair_share
+            .next_demand(&demands, &HashSet::new(), &config, now)
+            .unwrap();
+        fair_share.record(
+            &pick.central_net,
+            &pick.node_id,
+            now,
+            config.share_window_secs,
+        );
+        *given.entry(pick.demand_id).or_default() += 1;
+    }
+    // net-a gets 3/4 of offers, split 2:1 between its requestors
+    assert_eq!(given["a1"], 60);
+    assert_eq!(given["a2"], 30);
+    assert_eq!(given["b1"], 30);
+    assert!(!given.contains_key("none"));
+
+    let shares = fair_share.shares(&demands, &config, now);
+    assert_eq!(shares.len(), 3);
+    for share in shares.iter() {
+        assert!(share.deficit.abs() < 1e-9, "{:?}", share);
+    }
+    assert_eq!(shares[0].target_share, 0.5);
+
+    // requestors without offers available do not block others
+    let exhausted: HashSet<String> = ["a1".to_string(), "a2".to_string()].into();
+    let pick = fair_share
+        .next_demand(&demands, &exhausted, &config, now)
+        .unwrap();
+    assert_eq!(
+        pick,
+        Pick {
+            central_net: "net-b".to_string(),
+            node_id: node(3),
+            demand_id: "b1".to_string(),
+        }
+    );
+    let exhausted: HashSet<String> = ["a1", "a2", "b1"].map(String::from).into();
+    assert!(fair_share
+        .next_demand(&demands, &exhausted, &config, now)
+        .is_none());
+
+    // usage decays, after many windows past assignments are forgotten
+    let later = now + chrono::Duration::seconds((config.share_window_secs * 20.0) as i64);
+    let shares = fair_share.shares(&demands, &config, later);
+    assert!(shares.iter().all(|share| share.usage < 1e-6));
+
+    assert_eq!(fair_share.reset(Some(&node(1))), 1);
+    let shares = fair_share.shares(&demands, &config, now);
+    assert_eq!(shares[0].usage, 0.0);
+    assert!(shares[0].deficit > 0.0);
+
+    // demands with full queues or reached allocation wait, a1 is still the most entitled
+    let mut full = demand("a1", 1, Some("net-a"));
+    full.demand.queue_depth = Some(2);
+    full.push_offer("x".to_string());
+    full.push_offer("y".to_string());
+    let mut capped = demand("b1", 3, Some("net-b"));
+    capped.demand.max_offers = Some(2);
+    capped.allocated = 2;
+    let waiting = [full, demand("a2", 2, Some("net-a")), capped];
+    let waiting: Vec<&DemandObj> = waiting.iter().collect();
+    let pick = fair_share
+        .next_demand(&waiting, &HashSet::new(), &config, now)
+        .unwrap();
+    assert_eq!(pick.demand_id, "a2");
+    config.queue_depth = Some(1);
+    assert!(fair_share
+        .next_demand(&waiting, &HashSet::new(), &config, now)
+        .is_some());
+    // taking an offer from the queue frees a slot, the default depth applies to a2 as well
+    let mut taken = waiting[0].clone();
+    taken.offer_list.pop_front();
+    let mut a2 = waiting[1].clone();
+    a2.push_offer("z".to_string());
+    let waiting = [&taken, &a2, waiting[2]];
+    let pick = fair_share
+        .next_demand(&waiting, &HashSet::new(), &config, now)
+        .unwrap();
+    assert_eq!(pick.demand_id, "a1");
+    assert!(fair_share
+        .next_demand(&[&a2, waiting[2]], &HashSet::new(), &config, now)
+        .is_none());
+
+    // requestor active in two nets is not charged in one net for offers from the other
+    let fair_share = FairShare::default();
+    let config = PickerConfig::default();
+    for _ in 0..5 {
+        fair_share.record("net-a", &node(1), now, config.share_window_secs);
+    }
+    let demands = [
+        demand("a1", 1, Some("net-a")),
+        demand("b1", 1, Some("net-b")),
+        demand("b2", 2, Some("net-b")),
+    ];
+    let demands: Vec<&DemandObj> = demands.iter().collect();
+    let pick = fair_share
+        .next_demand(&demands, &HashSet::new(), &config, now)
+        .unwrap();
+    assert_eq!(pick.demand_id, "b1");
+    let shares = fair_share.shares(&demands, &config, now);
+    assert_eq!(shares[0].usage, 5.0);
+    assert_eq!(shares[1].usage, 0.0);
+    assert_eq!(fair_share.reset(Some(&node(1))), 1);
+    assert_eq!(fair_share.reset(None), 0);
+}
@@ -0,0 +1,275 @@
+use crate::config::ScoreWeights;
+use crate::model::offer::pricing::OfferOrder;
+use crate::state::OfferObj;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use ya_client_model::NodeId;
+
+/// State of the market used by strategies, besides the candidates themselves
+pub struct SelectionContext<'a> {
+    pub now: DateTime<Utc>,
+    /// Latest assignment of every provider, see [`crate::state::Offers::last_assigned`]
+    pub last_assigned: &'a HashMap<NodeId, DateTime<Utc>>,
+    pub weights: ScoreWeights,
+}
+
+/// Picks one offer out of candidates matching the demand (or `/offer/take` filter)
+pub trait SelectionStrategy: Send + Sync {
+    /// Candidates are ordered by offer id and never empty
+    fn select<'a>(
+        &self,
+        candidates: &[&'a OfferObj],
+        ctx: &SelectionContext,
+    ) -> Option<&'a OfferObj>;
+}
+
+/// Candidate with the highest key, the first one wins if keys are equal
+fn first_best<'a, K: PartialOrd>(
+    candidates: &[&'a OfferObj],
+    key: impl Fn(&OfferObj) -> K,
+) -> Option<&'a OfferObj> {
+    candidates
+        .iter()
+        .map(|offer| (key(offer), *offer))
+        .fold(
+            None,
+            |best: Option<(K, &OfferObj)>, (key, offer)| match best {
+                Some(best) if best.0 >= key => Some(best),
+                _ => Some((key, offer)),
+            },
+        )
+        .map(|(_, offer)| offer)
+}
+
+pub struct NewestFirst;
+
+impl SelectionStrategy for NewestFirst {
+    fn select<'a>(
+        &self,
+        candidates: &[&'a OfferObj],
+        _ctx: &SelectionContext,
+    ) -> Option<&'a OfferObj> {
+        first_best(candidates, |offer| offer.offer.timestamp)
+    }
+}
+
+/// Newer offer wins if prices are equal
+pub struct CheapestFirst;
+
+impl SelectionStrategy for CheapestFirst {
+    fn select<'a>(
+        &self,
+        candidates: &[&'a OfferObj],
+        _ctx: &SelectionContext,
+    ) -> Option<&'a OfferObj> {
+        first_best(candidates, |offer| {
+            (Reverse(offer.price_per_hour()), offer.offer.timestamp)
+        })
+    }
+}
+
+pub struct RandomPick;
+
+impl SelectionStrategy for RandomPick {
+    fn select<'a>(
+        &self,
+        candidates: &[&'a OfferObj],
+        _ctx: &SelectionContext,
+    ) -> Option<&'a OfferObj> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let idx = rand::rng().random_range(0..candidates.len());
+        candidates.get(idx).copied()
+    }
+}
+
+/// Providers never assigned go first, newer offer wins among offers of equally idle providers
+pub struct LeastRecentlyAssigned;
+
+impl SelectionStrategy for LeastRecentlyAssigned {
+    fn select<'a>(
+        &self,
+        candidates: &[&'a OfferObj],
+        ctx: &SelectionContext,
+    ) -> Option<&'a OfferObj> {
+        first_best(candidates, |offer| {
+            (
+                Reverse(ctx.last_assigned.get(&offer.offer.provider_id).copied()),
+                offer.offer.timestamp,
+            )
+        })
+    }
+}
+
+/// Position of the value between min and max, 1 if all values are equal
+fn scale(value: f64, min: f64, max: f64) -> f64 {
+    if max > min {
+        (value - min) / (max - min)
+    } else {
+        1.0
+    }
+}
+
+fn min_max(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| {
+        (min.min(value), max.max(value))
+    })
+}
+
+/// Weighted sum of price, freshness and resources, every component scaled to 0..1
+/// among the candidates. Offers with unknown pricing get 0 for the price.
+pub struct WeightedScore;
+
+impl SelectionStrategy for WeightedScore {
+    fn select<'a>(
+        &self,
+        candidates: &[&'a OfferObj],
+        ctx: &SelectionContext,
+    ) -> Option<&'a OfferObj> {
+        let prices = min_max(
+            candidates
+                .iter()
+                .map(|offer| offer.price_per_hour())
+                .filter(|price| price.is_finite()),
+        );
+        let ages = min_max(
+            candidates
+                .iter()
+                .map(|offer| (ctx.now - offer.offer.timestamp).num_milliseconds() as f64),
+        );
+        let resources = |offer: &OfferObj| {
+            [
+                offer.attributes.cpu_threads as f64,
+                offer.attributes.mem_gib,
+                offer.attributes.storage_gib,
+            ]
+        };
+        let resource_ranges: Vec<(f64, f64)> = (0..3)
+            .map(|idx| min_max(candidates.iter().map(|offer| resources(offer)[idx])))
+            .collect();
+
+        let weights = ctx.weights;
+        first_best(candidates, |offer| {
+            let price = offer.price_per_hour();
+            let price_score = match price.is_finite() {
+                true => 1.0 - scale(price, prices.0, prices.1),
+                false => 0.0,
+            };
+            let age = (ctx.now - offer.offer.timestamp).num_milliseconds() as f64;
+            let freshness_score = 1.0 - scale(age, ages.0, ages.1);
+            let resource_score = resources(offer)
+                .iter()
+                .zip(resource_ranges.iter())
+                .map(|(value, (min, max))| scale(*value, *min, *max))
+                .sum::<f64>()
+                / 3.0;
+            weights.price * price_score
+                + weights.freshness * freshness_score
+                + weights.resources * resource_score
+        })
+    }
+}
+
+pub fn strategy(order: OfferOrder) -> &'static dyn SelectionStrategy {
+    match order {
+        OfferOrder::Newest => &NewestFirst,
+        OfferOrder::Cheapest => &CheapestFirst,
+        OfferOrder::Random => &RandomPick,
+        OfferOrder::LeastRecentlyAssigned => &LeastRecentlyAssigned,
+        OfferOrder::Weighted => &WeightedScore,
+    }
+}
+
+/// Selects offer from unassigned, unexpired candidates using strategy of the order.
+/// Offers published in the future (provider clock skew) are skipped.
+pub fn select_offer<'a>(
+    candidates: &[&'a OfferObj],
+    order: OfferOrder,
+    ctx: &SelectionContext,
+) -> Option<&'a OfferObj> {
+    let candidates: Vec<&OfferObj> = candidates
+        .iter()
+        .copied()
+        .filter(|offer| offer.offer.timestamp < ctx.now)
+        .collect();
+    if candidates.is_empty() {
+        return None;
+    }
+    strategy(order).select(&candidates, ctx)
+}
+
+#[test]
+fn test_selection_strategies() {
+    use crate::test_util::golem_offer;
+
+    let now = Utc::now();
+    let offer = |id: &str, provider: u8, age_mins: i64, price: f64, threads: u32| {
+        let mut gbo = golem_offer(id, provider);
+        gbo.timestamp = now - chrono::Duration::minutes(age_mins);
+        gbo.properties
+            .set(
+                "golem.com.pricing.model.linear.coeffs",
+                serde_json::json!([price, 0.0, 0.0]),
+            )
+            .unwrap();
+        gbo.properties
+            .set("golem.inf.cpu.threads", threads.into())
+            .unwrap();
+        OfferObj::new(gbo)
+    };
+    // per hour: a is old and cheap, b is fresh and expensive,
+    // c is in the middle for age, a bit more expensive than a, and has most threads
+    let offers = [
+        offer("a", 1, 60, 0.001, 2),
+        offer("b", 2, 1, 0.010, 2),
+        offer("c", 3, 30, 0.0001, 32),
+        offer("future", 4, -10, 0.0, 64),
+    ];
+    let candidates: Vec<&OfferObj> = offers.iter().collect();
+    let never_assigned = HashMap::new();
+    let mut ctx = SelectionContext {
+        now,
+        last_assigned: &never_assigned,
+        weights: ScoreWeights::default(),
+    };
+    let select = |order: OfferOrder, ctx: &SelectionContext| {
+        select_offer(&candidates, order, ctx).map(|offer| offer.offer.id.clone())
+    };
+
+    assert_eq!(select(OfferOrder::Newest, &ctx).as_deref(), Some("b"));
+    assert_eq!(select(OfferOrder::Cheapest, &ctx).as_deref(), Some("a"));
+    assert_ne!(select(OfferOrder::Random, &ctx).as_deref(), Some("future"));
+    // nobody was assigned yet, the newest offer wins
+    assert_eq!(
+        select(OfferOrder::LeastRecentlyAssigned, &ctx).as_deref(),
+        Some("b")
+    );
+    assert_eq!(select(OfferOrder::Weighted, &ctx).as_deref(), Some("c"));
+
+    let last_assigned = [
+        (NodeId::from([1; 20]), now - chrono::Duration::minutes(5)),
+        (NodeId::from([2; 20]), now - chrono::Duration::minutes(1)),
+        (NodeId::from([3; 20]), now),
+    ]
+    .into_iter()
+    .collect();
+    ctx.last_assigned = &last_assigned;
+    assert_eq!(
+        select(OfferOrder::LeastRecentlyAssigned, &ctx).as_deref(),
+        Some("a")
+    );
+
+    ctx.weights = ScoreWeights {
+        price: 1.0,
+        freshness: 0.0,
+        resources: 0.0,
+    };
+    assert_eq!(select(OfferOrder::Weighted, &ctx).as_deref(), Some("a"));
+    ctx.weights.freshness = 10.0;
+    assert_eq!(select(OfferOrder::Weighted, &ctx).as_deref(), Some("b"));
+
+    assert!(select_offer(&[], OfferOrder::Random, &ctx).is_none());
+}
@@ -1,16 +1,61 @@
+use crate::auth::{AdminTokens, SignatureVerifier};
+use crate::config::SharedConfig;
+use crate::constraints::OfferMatching;
+use crate::index::{OfferIndex, OfferQuery};
+use crate::metrics::Metrics;
 use crate::model::demand::base::DemandSubscription;
 use crate::model::offer::attributes::OfferFlatAttributes;
 use crate::model::offer::base::GolemBaseOffer;
+use crate::offers::MirrorStatus;
+use crate::scheduler::FairShare;
+use crate::storage::sqlite::SqliteStorage;
+use crate::store::{Publish, Store};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, VecDeque};
-use std::sync::Arc;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::sync::{Arc, OnceLock};
+use tokio::sync::Notify;
 use ya_client_model::NodeId;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DemandObj {
     pub demand: DemandSubscription,
     pub offer_list: VecDeque<String>,
+    /// Offers assigned to the demand so far, including ones already taken or released
+    #[serde(default)]
+    pub allocated: u64,
+}
+
+impl DemandObj {
+    pub fn new(demand: DemandSubscription) -> Self {
+        Self {
+            demand,
+            offer_list: VecDeque::new(),
+            allocated: 0,
+        }
+    }
+
+    /// Queues the assigned offer for the requestor
+    pub fn push_offer(&mut self, offer_id: String) {
+        self.offer_list.push_back(offer_id);
+        self.allocated += 1;
+    }
+
+    /// True if the picker should assign more offers to the demand: its queue is below
+    /// the target depth (`default_depth` if the demand has none, unlimited if neither)
+    /// and the maximum total allocation is not reached yet
+    pub fn wants_offers(&self, default_depth: Option<usize>) -> bool {
+        let below_depth = self
+            .demand
+            .queue_depth
+            .or(default_depth)
+            .is_none_or(|depth| self.offer_list.len() < depth);
+        let below_max = self
+            .demand
+            .max_offers
+            .is_none_or(|max_offers| self.allocated < max_offers);
+        below_depth && below_max
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,21 +64,923 @@ pub struct OfferObj {
     pub pushed_at: DateTime<Utc>,
     pub requestor_id: Option<NodeId>,
     pub attributes: OfferFlatAttributes,
+    /// When the offer was assigned to requestor_id, start of the lease
+    #[serde(default)]
+    pub assigned_at: Option<DateTime<Utc>>,
+    /// Set when requestor confirmed it uses the offer, confirmed offers are never released
+    #[serde(default)]
+    pub confirmed_at: Option<DateTime<Utc>>,
+    /// Name of the mirror source the offer was downloaded from, None if pushed by the provider
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Computed at ingest, or on the first match for offers read back from storage
+    #[serde(skip)]
+    matching: OnceLock<Arc<OfferMatching>>,
+}
+
+impl OfferObj {
+    pub fn new(offer: GolemBaseOffer) -> Self {
+        Self {
+            attributes: OfferFlatAttributes::from_gbo(&offer),
+            offer,
+            pushed_at: Utc::now(),
+            requestor_id: None,
+            assigned_at: None,
+            confirmed_at: None,
+            source: None,
+            matching: OnceLock::new(),
+        }
+    }
+
+    /// Flat properties and parsed constraints of the offer, see [`crate::constraints::DemandMatcher`]
+    pub fn matching(&self) -> &OfferMatching {
+        self.matching
+            .get_or_init(|| Arc::new(OfferMatching::new(&self.offer)))
+    }
+
+    /// Computes attributes and matching data of the offer again, after it was
+    /// received from a provider or a mirror source
+    pub fn refresh_derived(&mut self) {
+        self.attributes = OfferFlatAttributes::from_gbo(&self.offer);
+        self.matching = OnceLock::from(Arc::new(OfferMatching::new(&self.offer)));
+    }
+
+    pub fn assign(&mut self, requestor_id: NodeId) {
+        self.requestor_id = Some(requestor_id);
+        self.assigned_at = Some(Utc::now());
+        self.confirmed_at = None;
+    }
+
+    /// Returns offer to the pool of available offers
+    pub fn release(&mut self) {
+        self.requestor_id = None;
+        self.assigned_at = None;
+        self.confirmed_at = None;
+    }
+
+    /// Assigned, but not confirmed within ttl. Offers assigned before leases
+    /// were introduced have no assigned_at and never expire.
+    pub fn lease_expired(&self, now: DateTime<Utc>, ttl: chrono::Duration) -> bool {
+        match (self.requestor_id, self.assigned_at, self.confirmed_at) {
+            (Some(_), Some(assigned_at), None) => assigned_at + ttl < now,
+            _ => false,
+        }
+    }
+}
+
+/// Tombstones kept for mirrors, older ones are dropped and mirrors behind them get full list
+const MAX_TOMBSTONES: usize = 10000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Change {
+    Updated,
+    Removed,
+}
+
+/// Sequence of offer store mutations, used by mirrors to download only changes.
+/// Only the latest change of every offer is kept. Sequence numbers are valid within
+/// the epoch, which changes on every server start.
+#[derive(Debug, Clone)]
+struct ChangeFeed {
+    epoch: String,
+    seq: u64,
+    /// Changes older than that were compacted, mirrors behind it need full list
+    min_seq: u64,
+    changes: BTreeMap<u64, (String, Change)>,
+    last_change: HashMap<String, u64>,
+    tombstones: BTreeSet<u64>,
+}
+
+impl Default for ChangeFeed {
+    fn default() -> Self {
+        Self {
+            epoch: format!("{:x}", Utc::now().timestamp_nanos_opt().unwrap_or_default()),
+            seq: 0,
+            min_seq: 0,
+            changes: BTreeMap::new(),
+            last_change: HashMap::new(),
+            tombstones: BTreeSet::new(),
+        }
+    }
+}
+
+impl ChangeFeed {
+    fn record(&mut self, id: &str, change: Change) {
+        self.seq += 1;
+        if let Some(prev_seq) = self.last_change.insert(id.to_string(), self.seq) {
+            self.changes.remove(&prev_seq);
+            self.tombstones.remove(&prev_seq);
+        }
+        self.changes.insert(self.seq, (id.to_string(), change));
+        if change == Change::Removed {
+            self.tombstones.insert(self.seq);
+        }
+        while self.tombstones.len() > MAX_TOMBSTONES {
+            let Some(seq) = self.tombstones.pop_first() else {
+                break;
+            };
+            if let Some((id, _)) = self.changes.remove(&seq) {
+                self.last_change.remove(&id);
+            }
+            self.min_seq = seq;
+        }
+    }
+}
+
+/// Change feed of the offer store, updated when the store lock is released,
+/// so mirrors read it together with the published snapshot without the lock
+#[derive(Debug, Default)]
+pub struct OfferFeed(std::sync::RwLock<ChangeFeed>);
+
+/// Offer removed from the store, sent to mirrors so they can remove it too
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct OfferTombstone {
+    pub id: String,
+    pub seq: u64,
+}
+
+/// Offers changed since the given sequence number, response of /offers/changes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OfferChanges {
+    pub epoch: String,
+    /// Sequence number of the last change, to be passed as `since` in the next request
+    pub seq: u64,
+    /// Requested changes are not available (other epoch or compacted), `offers` contains all offers
+    pub reset: bool,
+    pub offers: Vec<OfferObj>,
+    pub removed: Vec<OfferTombstone>,
+}
+
+/// Where offers are kept, selected at startup
+#[derive(Debug, Clone)]
+enum OfferBackend {
+    /// Offers kept in memory, looked up through the in-memory index
+    Memory {
+        offers: BTreeMap<String, Arc<OfferObj>>,
+        index: Box<OfferIndex>,
+    },
+    /// Offers kept only in the database, looked up through its indexes
+    Sqlite(SqliteStorage),
+}
+
+impl Default for OfferBackend {
+    fn default() -> Self {
+        OfferBackend::Memory {
+            offers: BTreeMap::new(),
+            index: Box::default(),
+        }
+    }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// Offer store, all reads and mutations of offers go through this type,
+/// regardless of the storage selected at startup.
+#[derive(Debug, Clone, Default)]
 pub struct Offers {
-    pub offer_map: BTreeMap<String, OfferObj>,
+    backend: OfferBackend,
+    feed: Arc<OfferFeed>,
+    /// Changes of the current batch, recorded in the feed when the store is published
+    feed_changes: Vec<(String, Change)>,
+    /// Latest assignment of every provider, kept also after its offers are gone
+    last_assigned: HashMap<NodeId, DateTime<Utc>>,
+    /// Offers changed since the store was last published, None for removed ones
+    changes: HashMap<String, Option<Arc<OfferObj>>>,
+    /// Offers were written to the database since the store was last published
+    stored: bool,
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+impl Offers {
+    /// Opens offers kept in the database, only assignment times of providers are loaded
+    pub async fn new_sqlite(storage: SqliteStorage) -> anyhow::Result<Self> {
+        Ok(Self {
+            last_assigned: storage.last_assigned().await?,
+            backend: OfferBackend::Sqlite(storage),
+            ..Default::default()
+        })
+    }
+
+    pub async fn len(&self) -> anyhow::Result<usize> {
+        match &self.backend {
+            OfferBackend::Memory { offers, .. } => Ok(offers.len()),
+            OfferBackend::Sqlite(storage) => storage.count_offers().await,
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> anyhow::Result<Option<OfferObj>> {
+        match &self.backend {
+            OfferBackend::Memory { offers, .. } => {
+                Ok(offers.get(id).map(|offer_obj| offer_obj.as_ref().clone()))
+            }
+            OfferBackend::Sqlite(storage) => storage.get_offer(id).await,
+        }
+    }
+
+    pub async fn contains(&self, id: &str) -> anyhow::Result<bool> {
+        match &self.backend {
+            OfferBackend::Memory { offers, .. } => Ok(offers.contains_key(id)),
+            OfferBackend::Sqlite(storage) => Ok(storage.get_offer(id).await?.is_some()),
+        }
+    }
+
+    /// Inserts new offer or replaces the one with the same id
+    pub async fn insert(&mut self, offer_obj: OfferObj) -> anyhow::Result<()> {
+        let id = offer_obj.offer.id.clone();
+        self.track_assignment(&offer_obj);
+        match &mut self.backend {
+            OfferBackend::Memory { offers, index } => {
+                index.insert(&offer_obj);
+                let offer_obj = Arc::new(offer_obj);
+                self.changes.insert(id.clone(), Some(offer_obj.clone()));
+                offers.insert(id.clone(), offer_obj);
+            }
+            OfferBackend::Sqlite(storage) => {
+                storage.upsert_offer(&offer_obj).await?;
+                self.stored = true;
+            }
+        }
+        self.feed_changes.push((id, Change::Updated));
+        Ok(())
+    }
+
+    pub async fn remove(&mut self, id: &str) -> anyhow::Result<Option<OfferObj>> {
+        Ok(self.remove_many(&[id.to_string()]).await?.pop())
+    }
+
+    /// Removes offers of the given ids, returns the ones which were stored
+    async fn remove_many(&mut self, ids: &[String]) -> anyhow::Result<Vec<OfferObj>> {
+        let removed = match &mut self.backend {
+            OfferBackend::Memory { offers, index } => {
+                let mut removed = Vec::new();
+                for id in ids {
+                    if let Some(offer_obj) = offers.remove(id) {
+                        index.remove(id);
+                        self.changes.insert(id.clone(), None);
+                        removed.push(Arc::unwrap_or_clone(offer_obj));
+                    }
+                }
+                removed
+            }
+            OfferBackend::Sqlite(storage) => {
+                let removed = storage.remove_offers(ids).await?;
+                self.stored |= !removed.is_empty();
+                removed
+            }
+        };
+        self.record_removed(&removed);
+        Ok(removed)
+    }
+
+    fn record_removed(&mut self, removed: &[OfferObj]) {
+        self.feed_changes.extend(
+            removed
+                .iter()
+                .map(|offer_obj| (offer_obj.offer.id.clone(), Change::Removed)),
+        );
+    }
+
+    pub async fn clear(&mut self) -> anyhow::Result<()> {
+        let ids = match &mut self.backend {
+            OfferBackend::Memory { offers, index } => {
+                index.clear();
+                std::mem::take(offers).into_keys().collect()
+            }
+            OfferBackend::Sqlite(storage) => {
+                let ids = storage.clear_offers().await?;
+                self.stored |= !ids.is_empty();
+                ids
+            }
+        };
+        for id in ids {
+            self.feed_changes.push((id.clone(), Change::Removed));
+            if matches!(self.backend, OfferBackend::Memory { .. }) {
+                self.changes.insert(id, None);
+            }
+        }
+        Ok(())
+    }
+
+    /// All offers ordered by id
+    pub async fn all(&self) -> anyhow::Result<Vec<OfferObj>> {
+        self.filter(|_| true).await
+    }
+
+    pub async fn filter<F: Fn(&OfferObj) -> bool>(
+        &self,
+        predicate: F,
+    ) -> anyhow::Result<Vec<OfferObj>> {
+        let offers = self.query(&OfferQuery::default(), predicate).await?;
+        Ok(offers.into_iter().map(Arc::unwrap_or_clone).collect())
+    }
+
+    /// Same as [`Offers::filter`], but only offers not assigned to any requestor are considered
+    pub async fn filter_available<F: Fn(&OfferObj) -> bool>(
+        &self,
+        predicate: F,
+    ) -> anyhow::Result<Vec<OfferObj>> {
+        let offers = self
+            .query(
+                &OfferQuery {
+                    available: true,
+                    ..Default::default()
+                },
+                predicate,
+            )
+            .await?;
+        Ok(offers.into_iter().map(Arc::unwrap_or_clone).collect())
+    }
+
+    /// First offer (ordered by id) not assigned to any requestor and matching predicate
+    pub async fn find_available<F: Fn(&OfferObj) -> bool>(
+        &self,
+        predicate: F,
+    ) -> anyhow::Result<Option<OfferObj>> {
+        self.find(
+            &OfferQuery {
+                available: true,
+                ..Default::default()
+            },
+            predicate,
+        )
+        .await
+    }
+
+    /// Offers (ordered by id) selected by indexes and matching predicate. Only offers
+    /// selected by the query are checked, so it is cheap for selective queries.
+    /// Offers are shared with the store, clone only the ones to be modified.
+    pub async fn query<F: Fn(&OfferObj) -> bool>(
+        &self,
+        query: &OfferQuery,
+        predicate: F,
+    ) -> anyhow::Result<Vec<Arc<OfferObj>>> {
+        match &self.backend {
+            OfferBackend::Memory { offers, index } => Ok(index
+                .query(query)
+                .iter()
+                .filter_map(|id| offers.get(id))
+                .filter(|offer_obj| predicate(offer_obj))
+                .cloned()
+                .collect()),
+            OfferBackend::Sqlite(storage) => Ok(storage
+                .query_offers(query)
+                .await?
+                .into_iter()
+                .filter(|offer_obj| predicate(offer_obj))
+                .map(Arc::new)
+                .collect()),
+        }
+    }
+
+    /// First offer (ordered by id) selected by indexes and matching predicate
+    pub async fn find<F: Fn(&OfferObj) -> bool>(
+        &self,
+        query: &OfferQuery,
+        predicate: F,
+    ) -> anyhow::Result<Option<OfferObj>> {
+        match &self.backend {
+            OfferBackend::Memory { offers, index } => Ok(index
+                .query(query)
+                .iter()
+                .filter_map(|id| offers.get(id))
+                .find(|offer_obj| predicate(offer_obj))
+                .map(|offer_obj| offer_obj.as_ref().clone())),
+            OfferBackend::Sqlite(storage) => storage.find_offer(query, predicate).await,
+        }
+    }
+
+    fn track_assignment(&mut self, offer_obj: &OfferObj) {
+        if let Some(assigned_at) = offer_obj.assigned_at {
+            let last = self
+                .last_assigned
+                .entry(offer_obj.offer.provider_id)
+                .or_insert(assigned_at);
+            *last = (*last).max(assigned_at);
+        }
+    }
+
+    /// Time of the latest assignment of offers of every provider
+    pub fn last_assigned(&self) -> &HashMap<NodeId, DateTime<Utc>> {
+        &self.last_assigned
+    }
+
+    /// Node name groups of stored offers, see [`crate::index::node_name_group`]
+    pub async fn node_groups(&self) -> anyhow::Result<Vec<String>> {
+        match &self.backend {
+            OfferBackend::Memory { index, .. } => Ok(index.node_groups()),
+            OfferBackend::Sqlite(storage) => storage.offer_node_groups().await,
+        }
+    }
+
+    /// Removes offers expiring at or before the given time, returns removed offers
+    pub async fn remove_expired(&mut self, before: DateTime<Utc>) -> anyhow::Result<Vec<OfferObj>> {
+        match &self.backend {
+            OfferBackend::Memory { index, .. } => {
+                let ids = index.expiring_before(before);
+                self.remove_many(&ids).await
+            }
+            OfferBackend::Sqlite(storage) => {
+                let removed = storage.remove_expired_offers(before).await?;
+                self.stored |= !removed.is_empty();
+                self.record_removed(&removed);
+                Ok(removed)
+            }
+        }
+    }
+
+    /// Removes offers not matching predicate, returns removed offers
+    pub async fn retain<F: Fn(&OfferObj) -> bool>(
+        &mut self,
+        predicate: F,
+    ) -> anyhow::Result<Vec<OfferObj>> {
+        let ids: Vec<String> = self
+            .filter(|offer_obj| !predicate(offer_obj))
+            .await?
+            .into_iter()
+            .map(|offer_obj| offer_obj.offer.id)
+            .collect();
+        self.remove_many(&ids).await
+    }
+}
+
+impl Publish for Offers {
+    type Item = OfferObj;
+    type Reader = Arc<OfferFeed>;
+
+    fn key(offer_obj: &OfferObj) -> &str {
+        &offer_obj.offer.id
+    }
+
+    fn take_changes(&mut self) -> Vec<(String, Option<Arc<OfferObj>>)> {
+        if !self.feed_changes.is_empty() {
+            let mut feed = self.feed.0.write().unwrap_or_else(|e| e.into_inner());
+            for (id, change) in self.feed_changes.drain(..) {
+                feed.record(&id, change);
+            }
+        }
+        self.changes.drain().collect()
+    }
+
+    fn take_stored(&mut self) -> bool {
+        std::mem::take(&mut self.stored)
+    }
+
+    fn reader(&self) -> Arc<OfferFeed> {
+        self.feed.clone()
+    }
+
+    fn storage(&self) -> Option<SqliteStorage> {
+        match &self.backend {
+            OfferBackend::Memory { .. } => None,
+            OfferBackend::Sqlite(storage) => Some(storage.clone()),
+        }
+    }
+
+    async fn load(storage: &SqliteStorage) -> anyhow::Result<Vec<OfferObj>> {
+        storage.query_offers(&OfferQuery::default()).await
+    }
+}
+
+impl Store<Offers> {
+    /// Offers added, updated or removed after the given sequence number of the epoch,
+    /// served from the published feed and snapshot without waiting for writers
+    pub async fn changes_since(
+        &self,
+        epoch: Option<&str>,
+        since: u64,
+    ) -> anyhow::Result<OfferChanges> {
+        // the feed is read first, offers read after it include all changes it lists
+        let (current_epoch, seq, changes) = {
+            let feed = self.reader().0.read().unwrap_or_else(|e| e.into_inner());
+            let changes =
+                (epoch == Some(feed.epoch.as_str()) && since >= feed.min_seq).then(|| {
+                    feed.changes
+                        .range(since + 1..)
+                        .map(|(seq, (id, change))| (*seq, id.clone(), *change))
+                        .collect::<Vec<_>>()
+                });
+            (feed.epoch.clone(), feed.seq, changes)
+        };
+        let Some(changes) = changes else {
+            return Ok(OfferChanges {
+                epoch: current_epoch,
+                seq,
+                reset: true,
+                offers: self.snapshot().await?.iter().cloned().collect(),
+                removed: Vec::new(),
+            });
+        };
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+        for (seq, id, change) in changes {
+            match change {
+                Change::Updated => updated.push(id),
+                Change::Removed => removed.push(OfferTombstone { id, seq }),
+            }
+        }
+        let offers = match self.storage() {
+            Some(storage) => {
+                let mut offers: HashMap<String, OfferObj> = storage
+                    .get_offers(&updated)
+                    .await?
+                    .into_iter()
+                    .map(|offer_obj| (offer_obj.offer.id.clone(), offer_obj))
+                    .collect();
+                updated.iter().filter_map(|id| offers.remove(id)).collect()
+            }
+            None => {
+                let snapshot = self.snapshot().await?;
+                updated
+                    .iter()
+                    .filter_map(|id| snapshot.get(id).cloned())
+                    .collect()
+            }
+        };
+        Ok(OfferChanges {
+            epoch: current_epoch,
+            seq,
+            reset: false,
+            offers,
+            removed,
+        })
+    }
+
+    /// Numbers of available and taken offers, without reading all of them
+    pub async fn count_by_state(&self) -> anyhow::Result<(usize, usize)> {
+        match self.storage() {
+            Some(storage) => storage.count_offers_by_state().await,
+            None => {
+                let snapshot = self.snapshot().await?;
+                let taken = snapshot.iter().filter(|o| o.requestor_id.is_some()).count();
+                Ok((snapshot.len() - taken, taken))
+            }
+        }
+    }
+}
+
+/// Where demands are kept, selected at startup
+#[derive(Debug, Clone)]
+enum DemandBackend {
+    Memory(BTreeMap<String, Arc<DemandObj>>),
+    Sqlite(SqliteStorage),
+}
+
+impl Default for DemandBackend {
+    fn default() -> Self {
+        DemandBackend::Memory(BTreeMap::new())
+    }
+}
+
+/// Demand store, counterpart of [`Offers`] for requestor demands.
+#[derive(Debug, Clone, Default)]
 pub struct Demands {
-    pub demand_map: BTreeMap<String, DemandObj>,
+    backend: DemandBackend,
+    /// Demands changed since the store was last published, None for removed ones
+    changes: HashMap<String, Option<Arc<DemandObj>>>,
+    /// Demands were written to the database since the store was last published
+    stored: bool,
+}
+
+impl Demands {
+    /// Opens demands kept in the database
+    pub fn new_sqlite(storage: SqliteStorage) -> Self {
+        Self {
+            backend: DemandBackend::Sqlite(storage),
+            changes: HashMap::new(),
+            stored: false,
+        }
+    }
+
+    pub async fn get(&self, id: &str) -> anyhow::Result<Option<DemandObj>> {
+        match &self.backend {
+            DemandBackend::Memory(demands) => Ok(demands
+                .get(id)
+                .map(|demand_obj| demand_obj.as_ref().clone())),
+            DemandBackend::Sqlite(storage) => storage.get_demand(id).await,
+        }
+    }
+
+    pub async fn contains(&self, id: &str) -> anyhow::Result<bool> {
+        match &self.backend {
+            DemandBackend::Memory(demands) => Ok(demands.contains_key(id)),
+            DemandBackend::Sqlite(storage) => Ok(storage.get_demand(id).await?.is_some()),
+        }
+    }
+
+    pub async fn find_by_node(&self, node_id: NodeId) -> anyhow::Result<Option<DemandObj>> {
+        match &self.backend {
+            DemandBackend::Memory(demands) => Ok(demands
+                .values()
+                .find(|demand_obj| demand_obj.demand.node_id == node_id)
+                .map(|demand_obj| demand_obj.as_ref().clone())),
+            DemandBackend::Sqlite(storage) => storage.find_demand_by_node(node_id).await,
+        }
+    }
+
+    /// Inserts new demand or replaces the one with the same id
+    pub async fn insert(&mut self, demand_obj: DemandObj) -> anyhow::Result<()> {
+        match &mut self.backend {
+            DemandBackend::Memory(demands) => {
+                let demand_obj = Arc::new(demand_obj);
+                self.changes
+                    .insert(demand_obj.demand.id.clone(), Some(demand_obj.clone()));
+                demands.insert(demand_obj.demand.id.clone(), demand_obj);
+                Ok(())
+            }
+            DemandBackend::Sqlite(storage) => {
+                storage.upsert_demand(&demand_obj).await?;
+                self.stored = true;
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn remove(&mut self, id: &str) -> anyhow::Result<Option<DemandObj>> {
+        Ok(self.remove_many(&[id.to_string()]).await?.pop())
+    }
+
+    /// Removes demands of the given ids, returns the ones which were stored
+    async fn remove_many(&mut self, ids: &[String]) -> anyhow::Result<Vec<DemandObj>> {
+        match &mut self.backend {
+            DemandBackend::Memory(demands) => {
+                let mut removed = Vec::new();
+                for id in ids {
+                    if let Some(demand_obj) = demands.remove(id) {
+                        self.changes.insert(id.clone(), None);
+                        removed.push(Arc::unwrap_or_clone(demand_obj));
+                    }
+                }
+                Ok(removed)
+            }
+            DemandBackend::Sqlite(storage) => {
+                let removed = storage.remove_demands(ids).await?;
+                self.stored |= !removed.is_empty();
+                Ok(removed)
+            }
+        }
+    }
+
+    /// All demands ordered by id
+    pub async fn all(&self) -> anyhow::Result<Vec<DemandObj>> {
+        match &self.backend {
+            DemandBackend::Memory(demands) => Ok(demands
+                .values()
+                .map(|demand_obj| demand_obj.as_ref().clone())
+                .collect()),
+            DemandBackend::Sqlite(storage) => storage.list_demands().await,
+        }
+    }
+
+    /// Removes demands not matching predicate, returns removed demands
+    pub async fn retain<F: Fn(&DemandObj) -> bool>(
+        &mut self,
+        predicate: F,
+    ) -> anyhow::Result<Vec<DemandObj>> {
+        let ids: Vec<String> = self
+            .all()
+            .await?
+            .into_iter()
+            .filter(|demand_obj| !predicate(demand_obj))
+            .map(|demand_obj| demand_obj.demand.id)
+            .collect();
+        self.remove_many(&ids).await
+    }
+}
+
+impl Publish for Demands {
+    type Item = DemandObj;
+    type Reader = ();
+
+    fn key(demand_obj: &DemandObj) -> &str {
+        &demand_obj.demand.id
+    }
+
+    fn take_changes(&mut self) -> Vec<(String, Option<Arc<DemandObj>>)> {
+        self.changes.drain().collect()
+    }
+
+    fn take_stored(&mut self) -> bool {
+        std::mem::take(&mut self.stored)
+    }
+
+    fn reader(&self) {}
+
+    fn storage(&self) -> Option<SqliteStorage> {
+        match &self.backend {
+            DemandBackend::Memory(_) => None,
+            DemandBackend::Sqlite(storage) => Some(storage.clone()),
+        }
+    }
+
+    async fn load(storage: &SqliteStorage) -> anyhow::Result<Vec<DemandObj>> {
+        storage.list_demands().await
+    }
+}
+
+/// Wakes requestors waiting (long polling) for offers queued to their demand
+#[derive(Debug, Default)]
+pub struct DemandNotifier {
+    waiters: std::sync::Mutex<HashMap<String, Arc<Notify>>>,
+}
+
+impl DemandNotifier {
+    pub fn get(&self, demand_id: &str) -> Arc<Notify> {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        waiters.entry(demand_id.to_string()).or_default().clone()
+    }
+
+    /// Called after offer was pushed to offer list of the demand
+    pub fn notify(&self, demand_id: &str) {
+        let waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(notify) = waiters.get(demand_id) {
+            notify.notify_waiters();
+        }
+    }
+
+    /// Demand is gone, wakes remaining waiters so they can respond
+    pub fn remove(&self, demand_id: &str) {
+        let mut waiters = self.waiters.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(notify) = waiters.remove(demand_id) {
+            notify.notify_waiters();
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub lock: Arc<tokio::sync::Mutex<Offers>>,
-    pub demands: Arc<tokio::sync::Mutex<Demands>>,
-    pub offers_given_to_node: Arc<tokio::sync::Mutex<BTreeMap<String, u64>>>,
+    pub lock: Arc<Store<Offers>>,
+    pub demands: Arc<Store<Demands>>,
+    pub fair_share: Arc<FairShare>,
+    pub metrics: Arc<Metrics>,
+    pub demand_notifier: Arc<DemandNotifier>,
+    pub auth: Arc<SignatureVerifier>,
+    pub admin_tokens: Arc<AdminTokens>,
+    pub config: Arc<SharedConfig>,
+    pub mirror_status: Arc<MirrorStatus>,
+}
+
+#[tokio::test]
+async fn test_offer_change_feed() {
+    use crate::test_util::offer;
+
+    let offer = |id: &str| offer(id, 1);
+    let store = Store::new(Offers::default());
+    {
+        let mut offers = store.lock().await;
+        offers.insert(offer("a")).await.unwrap();
+        offers.insert(offer("b")).await.unwrap();
+    }
+
+    let full = store.changes_since(None, 0).await.unwrap();
+    assert!(full.reset);
+    assert_eq!(full.offers.len(), 2);
+    assert_eq!(full.seq, 2);
+    let epoch = Some(full.epoch.as_str());
+
+    let mut offers = store.lock().await;
+    offers.remove("a").await.unwrap();
+    offers.insert(offer("c")).await.unwrap();
+    offers.insert(offer("b")).await.unwrap();
+    // changes are published with the batch
+    assert_eq!(store.changes_since(epoch, full.seq).await.unwrap().seq, 2);
+    drop(offers);
+    let changes = store.changes_since(epoch, full.seq).await.unwrap();
+    assert!(!changes.reset);
+    assert_eq!(changes.seq, 5);
+    let ids: Vec<&str> = changes.offers.iter().map(|o| o.offer.id.as_str()).collect();
+    assert_eq!(ids, vec!["c", "b"]);
+    assert_eq!(
+        changes.removed,
+        vec![OfferTombstone {
+            id: "a".to_string(),
+            seq: 3
+        }]
+    );
+    assert!(store
+        .changes_since(epoch, 5)
+        .await
+        .unwrap()
+        .offers
+        .is_empty());
+
+    // removing missing offer is not a change
+    let mut offers = store.lock().await;
+    offers.remove("a").await.unwrap();
+    offers.retain(|o| o.offer.id != "b").await.unwrap();
+    drop(offers);
+    let changes = store.changes_since(epoch, 5).await.unwrap();
+    assert_eq!(changes.seq, 6);
+    assert_eq!(changes.removed[0].id, "b");
+    assert!(store.changes_since(Some("other"), 5).await.unwrap().reset);
+
+    let mut offers = store.lock().await;
+    for idx in 0..=MAX_TOMBSTONES {
+        let id = format!("tmp-{}", idx);
+        offers.insert(offer(&id)).await.unwrap();
+        offers.remove(&id).await.unwrap();
+    }
+    drop(offers);
+    assert!(store.changes_since(epoch, 6).await.unwrap().reset);
+}
+
+#[test]
+fn test_offer_lease_expiry() {
+    use crate::test_util::offer;
+
+    let mut offer_obj = offer("offer", 1);
+    let ttl = chrono::Duration::seconds(30);
+    let later = Utc::now() + chrono::Duration::seconds(60);
+    assert!(!offer_obj.lease_expired(later, ttl));
+
+    offer_obj.assign(offer_obj.offer.provider_id);
+    assert!(!offer_obj.lease_expired(Utc::now(), ttl));
+    assert!(offer_obj.lease_expired(later, ttl));
+
+    offer_obj.confirmed_at = Some(Utc::now());
+    assert!(!offer_obj.lease_expired(later, ttl));
+
+    offer_obj.release();
+    assert!(offer_obj.requestor_id.is_none());
+    assert!(!offer_obj.lease_expired(later, ttl));
+}
+
+/// Compares indexed lookup with a full scan at a large market size,
+/// run with `cargo test --release -- --ignored bench_offer_lookup --nocapture`
+#[tokio::test]
+#[ignore]
+async fn bench_offer_lookup() {
+    use crate::test_util::{fill_market, market_provider as provider_id};
+    use std::time::Instant;
+
+    const OFFER_COUNT: usize = 100_000;
+    const LOOKUPS: usize = 100;
+
+    let mut offers = Offers::default();
+    fill_market(&mut offers, OFFER_COUNT, Utc::now()).await;
+
+    // like `/offer/take` of a specific provider node, every 100th offer is available
+    let start = Instant::now();
+    for idx in 0..LOOKUPS {
+        let query = OfferQuery {
+            provider_id: Some(provider_id(idx * 100)),
+            available: true,
+            ..Default::default()
+        };
+        assert!(offers.find(&query, |_| true).await.unwrap().is_some());
+    }
+    let indexed = start.elapsed();
+
+    let start = Instant::now();
+    for idx in 0..LOOKUPS {
+        let provider_id = provider_id(idx * 100);
+        let found = offers
+            .filter(|o| o.requestor_id.is_none() && o.offer.provider_id == provider_id)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+    }
+    let scan = start.elapsed();
+
+    println!(
+        "{} offers, {} lookups: indexed {:?}, full scan {:?}",
+        OFFER_COUNT, LOOKUPS, indexed, scan
+    );
+}
+
+/// Query of the picker: available, not expired offers, in memory and in sqlite,
+/// run with `cargo test --release -- --ignored bench_offer_query --nocapture`
+#[tokio::test]
+#[ignore]
+async fn bench_offer_query() {
+    use crate::test_util::{fill_market, remove_sqlite, sqlite_storage};
+    use std::time::Instant;
+
+    const OFFER_COUNT: usize = 100_000;
+    const QUERIES: u32 = 20;
+
+    let now = Utc::now();
+    let (storage, db_file) = sqlite_storage("bench").await;
+    for (name, mut offers) in [
+        ("memory", Offers::default()),
+        ("sqlite", Offers::new_sqlite(storage).await.unwrap()),
+    ] {
+        fill_market(&mut offers, OFFER_COUNT, now).await;
+
+        let query = OfferQuery {
+            available: true,
+            not_expired_at: Some(now),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        for _ in 0..QUERIES {
+            let found = offers.query(&query, |_| true).await.unwrap();
+            assert_eq!(found.len(), OFFER_COUNT / 200);
+        }
+        println!(
+            "{}: {} offers, {:?} per available and not expired query",
+            name,
+            OFFER_COUNT,
+            start.elapsed() / QUERIES
+        );
+    }
+    remove_sqlite(&db_file);
 }
@@ -0,0 +1,37 @@
+pub mod sqlite;
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+/// Backend used by [`crate::state::Offers`] and [`crate::state::Demands`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StorageKind {
+    /// Everything kept in memory, optionally snapshotted to --file-name
+    Memory,
+    /// Offers and demands kept in sqlite database, including removed ones as history
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "memory" => Ok(StorageKind::Memory),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => Err(anyhow::anyhow!(
+                "Unknown storage kind {}, expected memory or sqlite",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for StorageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageKind::Memory => write!(f, "memory"),
+            StorageKind::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use crate::index::{node_name_group, OfferQuery};
+use crate::state::{DemandObj, OfferObj};
+use chrono::{DateTime, Utc};
+use futures_util::TryStreamExt;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{QueryBuilder, Sqlite, SqlitePool};
+use std::collections::HashMap;
+use std::str::FromStr;
+use ya_client_model::NodeId;
+
+/// Offers fetched by id in one query, keeps the number of bound variables low
+const IDS_PER_QUERY: usize = 500;
+
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    pool: SqlitePool,
+}
+
+fn decode<T: serde::de::DeserializeOwned>(data: &str) -> anyhow::Result<T> {
+    Ok(serde_json::from_str(data)?)
+}
+
+/// Selects data of stored offers satisfying the query, ordered by id. Every condition
+/// is served by an index of the offer table, see migrations.
+fn offer_query(query: &OfferQuery) -> QueryBuilder<'static, Sqlite> {
+    let mut builder = QueryBuilder::new("SELECT data FROM offer WHERE removed_at IS NULL");
+    if let Some(exe_name) = &query.exe_name {
+        builder.push(" AND exe_name = ").push_bind(exe_name.clone());
+    }
+    if let Some(subnet) = &query.subnet {
+        builder.push(" AND subnet = ").push_bind(subnet.clone());
+    }
+    if let Some(cpu_architecture) = &query.cpu_architecture {
+        builder
+            .push(" AND cpu_architecture = ")
+            .push_bind(cpu_architecture.clone());
+    }
+    if let Some(provider_id) = &query.provider_id {
+        builder
+            .push(" AND provider_id = ")
+            .push_bind(provider_id.to_string());
+    }
+    if let Some(node_groups) = &query.node_groups {
+        builder.push(" AND node_group IN (");
+        let mut groups = builder.separated(", ");
+        for group in node_groups {
+            groups.push_bind(group.clone());
+        }
+        builder.push(")");
+    }
+    if query.available {
+        builder.push(" AND requestor_id IS NULL");
+    }
+    if let Some(now) = query.not_expired_at {
+        builder.push(" AND expiration >= ").push_bind(now);
+    }
+    builder.push(" ORDER BY id");
+    builder
+}
+
+impl SqliteStorage {
+    pub async fn connect(db_file: &str) -> anyhow::Result<Self> {
+        let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", db_file))?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal);
+        let pool = SqlitePoolOptions::new()
+            .max_connections(4)
+            .connect_with(options)
+            .await?;
+        sqlx::migrate!("./migrations").run(&pool).await?;
+        log::info!("Connected to sqlite database {}", db_file);
+        Ok(Self { pool })
+    }
+
+    pub async fn count_offers(&self) -> anyhow::Result<usize> {
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM offer WHERE removed_at IS NULL")
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(count as usize)
+    }
+
+    /// Numbers of available and taken offers
+    pub async fn count_offers_by_state(&self) -> anyhow::Result<(usize, usize)> {
+        let (available, taken): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*) - COUNT(requestor_id), COUNT(requestor_id) FROM offer WHERE removed_at IS NULL",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok((available as usize, taken as usize))
+    }
+
+    pub async fn get_offer(&self, id: &str) -> anyhow::Result<Option<OfferObj>> {
+        let data: Option<String> =
+            sqlx::query_scalar("SELECT data FROM offer WHERE id = $1 AND removed_at IS NULL")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        data.as_deref().map(decode).transpose()
+    }
+
+    /// Stored offers of the given ids, ordered by id
+    pub async fn get_offers(&self, ids: &[String]) -> anyhow::Result<Vec<OfferObj>> {
+        let mut offers = Vec::new();
+        for ids in ids.chunks(IDS_PER_QUERY) {
+            let mut builder =
+                QueryBuilder::new("SELECT data FROM offer WHERE removed_at IS NULL AND id IN (");
+            let mut separated = builder.separated(", ");
+            for id in ids {
+                separated.push_bind(id.clone());
+            }
+            builder.push(")");
+            let rows: Vec<String> = builder.build_query_scalar().fetch_all(&self.pool).await?;
+            for data in rows {
+                offers.push(decode::<OfferObj>(&data)?);
+            }
+        }
+        offers.sort_by(|a, b| a.offer.id.cmp(&b.offer.id));
+        Ok(offers)
+    }
+
+    pub async fn query_offers(&self, query: &OfferQuery) -> anyhow::Result<Vec<OfferObj>> {
+        let rows: Vec<String> = offer_query(query)
+            .build_query_scalar()
+            .fetch_all(&self.pool)
+            .await?;
+        rows.iter().map(|data| decode(data)).collect()
+    }
+
+    /// First offer satisfying the query and predicate, rows are read only until it is found
+    pub async fn find_offer<F: Fn(&OfferObj) -> bool>(
+        &self,
+        query: &OfferQuery,
+        predicate: F,
+    ) -> anyhow::Result<Option<OfferObj>> {
+        let mut builder = offer_query(query);
+        let mut rows = builder.build_query_scalar::<String>().fetch(&self.pool);
+        while let Some(data) = rows.try_next().await? {
+            let offer_obj = decode(&data)?;
+            if predicate(&offer_obj) {
+                return Ok(Some(offer_obj));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Node name groups of stored offers, see [`node_name_group`]
+    pub async fn offer_node_groups(&self) -> anyhow::Result<Vec<String>> {
+        Ok(sqlx::query_scalar(
+            "SELECT DISTINCT node_group FROM offer WHERE removed_at IS NULL ORDER BY node_group",
+        )
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    /// Latest assignment of every provider, including assignments of removed offers
+    pub async fn last_assigned(&self) -> anyhow::Result<HashMap<NodeId, DateTime<Utc>>> {
+        let rows: Vec<(String, String)> = sqlx::query_as(
+            r"SELECT provider_id, MAX(json_extract(data, '$.assigned_at')) FROM offer
+            WHERE json_extract(data, '$.assigned_at') IS NOT NULL
+            GROUP BY provider_id",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|(provider_id, assigned_at)| {
+                Ok((
+                    NodeId::from_str(&provider_id)?,
+                    DateTime::parse_from_rfc3339(&assigned_at)?.with_timezone(&Utc),
+                ))
+            })
+            .collect()
+    }
+
+    pub async fn upsert_offer(&self, offer_obj: &OfferObj) -> anyhow::Result<()> {
+        sqlx::query(
+            r"INSERT INTO offer (id, provider_id, requestor_id, expiration, pushed_at, removed_at, data,
+                exe_name, subnet, cpu_architecture, node_group)
+            VALUES ($1, $2, $3, $4, $5, NULL, $6, $7, $8, $9, $10)
+            ON CONFLICT(id) DO UPDATE SET
+                provider_id = excluded.provider_id,
+                requestor_id = excluded.requestor_id,
+                expiration = excluded.expiration,
+                pushed_at = excluded.pushed_at,
+                removed_at = NULL,
+                data = excluded.data,
+                exe_name = excluded.exe_name,
+                subnet = excluded.subnet,
+                cpu_architecture = excluded.cpu_architecture,
+                node_group = excluded.node_group",
+        )
+        .bind(&offer_obj.offer.id)
+        .bind(offer_obj.offer.provider_id.to_string())
+        .bind(offer_obj.requestor_id.map(|id| id.to_string()))
+        .bind(offer_obj.offer.expiration)
+        .bind(offer_obj.pushed_at)
+        .bind(serde_json::to_string(offer_obj)?)
+        .bind(&offer_obj.attributes.exe_name)
+        .bind(&offer_obj.attributes.subnet)
+        .bind(&offer_obj.attributes.cpu_architecture)
+        .bind(node_name_group(&offer_obj.attributes.node_name))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Marks offers as removed, returns the ones which were stored
+    pub async fn remove_offers(&self, ids: &[String]) -> anyhow::Result<Vec<OfferObj>> {
+        let now = Utc::now();
+        let mut removed = Vec::new();
+        let mut tx = self.pool.begin().await?;
+        for id in ids {
+            let data: Option<String> = sqlx::query_scalar(
+                "UPDATE offer SET removed_at = $1 WHERE id = $2 AND removed_at IS NULL RETURNING data",
+            )
+            .bind(now)
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(data) = data {
+                removed.push(decode(&data)?);
+            }
+        }
+        tx.commit().await?;
+        Ok(removed)
+    }
+
+    /// Marks offers expiring at or before the given time as removed, returns them
+    pub async fn remove_expired_offers(
+        &self,
+        before: DateTime<Utc>,
+    ) -> anyhow::Result<Vec<OfferObj>> {
+        let rows: Vec<String> = sqlx::query_scalar(
+            "UPDATE offer SET removed_at = $1 WHERE removed_at IS NULL AND expiration <= $2 RETURNING data",
+        )
+        .bind(Utc::now())
+        .bind(before)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(|data| decode(data)).collect()
+    }
+
+    /// Marks all offers as removed, returns their ids
+    pub async fn clear_offers(&self) -> anyhow::Result<Vec<String>> {
+        Ok(sqlx::query_scalar(
+            "UPDATE offer SET removed_at = $1 WHERE removed_at IS NULL RETURNING id",
+        )
+        .bind(Utc::now())
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    pub async fn get_demand(&self, id: &str) -> anyhow::Result<Option<DemandObj>> {
+        let data: Option<String> =
+            sqlx::query_scalar("SELECT data FROM demand WHERE id = $1 AND removed_at IS NULL")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        data.as_deref().map(decode).transpose()
+    }
+
+    pub async fn find_demand_by_node(&self, node_id: NodeId) -> anyhow::Result<Option<DemandObj>> {
+        let data: Option<String> = sqlx::query_scalar(
+            "SELECT data FROM demand WHERE removed_at IS NULL AND node_id = $1 ORDER BY id LIMIT 1",
+        )
+        .bind(node_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+        data.as_deref().map(decode).transpose()
+    }
+
+    pub async fn upsert_demand(&self, demand_obj: &DemandObj) -> anyhow::Result<()> {
+        sqlx::query(
+            r"INSERT INTO demand (id, node_id, expiration, removed_at, data)
+            VALUES ($1, $2, $3, NULL, $4)
+            ON CONFLICT(id) DO UPDATE SET
+                node_id = excluded.node_id,
+                expiration = excluded.expiration,
+                removed_at = NULL,
+                data = excluded.data",
+        )
+        .bind(&demand_obj.demand.id)
+        .bind(demand_obj.demand.node_id.to_string())
+        .bind(demand_obj.demand.expiration_ts.and_utc())
+        .bind(serde_json::to_string(demand_obj)?)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Marks demands as removed, returns the ones which were stored
+    pub async fn remove_demands(&self, ids: &[String]) -> anyhow::Result<Vec<DemandObj>> {
+        let now = Utc::now();
+        let mut removed = Vec::new();
+        let mut tx = self.pool.begin().await?;
+        for id in ids {
+            let data: Option<String> = sqlx::query_scalar(
+                "UPDATE demand SET removed_at = $1 WHERE id = $2 AND removed_at IS NULL RETURNING data",
+            )
+            .bind(now)
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(data) = data {
+                removed.push(decode(&data)?);
+            }
+        }
+        tx.commit().await?;
+        Ok(removed)
+    }
+
+    pub async fn list_demands(&self) -> anyhow::Result<Vec<DemandObj>> {
+        let rows: Vec<String> =
+            sqlx::query_scalar("SELECT data FROM demand WHERE removed_at IS NULL ORDER BY id")
+                .fetch_all(&self.pool)
+                .await?;
+        rows.iter().map(|data| decode(data)).collect()
+    }
+
+    /// Deletes offers and demands removed before the given time, returns number of rows.
+    /// Assignment times of providers whose offers are deleted are not loaded on restart.
+    pub async fn prune_history(&self, before: DateTime<Utc>) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut pruned = 0;
+        for table in ["offer", "demand"] {
+            pruned += sqlx::query(&format!(
+                "DELETE FROM {} WHERE removed_at IS NOT NULL AND removed_at < $1",
+                table
+            ))
+            .bind(before)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(pruned)
+    }
+}
+
+#[tokio::test]
+async fn test_sqlite_offer_store_keeps_history() {
+    use crate::state::{Demands, Offers};
+    use crate::store::Store;
+    use crate::test_util::{demand, node_offer, remove_sqlite, sqlite_storage};
+
+    let (storage, db_file) = sqlite_storage("test").await;
+    let offers = Store::new(Offers::new_sqlite(storage.clone()).await.unwrap());
+
+    let expiration = Utc::now() + chrono::Duration::hours(1);
+    let offer = |id: &str, provider: u8, name: &str| {
+        let mut offer_obj = node_offer(id, provider, name);
+        offer_obj.offer.expiration = expiration;
+        offer_obj
+    };
+    let mut offer_obj = offer("a", 1, "brick-1");
+    {
+        let mut lock = offers.lock().await;
+        lock.insert(offer_obj.clone()).await.unwrap();
+        lock.insert(offer("b", 2, "stone-1")).await.unwrap();
+        assert_eq!(lock.filter_available(|_| true).await.unwrap().len(), 2);
+
+        offer_obj.assign(offer_obj.offer.provider_id);
+        lock.insert(offer_obj.clone()).await.unwrap();
+        let available = lock.filter_available(|_| true).await.unwrap();
+        assert_eq!(available.len(), 1);
+        assert_eq!(available[0].offer.id, "b");
+        assert_eq!(
+            lock.get("a").await.unwrap().unwrap().requestor_id,
+            Some(offer_obj.offer.provider_id)
+        );
+        assert_eq!(lock.node_groups().await.unwrap(), vec!["brick", "stone"]);
+
+        // lookups are served by the indexed columns
+        let query = OfferQuery {
+            exe_name: Some(offer_obj.attributes.exe_name.clone()),
+            node_groups: Some(vec!["stone".to_string(), "rock".to_string()]),
+            not_expired_at: Some(expiration),
+            ..Default::default()
+        };
+        let found = lock.query(&query, |_| true).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].offer.id, "b");
+        let query = OfferQuery {
+            provider_id: Some(NodeId::from([1; 20])),
+            ..Default::default()
+        };
+        assert!(lock.find(&query, |_| true).await.unwrap().is_some());
+        assert!(lock
+            .find(
+                &OfferQuery {
+                    available: true,
+                    ..query
+                },
+                |_| true
+            )
+            .await
+            .unwrap()
+            .is_none());
+        let query = OfferQuery {
+            not_expired_at: Some(expiration + chrono::Duration::seconds(1)),
+            ..Default::default()
+        };
+        assert!(lock.query(&query, |_| true).await.unwrap().is_empty());
+    }
+    // listings are read from the database
+    assert_eq!(offers.snapshot().await.unwrap().len(), 2);
+    assert_eq!(offers.count_by_state().await.unwrap(), (1, 1));
+
+    // assignments are loaded back when the store is opened again
+    let reopened = Offers::new_sqlite(storage.clone()).await.unwrap();
+    assert!(reopened
+        .last_assigned()
+        .contains_key(&offer_obj.offer.provider_id));
+    assert_eq!(reopened.len().await.unwrap(), 2);
+
+    let mut lock = offers.lock().await;
+    assert!(lock.remove("a").await.unwrap().is_some());
+    assert!(lock.remove("a").await.unwrap().is_none());
+    assert_eq!(lock.remove_expired(expiration).await.unwrap().len(), 1);
+    assert_eq!(lock.len().await.unwrap(), 0);
+    drop(lock);
+    let changes = offers.changes_since(None, 0).await.unwrap();
+    let changes = offers.changes_since(Some(&changes.epoch), 3).await.unwrap();
+    assert_eq!(changes.removed.len(), 2);
+    let history: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM offer")
+        .fetch_one(&storage.pool)
+        .await
+        .unwrap();
+    assert_eq!(history, 2);
+
+    let demand = demand("demand", 0x11, expiration);
+    let node_id = demand.node_id;
+    let mut demands = Demands::new_sqlite(storage.clone());
+    demands.insert(DemandObj::new(demand)).await.unwrap();
+    assert!(demands.find_by_node(node_id).await.unwrap().is_some());
+    assert_eq!(demands.retain(|_| false).await.unwrap().len(), 1);
+    assert!(demands.all().await.unwrap().is_empty());
+
+    // history is kept until the retention passes
+    let now = Utc::now();
+    assert_eq!(
+        storage
+            .prune_history(now - chrono::Duration::hours(1))
+            .await
+            .unwrap(),
+        0
+    );
+    assert_eq!(
+        storage
+            .prune_history(now + chrono::Duration::seconds(1))
+            .await
+            .unwrap(),
+        3
+    );
+    let history: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM offer")
+        .fetch_one(&storage.pool)
+        .await
+        .unwrap();
+    assert_eq!(history, 0);
+
+    storage.pool.close().await;
+    remove_sqlite(&db_file);
+}
@@ -0,0 +1,342 @@
+use crate::storage::sqlite::SqliteStorage;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+
+/// Store which can be published as an immutable snapshot
+pub trait Publish {
+    type Item;
+    /// Part of the store readable without its lock, published together with the items
+    type Reader;
+
+    /// Id the items are ordered by
+    fn key(item: &Self::Item) -> &str;
+
+    /// Items changed since the last call, None for removed ones. Called every time
+    /// the lock is released, so it should be cheap when nothing changed. Stores kept
+    /// in the database return no changes, their snapshots are read from it.
+    fn take_changes(&mut self) -> Vec<(String, Option<Arc<Self::Item>>)>;
+
+    /// True if items were written to the database since the last call, snapshots
+    /// of stores kept in the database are read again only after such a batch
+    fn take_stored(&mut self) -> bool;
+
+    fn reader(&self) -> Self::Reader;
+
+    /// Database keeping the items, None if they are kept in memory
+    fn storage(&self) -> Option<SqliteStorage>;
+
+    /// All items kept in the database, ordered by id
+    fn load(
+        storage: &SqliteStorage,
+    ) -> impl Future<Output = anyhow::Result<Vec<Self::Item>>> + Send;
+}
+
+/// Immutable view of a store at the end of a batch of mutations
+#[derive(Debug)]
+pub struct Snapshot<T> {
+    pub version: u64,
+    items: Vec<Arc<T>>,
+    key: fn(&T) -> &str,
+}
+
+impl<T> Snapshot<T> {
+    /// Items ordered by id
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items.iter().map(|item| item.as_ref())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&T> {
+        self.items
+            .binary_search_by(|item| (self.key)(item).cmp(id))
+            .ok()
+            .map(|idx| self.items[idx].as_ref())
+    }
+
+    pub fn filter<F: Fn(&T) -> bool>(&self, predicate: F) -> Vec<&T> {
+        self.iter().filter(|item| predicate(item)).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+}
+
+/// Items as of the last released lock, shared with snapshots
+#[derive(Debug)]
+struct Published<T> {
+    version: u64,
+    items: BTreeMap<String, Arc<T>>,
+    snapshot: Arc<Snapshot<T>>,
+    /// Last snapshot read from the database, None until the first read
+    loaded: Option<Arc<Snapshot<T>>>,
+}
+
+/// Store guarded by a mutex for writers. Every time the lock is released after
+/// mutations, changes of the batch are handed over to the published items, and
+/// the next reader builds a snapshot of them. Readers list the store without
+/// waiting for the lock (and without blocking the picker and push handlers),
+/// writers pay only for their own changes, not for the size of the store.
+#[derive(Debug)]
+pub struct Store<T: Publish> {
+    inner: tokio::sync::Mutex<T>,
+    published: Mutex<Published<T::Item>>,
+    reader: T::Reader,
+    storage: Option<SqliteStorage>,
+}
+
+impl<T: Publish> Store<T> {
+    pub fn new(mut inner: T) -> Self {
+        let items: BTreeMap<String, Arc<T::Item>> = inner
+            .take_changes()
+            .into_iter()
+            .filter_map(|(id, item)| Some((id, item?)))
+            .collect();
+        let snapshot = Arc::new(Snapshot {
+            version: 0,
+            items: items.values().cloned().collect(),
+            key: T::key,
+        });
+        Self {
+            published: Mutex::new(Published {
+                version: 0,
+                items,
+                snapshot,
+                loaded: None,
+            }),
+            reader: inner.reader(),
+            storage: inner.storage(),
+            inner: tokio::sync::Mutex::new(inner),
+        }
+    }
+
+    pub async fn lock(&self) -> StoreGuard<'_, T> {
+        StoreGuard {
+            store: self,
+            guard: self.inner.lock().await,
+        }
+    }
+
+    /// True if the storage keeps items on its own, without the need of snapshots
+    pub fn is_persistent(&self) -> bool {
+        self.storage.is_some()
+    }
+
+    /// See [`Publish::storage`]
+    pub fn storage(&self) -> Option<&SqliteStorage> {
+        self.storage.as_ref()
+    }
+
+    /// Part of the store readable without the lock, see [`Publish::Reader`]
+    pub fn reader(&self) -> &T::Reader {
+        &self.reader
+    }
+
+    /// Last published snapshot, never waits for writers. Built on the first call
+    /// after a batch of mutations, later calls share it. Stores kept in the database
+    /// are read from it on the first call after a batch written to it.
+    pub async fn snapshot(&self) -> anyhow::Result<Arc<Snapshot<T::Item>>> {
+        match &self.storage {
+            Some(storage) => self.loaded(storage).await,
+            None => Ok(self.published()),
+        }
+    }
+
+    async fn loaded(&self, storage: &SqliteStorage) -> anyhow::Result<Arc<Snapshot<T::Item>>> {
+        let version = {
+            let published = self.published.lock().unwrap_or_else(|e| e.into_inner());
+            match &published.loaded {
+                Some(loaded) if loaded.version == published.version => return Ok(loaded.clone()),
+                _ => published.version,
+            }
+        };
+        // read without the lock of published items, a batch written meanwhile is
+        // either already in the snapshot or makes the next call read it again
+        let snapshot = Arc::new(Snapshot {
+            version,
+            items: T::load(storage).await?.into_iter().map(Arc::new).collect(),
+            key: T::key,
+        });
+        let mut published = self.published.lock().unwrap_or_else(|e| e.into_inner());
+        if published.version == version {
+            published.loaded = Some(snapshot.clone());
+        }
+        Ok(snapshot)
+    }
+
+    fn published(&self) -> Arc<Snapshot<T::Item>> {
+        let mut published = self.published.lock().unwrap_or_else(|e| e.into_inner());
+        if published.snapshot.version != published.version {
+            published.snapshot = Arc::new(Snapshot {
+                version: published.version,
+                items: published.items.values().cloned().collect(),
+                key: T::key,
+            });
+        }
+        published.snapshot.clone()
+    }
+}
+
+impl<T: Publish + Default> Default for Store<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+pub struct StoreGuard<'a, T: Publish> {
+    store: &'a Store<T>,
+    guard: tokio::sync::MutexGuard<'a, T>,
+}
+
+impl<T: Publish> Deref for StoreGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: Publish> DerefMut for StoreGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: Publish> Drop for StoreGuard<'_, T> {
+    fn drop(&mut self) {
+        // handed over before the lock is released, so the next writer never sees
+        // an older snapshot than its own state. Changes are taken under the lock of
+        // published items, so a reader seeing them in the reader also sees the items.
+        let mut published = self
+            .store
+            .published
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let changes = self.guard.take_changes();
+        let stored = self.guard.take_stored();
+        if changes.is_empty() && !stored {
+            return;
+        }
+        for (id, item) in changes {
+            match item {
+                Some(item) => published.items.insert(id, item),
+                None => published.items.remove(&id),
+            };
+        }
+        published.version += 1;
+    }
+}
+
+#[tokio::test]
+async fn test_snapshot_published_on_unlock() {
+    use crate::state::Offers;
+    use crate::test_util::offer;
+
+    let offer = |id: &str| offer(id, 1);
+    let store = Store::new(Offers::default());
+    let empty = store.snapshot().await.unwrap();
+    {
+        let mut lock = store.lock().await;
+        lock.insert(offer("b")).await.unwrap();
+        lock.insert(offer("a")).await.unwrap();
+        // batch is not visible until the lock is released
+        assert!(store.snapshot().await.unwrap().is_empty());
+    }
+    let snapshot = store.snapshot().await.unwrap();
+    let ids: Vec<&str> = snapshot.iter().map(|o| o.offer.id.as_str()).collect();
+    assert_eq!(ids, vec!["a", "b"]);
+    assert!(snapshot.get("b").is_some());
+    assert!(snapshot.get("c").is_none());
+    assert!(empty.is_empty());
+
+    // read only lock does not publish again
+    drop(store.lock().await);
+    assert!(Arc::ptr_eq(&snapshot, &store.snapshot().await.unwrap()));
+
+    let mut lock = store.lock().await;
+    lock.remove("a").await.unwrap();
+    // readers are not blocked by the writer and see the last batch
+    assert_eq!(store.snapshot().await.unwrap().len(), 2);
+    drop(lock);
+    assert_eq!(store.snapshot().await.unwrap().len(), 1);
+    // snapshot taken earlier stays intact
+    assert_eq!(snapshot.len(), 2);
+}
+
+#[tokio::test]
+async fn test_sqlite_snapshot_read_once_per_batch() {
+    use crate::state::Offers;
+    use crate::test_util::{offer, remove_sqlite, sqlite_storage};
+
+    let (storage, db_file) = sqlite_storage("snapshot").await;
+    let store = Store::new(Offers::new_sqlite(storage).await.unwrap());
+    let empty = store.snapshot().await.unwrap();
+    assert!(empty.is_empty());
+    assert!(Arc::ptr_eq(&empty, &store.snapshot().await.unwrap()));
+
+    store.lock().await.insert(offer("a", 1)).await.unwrap();
+    let snapshot = store.snapshot().await.unwrap();
+    assert_eq!(snapshot.len(), 1);
+    assert!(Arc::ptr_eq(&snapshot, &store.snapshot().await.unwrap()));
+
+    // read only lock does not read the database again
+    drop(store.lock().await);
+    assert!(Arc::ptr_eq(&snapshot, &store.snapshot().await.unwrap()));
+
+    store.lock().await.remove("a").await.unwrap();
+    assert!(store.snapshot().await.unwrap().is_empty());
+    remove_sqlite(&db_file);
+}
+
+/// Push latency at growing store sizes, it should stay flat as writers pay only for their
+/// own changes. Building the snapshot is left to the next reader, run with
+/// `cargo test --release -- --ignored bench_store_push --nocapture`
+#[tokio::test]
+#[ignore]
+async fn bench_store_push() {
+    use crate::state::{OfferObj, Offers};
+    use crate::test_util::golem_offer;
+    use std::time::{Duration, Instant};
+
+    const PUSHES: usize = 1000;
+
+    let gbo = golem_offer("offer", 1);
+    let offer = |id: String| {
+        let mut gbo = gbo.clone();
+        gbo.id = id;
+        OfferObj::new(gbo)
+    };
+    let store = Store::new(Offers::default());
+    let mut size = 0;
+    for target in [1_000, 10_000, 100_000] {
+        let mut lock = store.lock().await;
+        while size < target {
+            lock.insert(offer(format!("{:08}", size))).await.unwrap();
+            size += 1;
+        }
+        drop(lock);
+        store.snapshot().await.unwrap();
+
+        let mut pushes = Duration::ZERO;
+        for idx in 0..PUSHES {
+            let offer_obj = offer(format!("{:08}", idx));
+            let start = Instant::now();
+            store.lock().await.insert(offer_obj).await.unwrap();
+            pushes += start.elapsed();
+        }
+        let start = Instant::now();
+        assert_eq!(store.snapshot().await.unwrap().len(), size);
+        let read = start.elapsed();
+        println!(
+            "{} offers: {:?} per push, {:?} to build the next snapshot",
+            size,
+            pushes / PUSHES as u32,
+            read
+        );
+    }
+}
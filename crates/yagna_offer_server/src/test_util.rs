@@ -0,0 +1,122 @@
+use crate::model::demand::base::DemandSubscription;
+use crate::model::offer::base::{GolemBaseOffer, EXAMPLE_OFFER_JSON};
+use crate::state::{AppState, Demands, OfferObj, Offers};
+use crate::storage::sqlite::SqliteStorage;
+use crate::store::Store;
+use actix_web::web;
+use chrono::{DateTime, Utc};
+use std::path::PathBuf;
+use std::sync::Arc;
+use ya_client_model::NodeId;
+
+/// Example offer of the provider `[provider; 20]`, paid to the provider address,
+/// published now and valid for an hour, so it passes the default validation
+pub fn golem_offer(id: &str, provider: u8) -> GolemBaseOffer {
+    let mut gbo = serde_json::from_str::<GolemBaseOffer>(EXAMPLE_OFFER_JSON).unwrap();
+    gbo.id = id.to_string();
+    gbo.provider_id = NodeId::from([provider; 20]);
+    gbo.properties
+        .set(
+            "golem.com.payment.platform.erc20-polygon-glm.address",
+            gbo.provider_id.to_string().into(),
+        )
+        .unwrap();
+    gbo.timestamp = Utc::now();
+    gbo.expiration = gbo.timestamp + chrono::Duration::hours(1);
+    gbo
+}
+
+pub fn offer(id: &str, provider: u8) -> OfferObj {
+    OfferObj::new(golem_offer(id, provider))
+}
+
+/// Offer of the node named `node_name`, node group is the part before the first `-`
+pub fn node_offer(id: &str, provider: u8, node_name: &str) -> OfferObj {
+    let mut gbo = golem_offer(id, provider);
+    gbo.properties
+        .set("golem.node.id.name", node_name.into())
+        .unwrap();
+    OfferObj::new(gbo)
+}
+
+/// Provider of the offer number `idx` of [`fill_market`]
+pub fn market_provider(idx: usize) -> NodeId {
+    let mut provider_id = [0u8; 20];
+    provider_id[..8].copy_from_slice(&(idx as u64).to_be_bytes());
+    NodeId::from(provider_id)
+}
+
+/// Offers of distinct providers for benchmarks, ids are the numbers of offers.
+/// Every 100th offer is available and half of those are already expired.
+pub async fn fill_market(offers: &mut Offers, count: usize, now: DateTime<Utc>) {
+    let gbo = golem_offer("offer", 1);
+    for idx in 0..count {
+        let mut gbo = gbo.clone();
+        gbo.id = format!("{:08}", idx);
+        gbo.provider_id = market_provider(idx);
+        gbo.timestamp = now - chrono::Duration::minutes(1);
+        gbo.expiration = match idx % 200 {
+            0 => now - chrono::Duration::minutes(1),
+            _ => now + chrono::Duration::hours(1),
+        };
+        let mut offer_obj = OfferObj::new(gbo);
+        if idx % 100 != 0 {
+            offer_obj.assign(NodeId::default());
+        }
+        offers.insert(offer_obj).await.unwrap();
+    }
+}
+
+/// Demand of the requestor `[node; 20]` accepting any offer, created an hour before expiration
+pub fn demand(id: &str, node: u8, expiration: DateTime<Utc>) -> DemandSubscription {
+    serde_json::from_value(serde_json::json!({
+        "id": id,
+        "properties": "{}",
+        "constraints": "()",
+        "nodeId": NodeId::from([node; 20]),
+        "creationTs": (expiration - chrono::Duration::hours(1)).naive_utc(),
+        "insertionTs": null,
+        "expirationTs": expiration.naive_utc(),
+        "centralNetAddress": null
+    }))
+    .unwrap()
+}
+
+/// State with in memory stores and default configuration
+pub fn app_state() -> web::Data<AppState> {
+    app_state_with(Store::default(), Store::default())
+}
+
+pub fn app_state_with(offers: Store<Offers>, demands: Store<Demands>) -> web::Data<AppState> {
+    web::Data::new(AppState {
+        lock: Arc::new(offers),
+        demands: Arc::new(demands),
+        fair_share: Arc::new(Default::default()),
+        metrics: Arc::new(Default::default()),
+        demand_notifier: Arc::new(Default::default()),
+        auth: Arc::new(Default::default()),
+        admin_tokens: Arc::new(Default::default()),
+        config: Arc::new(Default::default()),
+        mirror_status: Arc::new(Default::default()),
+    })
+}
+
+/// Storage in a new database file, remove it with [`remove_sqlite`]
+pub async fn sqlite_storage(name: &str) -> (SqliteStorage, PathBuf) {
+    let db_file = std::env::temp_dir().join(format!(
+        "offer-server-{}-{}-{}.sqlite",
+        name,
+        std::process::id(),
+        Utc::now().timestamp_nanos_opt().unwrap_or_default()
+    ));
+    let storage = SqliteStorage::connect(db_file.to_str().unwrap())
+        .await
+        .unwrap();
+    (storage, db_file)
+}
+
+pub fn remove_sqlite(db_file: &std::path::Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{}", db_file.display(), suffix));
+    }
+}